@@ -0,0 +1,1177 @@
+use crate::blip::BlipBuf;
+
+pub const CPU_CLOCK: u32 = 4194304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+// Amplitude changes are collected for this many cycles (about 2 ms)
+// before being turned into samples, rather than on every tick
+const OUTPUT_BATCH_CYCLES: u32 = 8192;
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits that always read back as 1 for 0xFF10-0xFF2F
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope { initial: 0, increase: false, period: 0, volume: 0, timer: 0 }
+    }
+
+    fn write(&mut self, val: u8) {
+        self.initial = val >> 4;
+        self.increase = val & 0x08 != 0;
+        self.period = val & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+    // A negate calculation happened since the last trigger
+    negated: bool,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep { period: 0, negate: false, shift: 0, timer: 0, shadow: 0, enabled: false, negated: false }
+    }
+
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+struct Square {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_pos: u8,
+    length: u16,
+    length_enabled: bool,
+    freq: u16,
+    timer: i32,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl Square {
+    fn new(has_sweep: bool) -> Square {
+        Square {
+            enabled: false,
+            dac_enabled: false,
+            duty: 0,
+            duty_pos: 0,
+            length: 0,
+            length_enabled: false,
+            freq: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            sweep: if has_sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.freq as i32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.timer = self.period();
+        self.envelope.trigger();
+        let freq = self.freq;
+        let mut overflow = false;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = freq;
+            sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            sweep.negated = false;
+            if sweep.shift != 0 && sweep.calculate() > 2047 {
+                overflow = true;
+            }
+        }
+        if overflow {
+            self.enabled = false;
+        }
+    }
+
+    fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_pos = (self.duty_pos + 1) & 7;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = match self.sweep.as_mut() {
+            Some(s) => s,
+            None => return,
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let freq = sweep.calculate();
+        if freq > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow = freq;
+            self.freq = freq;
+            // The new frequency is checked again but not written back
+            if sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_TABLE[self.duty as usize][self.duty_pos as usize] * self.envelope.volume
+    }
+}
+
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    length: u16,
+    length_enabled: bool,
+    volume_code: u8,
+    freq: u16,
+    timer: i32,
+    position: u8,
+    sample: u8,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            length: 0,
+            length_enabled: false,
+            volume_code: 0,
+            freq: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            ram: [0; 16],
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.freq as i32) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length == 0 {
+            self.length = 256;
+        }
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) & 31;
+            let byte = self.ram[(self.position / 2) as usize];
+            self.sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.volume_code == 0 {
+            return 0;
+        }
+        self.sample >> (self.volume_code - 1)
+    }
+}
+
+struct Noise {
+    enabled: bool,
+    dac_enabled: bool,
+    length: u16,
+    length_enabled: bool,
+    shift: u8,
+    width_7: bool,
+    divisor: u8,
+    timer: i32,
+    lfsr: u16,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            dac_enabled: false,
+            length: 0,
+            length_enabled: false,
+            shift: 0,
+            width_7: false,
+            divisor: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (NOISE_DIVISORS[self.divisor as usize] << self.shift) as i32
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.width_7 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 == 1 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+}
+
+// The DMG's output capacitor, which slowly removes any DC offset
+struct HighPass {
+    capacitor: f32,
+    charge: f32,
+}
+
+impl HighPass {
+    fn new(sample_rate: u32) -> HighPass {
+        HighPass {
+            capacitor: 0.0,
+            charge: 0.999958f64.powf(CPU_CLOCK as f64 / sample_rate as f64) as f32,
+        }
+    }
+
+    fn apply(&mut self, input: f32) -> f32 {
+        let out = input - self.capacitor;
+        self.capacitor = input - out * self.charge;
+        out
+    }
+}
+
+// One stereo output: band-limited buffers plus the output filter
+struct Output {
+    left: BlipBuf,
+    right: BlipBuf,
+    amplitude: (f32, f32),
+    high_pass: [HighPass; 2],
+    samples: Vec<i16>,
+    // Reused between batches to read the buffers into
+    scratch: (Vec<f32>, Vec<f32>),
+}
+
+impl Output {
+    fn new(sample_rate: u32) -> Output {
+        Output {
+            left: BlipBuf::new(CPU_CLOCK as f64, sample_rate as f64),
+            right: BlipBuf::new(CPU_CLOCK as f64, sample_rate as f64),
+            amplitude: (0.0, 0.0),
+            high_pass: [HighPass::new(sample_rate), HighPass::new(sample_rate)],
+            samples: vec![],
+            scratch: (vec![], vec![]),
+        }
+    }
+
+    fn update(&mut self, time: u32, (left, right): (f32, f32)) {
+        if left != self.amplitude.0 {
+            self.left.add_delta(time, left - self.amplitude.0);
+        }
+        if right != self.amplitude.1 {
+            self.right.add_delta(time, right - self.amplitude.1);
+        }
+        self.amplitude = (left, right);
+    }
+
+    fn end_frame(&mut self, cycles: u32, high_pass: bool) {
+        self.left.end_frame(cycles);
+        self.right.end_frame(cycles);
+        let count = self.left.samples_avail();
+        let (left, right) = &mut self.scratch;
+        left.clear();
+        right.clear();
+        self.left.read_samples(left, count);
+        self.right.read_samples(right, count);
+        self.samples.reserve(count * 2);
+        for (&l, &r) in left.iter().zip(right.iter()) {
+            let (l, r) = if high_pass {
+                (self.high_pass[0].apply(l), self.high_pass[1].apply(r))
+            } else {
+                (l, r)
+            };
+            self.samples.push(l.clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+            self.samples.push(r.clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+        }
+    }
+}
+
+// What a channel is currently playing, in musical terms
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelState {
+    pub playing: bool,
+    // Tone frequency in Hz; for noise, the rate the LFSR is clocked at
+    pub frequency: f32,
+    // 0-15
+    pub volume: u8,
+    // Number of times the channel has been triggered
+    pub triggers: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [Channel::Square1, Channel::Square2, Channel::Wave, Channel::Noise];
+
+    pub fn index(self) -> usize {
+        match self {
+            Channel::Square1 => 0,
+            Channel::Square2 => 1,
+            Channel::Wave => 2,
+            Channel::Noise => 3,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Square1 => "square1",
+            Channel::Square2 => "square2",
+            Channel::Wave => "wave",
+            Channel::Noise => "noise",
+        }
+    }
+
+    // Accepts 1-4 (the NRx1 numbering) or the channel name
+    pub fn parse(name: &str) -> Option<Channel> {
+        match name.to_ascii_lowercase().as_str() {
+            "1" | "square1" => Some(Channel::Square1),
+            "2" | "square2" => Some(Channel::Square2),
+            "3" | "wave" => Some(Channel::Wave),
+            "4" | "noise" => Some(Channel::Noise),
+            _ => None,
+        }
+    }
+}
+
+pub struct Apu {
+    powered: bool,
+    ch1: Square,
+    ch2: Square,
+    ch3: Wave,
+    ch4: Noise,
+    // Last values written to 0xFF10-0xFF26, for readback
+    nr: [u8; 0x17],
+    nr50: u8,
+    nr51: u8,
+    frame_step: u8,
+    last_div: u16,
+    sample_rate: u32,
+    high_pass_enabled: bool,
+    mix: Output,
+    // Per-channel outputs, only generated while stems are enabled
+    stems: Vec<Output>,
+    // Cycles run since the outputs were last turned into samples
+    batch_cycles: u32,
+    muted: [bool; 4],
+    solo: [bool; 4],
+    triggers: [u32; 4],
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            powered: false,
+            ch1: Square::new(true),
+            ch2: Square::new(false),
+            ch3: Wave::new(),
+            ch4: Noise::new(),
+            nr: [0; 0x17],
+            nr50: 0,
+            nr51: 0,
+            frame_step: 0,
+            last_div: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            high_pass_enabled: true,
+            mix: Output::new(DEFAULT_SAMPLE_RATE),
+            stems: vec![],
+            batch_cycles: 0,
+            muted: [false; 4],
+            solo: [false; 4],
+            triggers: [0; 4],
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.mix = Output::new(rate);
+        if !self.stems.is_empty() {
+            self.set_stems_enabled(true);
+        }
+    }
+
+    pub fn set_high_pass(&mut self, enabled: bool) {
+        self.high_pass_enabled = enabled;
+    }
+
+    // Interleaved stereo (left, right) samples produced since the last call,
+    // which trail emulation by up to OUTPUT_BATCH_CYCLES
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.mix.samples)
+    }
+
+    pub fn samples(&self) -> &[i16] {
+        &self.mix.samples
+    }
+
+    // Drops the oldest samples
+    pub fn discard_samples(&mut self, count: usize) {
+        self.mix.samples.drain(..count.min(self.mix.samples.len()));
+    }
+
+    // Muting and soloing only affect the mix, never the stems
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel.index()] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel.index()]
+    }
+
+    // While any channel is soloed only soloed channels are mixed
+    pub fn set_solo(&mut self, channel: Channel, solo: bool) {
+        self.solo[channel.index()] = solo;
+    }
+
+    pub fn is_solo(&self, channel: Channel) -> bool {
+        self.solo[channel.index()]
+    }
+
+    pub fn is_audible(&self, channel: Channel) -> bool {
+        let i = channel.index();
+        if self.solo.iter().any(|&s| s) {
+            self.solo[i]
+        } else {
+            !self.muted[i]
+        }
+    }
+
+    pub fn set_stems_enabled(&mut self, enabled: bool) {
+        self.stems = if enabled {
+            Channel::ALL.iter().map(|_| Output::new(self.sample_rate)).collect()
+        } else {
+            vec![]
+        };
+    }
+
+    pub fn stems_enabled(&self) -> bool {
+        !self.stems.is_empty()
+    }
+
+    // Interleaved stereo samples of a single channel, as if it were the
+    // only one playing
+    pub fn take_stem_samples(&mut self, channel: Channel) -> Vec<i16> {
+        match self.stems.get_mut(channel.index()) {
+            Some(stem) => std::mem::take(&mut stem.samples),
+            None => vec![],
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF30..=0xFF3F => self.ch3.ram[(addr - 0xFF30) as usize],
+            0xFF26 => {
+                let mut val = READ_MASKS[0x16];
+                if self.powered { val |= 0x80; }
+                if self.ch1.enabled { val |= 0x01; }
+                if self.ch2.enabled { val |= 0x02; }
+                if self.ch3.enabled { val |= 0x04; }
+                if self.ch4.enabled { val |= 0x08; }
+                val
+            }
+            0xFF10..=0xFF2F => {
+                let i = (addr - 0xFF10) as usize;
+                let raw = if i < self.nr.len() { self.nr[i] } else { 0 };
+                raw | READ_MASKS[i]
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        if let 0xFF30..=0xFF3F = addr {
+            self.ch3.ram[(addr - 0xFF30) as usize] = val;
+            return;
+        }
+        if addr == 0xFF26 {
+            self.set_power(val & 0x80 != 0);
+            return;
+        }
+        if !self.powered || addr > 0xFF25 {
+            return;
+        }
+        self.nr[(addr - 0xFF10) as usize] = val;
+        if let 0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 = addr {
+            if val & 0x80 != 0 {
+                let i = ((addr - 0xFF10) / 5) as usize;
+                self.triggers[i] = self.triggers[i].wrapping_add(1);
+            }
+        }
+        match addr {
+            // Square 1
+            0xFF10 => {
+                if let Some(sweep) = self.ch1.sweep.as_mut() {
+                    sweep.period = (val >> 4) & 0x07;
+                    sweep.negate = val & 0x08 != 0;
+                    sweep.shift = val & 0x07;
+                    // Leaving negate mode after using it disables the channel
+                    if !sweep.negate && sweep.negated {
+                        self.ch1.enabled = false;
+                    }
+                }
+            }
+            0xFF11 => {
+                self.ch1.duty = val >> 6;
+                self.ch1.length = 64 - (val & 0x3F) as u16;
+            }
+            0xFF12 => {
+                self.ch1.envelope.write(val);
+                self.ch1.dac_enabled = val & 0xF8 != 0;
+                if !self.ch1.dac_enabled { self.ch1.enabled = false; }
+            }
+            0xFF13 => { self.ch1.freq = (self.ch1.freq & 0x700) | val as u16; }
+            0xFF14 => {
+                self.ch1.freq = (self.ch1.freq & 0xFF) | (((val & 0x07) as u16) << 8);
+                self.ch1.length_enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 { self.ch1.trigger(); }
+            }
+            // Square 2
+            0xFF16 => {
+                self.ch2.duty = val >> 6;
+                self.ch2.length = 64 - (val & 0x3F) as u16;
+            }
+            0xFF17 => {
+                self.ch2.envelope.write(val);
+                self.ch2.dac_enabled = val & 0xF8 != 0;
+                if !self.ch2.dac_enabled { self.ch2.enabled = false; }
+            }
+            0xFF18 => { self.ch2.freq = (self.ch2.freq & 0x700) | val as u16; }
+            0xFF19 => {
+                self.ch2.freq = (self.ch2.freq & 0xFF) | (((val & 0x07) as u16) << 8);
+                self.ch2.length_enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 { self.ch2.trigger(); }
+            }
+            // Wave
+            0xFF1A => {
+                self.ch3.dac_enabled = val & 0x80 != 0;
+                if !self.ch3.dac_enabled { self.ch3.enabled = false; }
+            }
+            0xFF1B => { self.ch3.length = 256 - val as u16; }
+            0xFF1C => { self.ch3.volume_code = (val >> 5) & 0x03; }
+            0xFF1D => { self.ch3.freq = (self.ch3.freq & 0x700) | val as u16; }
+            0xFF1E => {
+                self.ch3.freq = (self.ch3.freq & 0xFF) | (((val & 0x07) as u16) << 8);
+                self.ch3.length_enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 { self.ch3.trigger(); }
+            }
+            // Noise
+            0xFF20 => { self.ch4.length = 64 - (val & 0x3F) as u16; }
+            0xFF21 => {
+                self.ch4.envelope.write(val);
+                self.ch4.dac_enabled = val & 0xF8 != 0;
+                if !self.ch4.dac_enabled { self.ch4.enabled = false; }
+            }
+            0xFF22 => {
+                self.ch4.shift = val >> 4;
+                self.ch4.width_7 = val & 0x08 != 0;
+                self.ch4.divisor = val & 0x07;
+            }
+            0xFF23 => {
+                self.ch4.length_enabled = val & 0x40 != 0;
+                if val & 0x80 != 0 { self.ch4.trigger(); }
+            }
+            // Control
+            0xFF24 => { self.nr50 = val; }
+            0xFF25 => { self.nr51 = val; }
+            _ => {}
+        }
+    }
+
+    pub fn channel_state(&self, channel: Channel) -> ChannelState {
+        let clock = CPU_CLOCK as f32;
+        let (playing, period, volume) = match channel {
+            Channel::Square1 => (self.ch1.enabled, self.ch1.period(), self.ch1.envelope.volume),
+            Channel::Square2 => (self.ch2.enabled, self.ch2.period(), self.ch2.envelope.volume),
+            Channel::Wave => {
+                let volume = match self.ch3.volume_code {
+                    0 => 0,
+                    code => 15 >> (code - 1),
+                };
+                (self.ch3.enabled, self.ch3.period(), volume)
+            }
+            Channel::Noise => (self.ch4.enabled, self.ch4.period(), self.ch4.envelope.volume),
+        };
+        // Square and wave periods cover one of 8 or 32 waveform steps
+        let steps = match channel {
+            Channel::Square1 | Channel::Square2 => 8.0,
+            Channel::Wave => 32.0,
+            Channel::Noise => 1.0,
+        };
+        ChannelState {
+            playing: self.powered && playing,
+            frequency: clock / (period as f32 * steps),
+            volume,
+            triggers: self.triggers[channel.index()],
+        }
+    }
+
+    // Register writes that recreate the current register state on a freshly
+    // reset APU, without retriggering any channel. Used to start logs of
+    // register writes partway through a game.
+    pub fn state_writes(&self) -> Vec<(u16, u8)> {
+        let mut writes = vec![(0xFF26, if self.powered { 0x80 } else { 0x00 })];
+        for (i, &val) in self.nr.iter().enumerate().take(0x16) {
+            let addr = 0xFF10 + i as u16;
+            match addr {
+                0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => writes.push((addr, val & 0x7F)),
+                0xFF15 | 0xFF1F => {}
+                _ => writes.push((addr, val)),
+            }
+        }
+        for (i, &val) in self.ch3.ram.iter().enumerate() {
+            writes.push((0xFF30 + i as u16, val));
+        }
+        writes
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if self.powered && !on {
+            // Powering off clears every register except wave RAM
+            let ram = self.ch3.ram;
+            self.ch1 = Square::new(true);
+            self.ch2 = Square::new(false);
+            self.ch3 = Wave::new();
+            self.ch3.ram = ram;
+            self.ch4 = Noise::new();
+            self.nr = [0; 0x17];
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.powered && on {
+            self.frame_step = 0;
+        }
+        self.powered = on;
+    }
+
+    // Advances the APU by the given number of T-cycles. `div` is the
+    // timer's internal divider; the frame sequencer steps on each falling
+    // edge of its bit 12 (DIV bit 4), i.e. at 512 Hz.
+    pub fn tick(&mut self, cycles: u32, div: u16) {
+        if (self.last_div >> 12) & 1 == 1 && (div >> 12) & 1 == 0 && self.powered {
+            self.clock_frame_sequencer();
+        }
+        self.last_div = div;
+
+        // Register writes and frame sequencer changes since the last tick
+        // take effect at its start
+        self.update_amplitude(0);
+
+        // Step the channels from one waveform change to the next so every
+        // change is recorded at the cycle it happened on
+        if self.powered {
+            let mut time = 0;
+            while time < cycles {
+                let next = self.ch1.timer
+                    .min(self.ch2.timer)
+                    .min(self.ch3.timer)
+                    .min(self.ch4.timer)
+                    .max(1) as u32;
+                let dt = next.min(cycles - time);
+                self.ch1.step(dt);
+                self.ch2.step(dt);
+                self.ch3.step(dt);
+                self.ch4.step(dt);
+                time += dt;
+                self.update_amplitude(time);
+            }
+        }
+
+        self.batch_cycles += cycles;
+        if self.batch_cycles >= OUTPUT_BATCH_CYCLES {
+            self.mix.end_frame(self.batch_cycles, self.high_pass_enabled);
+            for stem in self.stems.iter_mut() {
+                stem.end_frame(self.batch_cycles, self.high_pass_enabled);
+            }
+            self.batch_cycles = 0;
+        }
+    }
+
+    // Time is in cycles since the start of this tick
+    fn update_amplitude(&mut self, time: u32) {
+        let time = self.batch_cycles + time;
+        if !self.stems.is_empty() {
+            let channels = self.channel_amplitudes();
+            for (stem, amplitude) in self.stems.iter_mut().zip(channels.iter()) {
+                stem.update(time, *amplitude);
+            }
+        }
+        let mix = self.mix();
+        self.mix.update(time, mix);
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_step & 1 == 0 {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) & 7;
+    }
+
+    // Each DAC maps 0-15 onto -1.0..1.0; a disabled DAC outputs silence
+    fn dac_outputs(&self) -> [f32; 4] {
+        let dac = |enabled: bool, digital: u8| {
+            if enabled { digital as f32 / 7.5 - 1.0 } else { 0.0 }
+        };
+        [
+            dac(self.ch1.dac_enabled, self.ch1.output()),
+            dac(self.ch2.dac_enabled, self.ch2.output()),
+            dac(self.ch3.dac_enabled, self.ch3.output()),
+            dac(self.ch4.dac_enabled, self.ch4.output()),
+        ]
+    }
+
+    // Each channel's contribution to the left and right outputs after
+    // NR51 panning and NR50 master volume
+    fn channel_amplitudes(&self) -> [(f32, f32); 4] {
+        let mut amplitudes = [(0.0, 0.0); 4];
+        if !self.powered {
+            return amplitudes;
+        }
+        let left_volume = (((self.nr50 >> 4) & 0x07) + 1) as f32 / 8.0;
+        let right_volume = ((self.nr50 & 0x07) + 1) as f32 / 8.0;
+        let scale = i16::MAX as f32 / 4.0;
+        for (i, out) in self.dac_outputs().iter().enumerate() {
+            if self.nr51 & (0x10 << i) != 0 {
+                amplitudes[i].0 = out * left_volume * scale;
+            }
+            if self.nr51 & (0x01 << i) != 0 {
+                amplitudes[i].1 = out * right_volume * scale;
+            }
+        }
+        amplitudes
+    }
+
+    fn mix(&self) -> (f32, f32) {
+        let channels = self.channel_amplitudes();
+        let mut mix = (0.0, 0.0);
+        for channel in Channel::ALL.iter() {
+            if self.is_audible(*channel) {
+                mix.0 += channels[channel.index()].0;
+                mix.1 += channels[channel.index()].1;
+            }
+        }
+        mix
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
+
+
+// APU Tests
+#[cfg(test)]
+fn powered_apu() -> Apu {
+    let mut apu = Apu::new();
+    apu.write(0xFF26, 0x80);
+    apu.write(0xFF24, 0x77);
+    apu.write(0xFF25, 0xFF);
+    apu
+}
+#[cfg(test)]
+fn clock_sequencer(apu: &mut Apu, steps: u32) {
+    for _ in 0..steps {
+        apu.tick(0, 0x1000);
+        apu.tick(0, 0x0000);
+    }
+}
+
+#[test]
+fn apu_power_off_ignores_writes() {
+    let mut apu = Apu::new();
+    apu.write(0xFF12, 0xF0);
+    assert_eq!(apu.read(0xFF12), 0x00);
+    assert_eq!(apu.read(0xFF26), 0x70);
+    apu.write(0xFF30, 0x12);
+    assert_eq!(apu.read(0xFF30), 0x12);
+}
+#[test]
+fn apu_register_read_masks() {
+    let mut apu = powered_apu();
+    apu.write(0xFF11, 0x80);
+    apu.write(0xFF13, 0x12);
+    assert_eq!(apu.read(0xFF11), 0xBF);
+    assert_eq!(apu.read(0xFF13), 0xFF);
+    assert_eq!(apu.read(0xFF27), 0xFF);
+}
+#[test]
+fn apu_trigger_sets_nr52_status() {
+    let mut apu = powered_apu();
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF14, 0x80);
+    assert_eq!(apu.read(0xFF26), 0xF1);
+    // Turning the DAC off disables the channel
+    apu.write(0xFF12, 0x00);
+    assert_eq!(apu.read(0xFF26), 0xF0);
+}
+#[test]
+fn apu_power_off_clears_registers() {
+    let mut apu = powered_apu();
+    apu.write(0xFF30, 0xAB);
+    apu.write(0xFF26, 0x00);
+    assert_eq!(apu.read(0xFF24), 0x00);
+    assert_eq!(apu.read(0xFF30), 0xAB);
+}
+#[test]
+fn apu_length_counter_disables_channel() {
+    let mut apu = powered_apu();
+    apu.write(0xFF17, 0xF0);
+    apu.write(0xFF16, 0x3E); // length 2
+    apu.write(0xFF19, 0xC0);
+    assert_eq!(apu.read(0xFF26) & 0x02, 0x02);
+    clock_sequencer(&mut apu, 1);
+    assert_eq!(apu.read(0xFF26) & 0x02, 0x02);
+    clock_sequencer(&mut apu, 2);
+    assert_eq!(apu.read(0xFF26) & 0x02, 0x00);
+}
+#[test]
+fn apu_envelope_decreases_volume() {
+    let mut apu = powered_apu();
+    apu.write(0xFF17, 0xF1);
+    apu.write(0xFF19, 0x80);
+    assert_eq!(apu.ch2.envelope.volume, 15);
+    clock_sequencer(&mut apu, 8);
+    assert_eq!(apu.ch2.envelope.volume, 14);
+}
+#[test]
+fn apu_sweep_overflow_disables_channel() {
+    let mut apu = powered_apu();
+    apu.write(0xFF10, 0x11);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF13, 0xFF);
+    apu.write(0xFF14, 0x87);
+    // 0x7FF + (0x7FF >> 1) overflows immediately on trigger
+    assert_eq!(apu.read(0xFF26) & 0x01, 0x00);
+}
+#[test]
+fn apu_sweep_raises_frequency() {
+    let mut apu = powered_apu();
+    apu.write(0xFF10, 0x11);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF13, 0x00);
+    apu.write(0xFF14, 0x81);
+    clock_sequencer(&mut apu, 3);
+    assert_eq!(apu.ch1.freq, 0x180);
+}
+#[test]
+fn apu_noise_lfsr_steps() {
+    let mut apu = powered_apu();
+    apu.write(0xFF21, 0xF0);
+    apu.write(0xFF22, 0x00);
+    apu.write(0xFF23, 0x80);
+    apu.tick(8, 0);
+    assert_eq!(apu.ch4.lfsr, 0x3FFF);
+}
+#[test]
+fn apu_wave_reads_samples() {
+    let mut apu = powered_apu();
+    apu.write(0xFF30, 0x1F);
+    apu.write(0xFF1A, 0x80);
+    apu.write(0xFF1C, 0x20);
+    apu.write(0xFF1D, 0xFF);
+    apu.write(0xFF1E, 0x87);
+    apu.tick(2, 0);
+    assert_eq!(apu.ch3.output(), 0x0F);
+}
+#[test]
+fn apu_produces_samples_at_rate() {
+    let mut apu = powered_apu();
+    apu.set_sample_rate(32768);
+    apu.tick(CPU_CLOCK / 64, 0);
+    assert_eq!(apu.take_samples().len(), 2 * 32768 / 64);
+    assert!(apu.take_samples().is_empty());
+}
+#[test]
+fn apu_mix_square_output() {
+    let mut apu = powered_apu();
+    apu.write(0xFF25, 0x11);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF11, 0x80);
+    apu.write(0xFF14, 0x80);
+    let (left, right) = apu.mix();
+    assert_eq!(left, right);
+    assert!(left != 0.0);
+}
+#[test]
+fn apu_high_pass_removes_dc() {
+    let mut apu = powered_apu();
+    apu.set_sample_rate(32768);
+    // DAC on with the channel silent is a constant offset
+    apu.write(0xFF12, 0x08);
+    apu.tick(CPU_CLOCK, 0);
+    let samples = apu.take_samples();
+    assert!(samples[0] != 0);
+    assert!(samples[samples.len() - 2].abs() < 16);
+}
+#[test]
+fn apu_square_output_is_band_limited() {
+    let mut apu = powered_apu();
+    apu.set_high_pass(false);
+    apu.write(0xFF25, 0x10);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF11, 0x80);
+    apu.write(0xFF14, 0x80);
+    apu.tick(CPU_CLOCK / 64, 0);
+    let samples = apu.take_samples();
+    let left: Vec<i16> = samples.iter().step_by(2).cloned().collect();
+    let max = *left.iter().max().unwrap();
+    let min = *left.iter().min().unwrap();
+    // Steps are smoothed, so some samples fall between the two levels
+    assert!(left.iter().any(|&s| s > min + 1000 && s < max - 1000));
+}
+#[test]
+fn apu_small_ticks_match_one_large_tick() {
+    let square = || {
+        let mut apu = powered_apu();
+        apu.write(0xFF25, 0x11);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF11, 0x80);
+        apu.write(0xFF14, 0x80);
+        apu
+    };
+    let mut large = square();
+    large.tick(OUTPUT_BATCH_CYCLES * 4, 0);
+    let mut small = square();
+    for _ in 0..OUTPUT_BATCH_CYCLES {
+        small.tick(4, 0);
+    }
+    // Samples only come out once a batch is complete
+    let mut partial = square();
+    partial.tick(OUTPUT_BATCH_CYCLES - 4, 0);
+    assert!(partial.take_samples().is_empty());
+    assert_eq!(small.take_samples(), large.take_samples());
+}
+#[test]
+fn apu_mute_and_solo() {
+    let mut apu = powered_apu();
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF11, 0x80);
+    apu.write(0xFF14, 0x80);
+    assert!(apu.mix().0 != 0.0);
+    apu.set_muted(Channel::Square1, true);
+    assert_eq!(apu.mix(), (0.0, 0.0));
+    assert!(!apu.is_audible(Channel::Square1));
+    apu.set_muted(Channel::Square1, false);
+    apu.set_solo(Channel::Noise, true);
+    assert!(!apu.is_audible(Channel::Square1));
+    assert!(apu.is_audible(Channel::Noise));
+    assert_eq!(apu.mix(), (0.0, 0.0));
+}
+#[test]
+fn apu_stems_ignore_mute() {
+    let mut apu = powered_apu();
+    apu.set_high_pass(false);
+    apu.set_sample_rate(32768);
+    apu.set_stems_enabled(true);
+    apu.set_muted(Channel::Square2, true);
+    apu.write(0xFF17, 0xF0);
+    apu.write(0xFF16, 0x80);
+    apu.write(0xFF19, 0x80);
+    apu.tick(CPU_CLOCK / 64, 0);
+    let mix = apu.take_samples();
+    let stem = apu.take_stem_samples(Channel::Square2);
+    let other = apu.take_stem_samples(Channel::Square1);
+    assert_eq!(stem.len(), mix.len());
+    assert!(mix.iter().all(|&s| s == 0));
+    assert!(stem.iter().any(|&s| s != 0));
+    assert!(other.iter().all(|&s| s == 0));
+}
+#[test]
+fn channel_parse() {
+    assert_eq!(Channel::parse("3"), Some(Channel::Wave));
+    assert_eq!(Channel::parse("Noise"), Some(Channel::Noise));
+    assert_eq!(Channel::parse("5"), None);
+}
+#[test]
+fn apu_state_writes_restore_registers() {
+    let mut apu = powered_apu();
+    apu.write(0xFF12, 0xF3);
+    apu.write(0xFF14, 0xC7);
+    apu.write(0xFF3A, 0x5A);
+    let mut copy = Apu::new();
+    for (addr, val) in apu.state_writes() {
+        copy.write(addr, val);
+    }
+    for addr in 0xFF10..0xFF40 {
+        if addr != 0xFF26 {
+            assert_eq!(copy.read(addr), apu.read(addr));
+        }
+    }
+    // The trigger bit isn't replayed
+    assert_eq!(copy.read(0xFF26), 0xF0);
+}
+#[test]
+fn apu_channel_state() {
+    let mut apu = powered_apu();
+    apu.write(0xFF12, 0xA0);
+    // 131072 / (2048 - 1750) = 439.8 Hz
+    apu.write(0xFF13, 0xD6);
+    apu.write(0xFF14, 0x86);
+    let state = apu.channel_state(Channel::Square1);
+    assert!(state.playing);
+    assert!((state.frequency - 439.8).abs() < 0.1);
+    assert_eq!(state.volume, 10);
+    assert_eq!(state.triggers, 1);
+    apu.write(0xFF1A, 0x80);
+    apu.write(0xFF1C, 0x40);
+    apu.write(0xFF1D, 0xD6);
+    apu.write(0xFF1E, 0x86);
+    let wave = apu.channel_state(Channel::Wave);
+    assert!((wave.frequency - 219.9).abs() < 0.1);
+    assert_eq!(wave.volume, 7);
+    assert!(!apu.channel_state(Channel::Noise).playing);
+}
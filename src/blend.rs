@@ -0,0 +1,126 @@
+use std::collections::VecDeque;
+
+// Longest history an exponential response keeps
+const MAX_FRAMES: usize = 16;
+
+// Simulates the slow response of the LCD by mixing each RGBA frame with the
+// ones before it, which games that flicker sprites on alternate frames rely
+// on to look right.
+//
+// The response curve is a weight for the current frame followed by a weight
+// for each frame before it.
+pub struct FrameBlender {
+    weights: Vec<f32>,
+    // Most recent frame first
+    history: VecDeque<Vec<u8>>,
+}
+
+impl FrameBlender {
+    pub fn new(weights: Vec<f32>) -> Result<FrameBlender, String> {
+        if weights.is_empty() || weights.len() > MAX_FRAMES {
+            return Err(format!("a response curve needs 1 to {} weights", MAX_FRAMES));
+        }
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0) || weights[0] <= 0.0 {
+            return Err("response weights must be positive".to_string());
+        }
+        Ok(FrameBlender { weights, history: VecDeque::new() })
+    }
+
+    // Each older frame keeps `persistence` of the weight of the one after
+    // it, like an LCD pixel fading out
+    pub fn exponential(persistence: f32) -> Result<FrameBlender, String> {
+        if !(0.0..1.0).contains(&persistence) {
+            return Err(format!("persistence {} must be at least 0 and below 1", persistence));
+        }
+        let mut weights = vec![1.0];
+        while weights.len() < MAX_FRAMES && weights[weights.len() - 1] * persistence >= 1.0 / 256.0 {
+            weights.push(weights[weights.len() - 1] * persistence);
+        }
+        FrameBlender::new(weights)
+    }
+
+    // A single persistence value, or comma separated weights starting with
+    // the current frame, e.g. "0.5" or "2,1"
+    pub fn parse(spec: &str) -> Result<FrameBlender, String> {
+        let values = spec.split(',')
+            .map(|v| v.trim().parse::<f32>().map_err(|_| format!("invalid response curve '{}'", spec)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if values.len() == 1 {
+            FrameBlender::exponential(values[0])
+        } else {
+            FrameBlender::new(values)
+        }
+    }
+
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    pub fn push(&mut self, frame: Vec<u8>) {
+        // A change of picture size, e.g. the SGB border appearing, starts
+        // over
+        if self.history.front().is_some_and(|f| f.len() != frame.len()) {
+            self.history.clear();
+        }
+        self.history.push_front(frame);
+        self.history.truncate(self.weights.len());
+    }
+
+    // The blended picture, or None before the first frame. Alpha is taken
+    // from the current frame.
+    pub fn output(&self) -> Option<Vec<u8>> {
+        let current = self.history.front()?;
+        let total: f32 = self.weights.iter().take(self.history.len()).sum();
+        let mut out = current.clone();
+        for (i, value) in out.iter_mut().enumerate() {
+            if i % 4 == 3 {
+                continue;
+            }
+            let sum: f32 = self.history.iter()
+                .zip(self.weights.iter())
+                .map(|(frame, w)| frame[i] as f32 * w)
+                .sum();
+            *value = (sum / total).round() as u8;
+        }
+        Some(out)
+    }
+}
+
+
+// Frame Blending Tests
+#[test]
+fn blend_mixes_frames() {
+    let mut blender = FrameBlender::new(vec![1.0, 1.0]).unwrap();
+    assert_eq!(blender.output(), None);
+    blender.push(vec![0, 0, 0, 255]);
+    assert_eq!(blender.output(), Some(vec![0, 0, 0, 255]));
+    blender.push(vec![255, 100, 0, 255]);
+    assert_eq!(blender.output(), Some(vec![128, 50, 0, 255]));
+    // Only as many frames as weights are kept
+    blender.push(vec![255, 100, 0, 255]);
+    assert_eq!(blender.output(), Some(vec![255, 100, 0, 255]));
+}
+#[test]
+fn blend_weights_favour_current_frame() {
+    let mut blender = FrameBlender::parse("3,1").unwrap();
+    blender.push(vec![0, 0, 0, 255]);
+    blender.push(vec![200, 200, 200, 255]);
+    assert_eq!(blender.output(), Some(vec![150, 150, 150, 255]));
+}
+#[test]
+fn blend_exponential_curve() {
+    let blender = FrameBlender::parse("0.5").unwrap();
+    assert_eq!(&blender.weights()[..4], &[1.0, 0.5, 0.25, 0.125]);
+    assert_eq!(blender.weights().len(), 9);
+    assert_eq!(FrameBlender::parse("0").unwrap().weights(), &[1.0]);
+    assert!(FrameBlender::parse("1").is_err());
+    assert!(FrameBlender::parse("0,1").is_err());
+    assert!(FrameBlender::parse("fast").is_err());
+}
+#[test]
+fn blend_size_change_resets_history() {
+    let mut blender = FrameBlender::new(vec![1.0, 1.0]).unwrap();
+    blender.push(vec![0, 0, 0, 255]);
+    blender.push(vec![200, 200, 200, 255, 200, 200, 200, 255]);
+    assert_eq!(blender.output(), Some(vec![200, 200, 200, 255, 200, 200, 200, 255]));
+}
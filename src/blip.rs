@@ -0,0 +1,148 @@
+use std::f64::consts::PI;
+
+// Band-limited step synthesis in the style of blargg's blip_buf. Instead of
+// point-sampling a waveform, callers report each change in amplitude with
+// the clock cycle it happened on. Every change is added to the buffer as a
+// band-limited impulse placed at its exact sub-sample position and the
+// buffer is integrated on read, producing alias-free steps at any output
+// rate.
+
+const HALF_WIDTH: usize = 8;
+const KERNEL_WIDTH: usize = HALF_WIDTH * 2;
+const PHASES: usize = 32;
+
+pub struct BlipBuf {
+    // Output samples per input clock
+    factor: f64,
+    // Sub-sample position of clock 0 of the current frame
+    offset: f64,
+    // Samples that are complete and ready to be read
+    avail: usize,
+    buf: Vec<f32>,
+    integrator: f32,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+}
+
+impl BlipBuf {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> BlipBuf {
+        BlipBuf {
+            factor: sample_rate / clock_rate,
+            offset: 0.0,
+            avail: 0,
+            buf: vec![0.0; KERNEL_WIDTH],
+            integrator: 0.0,
+            kernel: build_kernel(),
+        }
+    }
+
+    // Adds an amplitude change at the given clock within the current frame
+    pub fn add_delta(&mut self, time: u32, delta: f32) {
+        let pos = self.avail as f64 + self.offset + time as f64 * self.factor;
+        let index = pos.floor() as usize;
+        let phase = (((pos - pos.floor()) * PHASES as f64) as usize).min(PHASES - 1);
+        if self.buf.len() < index + KERNEL_WIDTH {
+            self.buf.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        let kernel = &self.kernel[phase];
+        for (i, k) in kernel.iter().enumerate() {
+            self.buf[index + i] += delta * k;
+        }
+    }
+
+    // Ends the current frame after `time` clocks; everything before that
+    // point becomes available to read.
+    pub fn end_frame(&mut self, time: u32) {
+        self.offset += time as f64 * self.factor;
+        let whole = self.offset.floor();
+        self.offset -= whole;
+        self.avail += whole as usize;
+        if self.buf.len() < self.avail + KERNEL_WIDTH {
+            self.buf.resize(self.avail + KERNEL_WIDTH, 0.0);
+        }
+    }
+
+    pub fn samples_avail(&self) -> usize {
+        self.avail
+    }
+
+    // Removes up to `count` samples and appends them to `out`
+    pub fn read_samples(&mut self, out: &mut Vec<f32>, count: usize) -> usize {
+        let count = count.min(self.avail);
+        for i in 0..count {
+            self.integrator += self.buf[i];
+            out.push(self.integrator);
+        }
+        self.buf.drain(..count);
+        self.buf.resize(self.buf.len().max(KERNEL_WIDTH), 0.0);
+        self.avail -= count;
+        count
+    }
+}
+
+// Windowed-sinc impulses, one per sub-sample phase, each normalised so a
+// delta integrates to exactly its size.
+fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    let cutoff = 0.45;
+    let mut kernel = vec![[0.0; KERNEL_WIDTH]; PHASES];
+    for (phase, taps) in kernel.iter_mut().enumerate() {
+        let frac = phase as f64 / PHASES as f64;
+        let mut sum = 0.0;
+        let mut values = [0.0f64; KERNEL_WIDTH];
+        for (i, value) in values.iter_mut().enumerate() {
+            let x = i as f64 - (HALF_WIDTH as f64 - 1.0) - frac;
+            let sinc = if x == 0.0 { 1.0 } else { (2.0 * PI * cutoff * x).sin() / (2.0 * PI * cutoff * x) };
+            // Blackman window over the kernel width
+            let w = (x + HALF_WIDTH as f64) / KERNEL_WIDTH as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+            *value = sinc * window.max(0.0);
+            sum += *value;
+        }
+        for (tap, value) in taps.iter_mut().zip(values.iter()) {
+            *tap = (value / sum) as f32;
+        }
+    }
+    kernel
+}
+
+
+// BlipBuf Tests
+#[test]
+fn blip_rate_conversion() {
+    let mut blip = BlipBuf::new(4194304.0, 48000.0);
+    blip.end_frame(4194304 / 2);
+    assert_eq!(blip.samples_avail(), 24000);
+    let mut out = vec![];
+    assert_eq!(blip.read_samples(&mut out, 100), 100);
+    assert_eq!(blip.samples_avail(), 23900);
+}
+#[test]
+fn blip_fractional_frames_accumulate() {
+    let mut blip = BlipBuf::new(4194304.0, 44100.0);
+    for _ in 0..60 {
+        blip.end_frame(70224);
+    }
+    // 60 * 70224 * 44100 / 4194304 = 44301.6
+    assert_eq!(blip.samples_avail(), 44301);
+}
+#[test]
+fn blip_step_settles_to_delta() {
+    let mut blip = BlipBuf::new(1000.0, 1000.0);
+    blip.add_delta(10, 100.0);
+    blip.end_frame(100);
+    let mut out = vec![];
+    blip.read_samples(&mut out, 100);
+    assert!(out[0].abs() < 0.01);
+    assert!((out[99] - 100.0).abs() < 0.01);
+    // Band limiting spreads the step over neighbouring samples
+    assert!(out.iter().any(|&s| s > 1.0 && s < 99.0));
+}
+#[test]
+fn blip_read_preserves_pending_deltas() {
+    let mut blip = BlipBuf::new(1000.0, 1000.0);
+    blip.end_frame(10);
+    blip.add_delta(5, 50.0);
+    blip.end_frame(50);
+    let mut out = vec![];
+    blip.read_samples(&mut out, 60);
+    assert!((out[59] - 50.0).abs() < 0.01);
+}
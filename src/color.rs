@@ -0,0 +1,184 @@
+// Conversion of the frame to RGBA. DMG frames hold shades, which are shown
+// in the colours of an output palette. CGB frames hold RGB555 colours which
+// go through a colour correction curve, since the CGB's LCD never showed
+// them at full saturation.
+
+// Colours for shades 0-3, from lightest to darkest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputPalette {
+    pub colors: [[u8; 3]; 4],
+}
+
+impl OutputPalette {
+    // The original DMG's green LCD
+    pub const CLASSIC_GREEN: OutputPalette = OutputPalette {
+        colors: [[0x9B, 0xBC, 0x0F], [0x8B, 0xAC, 0x0F], [0x30, 0x62, 0x30], [0x0F, 0x38, 0x0F]],
+    };
+    pub const POCKET: OutputPalette = OutputPalette {
+        colors: [[0xFF, 0xFF, 0xFF], [0xAA, 0xAA, 0xAA], [0x55, 0x55, 0x55], [0x00, 0x00, 0x00]],
+    };
+    // The Game Boy Light's backlit screen
+    pub const LIGHT: OutputPalette = OutputPalette {
+        colors: [[0x00, 0xB5, 0x81], [0x00, 0x9A, 0x71], [0x00, 0x69, 0x4A], [0x00, 0x4F, 0x3B]],
+    };
+
+    // A preset name, or four hex colours separated by commas, e.g.
+    // "e0f8d0,88c070,346856,081820"
+    pub fn parse(spec: &str) -> Result<OutputPalette, String> {
+        match spec.to_ascii_lowercase().as_str() {
+            "green" | "classic" | "classic-green" => return Ok(OutputPalette::CLASSIC_GREEN),
+            "pocket" | "gray" | "grey" | "grayscale" => return Ok(OutputPalette::POCKET),
+            "light" => return Ok(OutputPalette::LIGHT),
+            _ => {}
+        }
+        let colors = spec.split(',').map(parse_hex_color).collect::<Result<Vec<_>, _>>()?;
+        if colors.len() != 4 {
+            return Err(format!("palette '{}' needs four colours", spec));
+        }
+        Ok(OutputPalette { colors: [colors[0], colors[1], colors[2], colors[3]] })
+    }
+}
+
+impl Default for OutputPalette {
+    fn default() -> OutputPalette {
+        OutputPalette::POCKET
+    }
+}
+
+fn parse_hex_color(text: &str) -> Result<[u8; 3], String> {
+    let hex = text.trim().trim_start_matches('#');
+    let value = match hex.len() {
+        6 => u32::from_str_radix(hex, 16).ok(),
+        _ => None,
+    };
+    match value {
+        Some(v) => Ok([(v >> 16) as u8, (v >> 8) as u8, v as u8]),
+        None => Err(format!("invalid colour '{}'", text)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorCorrection {
+    // Each 5-bit channel scaled straight to 8 bits
+    Raw,
+    // Mimics the CGB LCD: colours bleed into each other and nothing
+    // reaches full brightness
+    #[default]
+    Accurate,
+    // Light channel mixing and a compressed range, which is easier on
+    // the eyes on modern displays without the CGB's washed out look
+    ReducedContrast,
+}
+
+impl ColorCorrection {
+    pub fn parse(name: &str) -> Option<ColorCorrection> {
+        match name.to_ascii_lowercase().as_str() {
+            "raw" | "none" => Some(ColorCorrection::Raw),
+            "accurate" | "cgb" => Some(ColorCorrection::Accurate),
+            "reduced-contrast" | "modern" => Some(ColorCorrection::ReducedContrast),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, color: u16) -> [u8; 3] {
+        let r = (color & 0x1F) as u32;
+        let g = ((color >> 5) & 0x1F) as u32;
+        let b = ((color >> 10) & 0x1F) as u32;
+        match self {
+            ColorCorrection::Raw => [expand(r), expand(g), expand(b)],
+            ColorCorrection::Accurate => [
+                ((r * 26 + g * 4 + b * 2).min(960) >> 2) as u8,
+                ((g * 24 + b * 8).min(960) >> 2) as u8,
+                ((r * 6 + g * 4 + b * 22).min(960) >> 2) as u8,
+            ],
+            ColorCorrection::ReducedContrast => {
+                let (r, g, b) = (expand(r) as u32, expand(g) as u32, expand(b) as u32);
+                let compress = |v: u32| (0x20 + v * 0xC0 / 0xFF) as u8;
+                [
+                    compress((r * 14 + g + b) / 16),
+                    compress((g * 14 + r + b) / 16),
+                    compress((b * 14 + r + g) / 16),
+                ]
+            }
+        }
+    }
+}
+
+fn expand(channel: u32) -> u8 {
+    ((channel << 3) | (channel >> 2)) as u8
+}
+
+// Converts a frame from the PPU to 8-bit RGBA
+pub fn frame_to_rgba(frame: &[u16], cgb: bool, correction: ColorCorrection, palette: &OutputPalette) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(frame.len() * 4);
+    for &pixel in frame {
+        if cgb {
+            rgba.extend_from_slice(&correction.apply(pixel));
+        } else {
+            rgba.extend_from_slice(&palette.colors[(pixel & 0x03) as usize]);
+        }
+        rgba.push(0xFF);
+    }
+    rgba
+}
+
+
+// Colour Tests
+#[test]
+fn color_raw_expands_channels() {
+    let raw = ColorCorrection::Raw;
+    assert_eq!(raw.apply(0x7FFF), [0xFF, 0xFF, 0xFF]);
+    assert_eq!(raw.apply(0x0000), [0x00, 0x00, 0x00]);
+    assert_eq!(raw.apply(0x001F), [0xFF, 0x00, 0x00]);
+    assert_eq!(raw.apply(0x03E0), [0x00, 0xFF, 0x00]);
+    assert_eq!(raw.apply(0x7C00), [0x00, 0x00, 0xFF]);
+    assert_eq!(raw.apply(0x4210), [0x84, 0x84, 0x84]);
+}
+#[test]
+fn color_accurate_curve() {
+    let accurate = ColorCorrection::Accurate;
+    assert_eq!(accurate.apply(0x7FFF), [240, 240, 240]);
+    assert_eq!(accurate.apply(0x0000), [0, 0, 0]);
+    assert_eq!(accurate.apply(0x001F), [201, 0, 46]);
+    assert_eq!(accurate.apply(0x03E0), [31, 186, 31]);
+    assert_eq!(accurate.apply(0x7C00), [15, 62, 170]);
+}
+#[test]
+fn color_reduced_contrast_curve() {
+    let reduced = ColorCorrection::ReducedContrast;
+    assert_eq!(reduced.apply(0x7FFF), [0xE0, 0xE0, 0xE0]);
+    assert_eq!(reduced.apply(0x0000), [0x20, 0x20, 0x20]);
+    assert_eq!(reduced.apply(0x001F), [199, 43, 43]);
+}
+#[test]
+fn color_parse_names() {
+    assert_eq!(ColorCorrection::parse("raw"), Some(ColorCorrection::Raw));
+    assert_eq!(ColorCorrection::parse("Accurate"), Some(ColorCorrection::Accurate));
+    assert_eq!(ColorCorrection::parse("reduced-contrast"), Some(ColorCorrection::ReducedContrast));
+    assert_eq!(ColorCorrection::parse("sepia"), None);
+}
+#[test]
+fn color_frame_to_rgba() {
+    let dmg = frame_to_rgba(&[0, 1, 2, 3], false, ColorCorrection::Raw, &OutputPalette::POCKET);
+    assert_eq!(dmg, vec![
+        0xFF, 0xFF, 0xFF, 0xFF, 0xAA, 0xAA, 0xAA, 0xFF,
+        0x55, 0x55, 0x55, 0xFF, 0x00, 0x00, 0x00, 0xFF,
+    ]);
+    let cgb = frame_to_rgba(&[0x001F], true, ColorCorrection::Raw, &OutputPalette::CLASSIC_GREEN);
+    assert_eq!(cgb, vec![0xFF, 0x00, 0x00, 0xFF]);
+}
+#[test]
+fn color_output_palette_maps_shades() {
+    let rgba = frame_to_rgba(&[0, 3], false, ColorCorrection::Raw, &OutputPalette::CLASSIC_GREEN);
+    assert_eq!(rgba, vec![0x9B, 0xBC, 0x0F, 0xFF, 0x0F, 0x38, 0x0F, 0xFF]);
+}
+#[test]
+fn color_output_palette_parse() {
+    assert_eq!(OutputPalette::parse("Light"), Ok(OutputPalette::LIGHT));
+    let custom = OutputPalette::parse("#e0f8d0, 88c070,346856,081820").unwrap();
+    assert_eq!(custom.colors[0], [0xE0, 0xF8, 0xD0]);
+    assert_eq!(custom.colors[3], [0x08, 0x18, 0x20]);
+    assert!(OutputPalette::parse("e0f8d0,88c070,346856").is_err());
+    assert!(OutputPalette::parse("e0f8d0,88c070,346856,zzzzzz").is_err());
+    assert!(OutputPalette::parse("sepia").is_err());
+}
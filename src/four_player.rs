@@ -0,0 +1,394 @@
+use std::sync::{Arc, Mutex};
+
+use crate::gb::GB;
+use crate::serial::SerialDevice;
+
+pub const MAX_PLAYERS: usize = 4;
+
+// The adapter clocks every transfer. During the ping phase it sends a byte
+// every PING_PERIOD cycles; during the transmission phase the period comes
+// from the RATE byte player 1 sent while pinging.
+const PING_PERIOD: u32 = 16384;
+const MIN_TRANSFER_PERIOD: u32 = 4096;
+const RATE_STEP: u32 = 512;
+// Game Boys are run in slices of this many cycles between adapter steps
+const SLICE: u32 = 256;
+
+const PING_HEADER: u8 = 0xFE;
+const ACK: u8 = 0x88;
+const START_REQUEST: u8 = 0xAA;
+const START_REPLY: u8 = 0xCC;
+const PING_LEN: usize = 4;
+const MAX_PACKET_SIZE: usize = 16;
+
+// The adapter's end of one Game Boy's link port
+#[derive(Default)]
+struct PortState {
+    // SB of a Game Boy currently waiting for the adapter to clock a byte
+    waiting: Option<u8>,
+    // Byte clocked in by the adapter, delivered on the next tick
+    incoming: Option<u8>,
+}
+
+struct Port(Arc<Mutex<PortState>>);
+
+impl SerialDevice for Port {
+    // Game Boys never drive the clock while plugged into the adapter; they
+    // only ever see 0xFF if they try
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+
+    fn external_clock(&mut self, byte: u8) -> Option<u8> {
+        let mut port = self.0.lock().unwrap();
+        match port.incoming.take() {
+            Some(received) => Some(received),
+            None => {
+                port.waiting = Some(byte);
+                None
+            }
+        }
+    }
+}
+
+struct Player {
+    gb: GB,
+    port: Arc<Mutex<PortState>>,
+    // Cycles run past the end of the last slice
+    ahead: u32,
+    // Answered the last ping header
+    connected: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    // Sending ping packets: a header followed by three status bytes, each
+    // holding the connected players in bits 4-7 and the player's own number
+    // in bits 0-2. Game Boys answer ACK, ACK, RATE, SIZE.
+    Ping,
+    // Player 1 answered a whole ping packet with 0xAA; the adapter confirms
+    // with four 0xCC bytes before starting to relay packets
+    Starting,
+    // Relaying packets in frames of 4 * SIZE bytes. While a frame is sent
+    // out, holding every player's packet from the previous frame, each
+    // Game Boy's first SIZE bytes are collected as its next packet. Player
+    // 1 sending a packet of all 0xFF returns to the ping phase.
+    Transmission,
+}
+
+// The DMG-07 four player adapter, with up to four Game Boys plugged in.
+// It runs the Game Boys itself so that every transfer reaches all of them
+// at the same emulated time.
+pub struct FourPlayerAdapter {
+    players: Vec<Player>,
+    phase: Phase,
+    timer: u32,
+    // Position within the current ping packet, start sequence or frame
+    index: usize,
+    rate: u8,
+    packet_size: usize,
+    // Player 1 answered 0xAA to every byte of the current ping packet
+    start_requested: bool,
+    frame_out: Vec<u8>,
+    frame_in: Vec<Vec<u8>>,
+}
+
+impl FourPlayerAdapter {
+    pub fn new() -> FourPlayerAdapter {
+        FourPlayerAdapter {
+            players: vec![],
+            phase: Phase::Ping,
+            timer: 0,
+            index: 0,
+            rate: 0,
+            packet_size: 1,
+            start_requested: true,
+            frame_out: vec![],
+            frame_in: vec![],
+        }
+    }
+
+    // Plugs a Game Boy into the next free port and returns its player
+    // index, counting from 0
+    pub fn add_player(&mut self, mut gb: GB) -> Result<usize, String> {
+        if self.players.len() == MAX_PLAYERS {
+            return Err(format!("all {} ports are in use", MAX_PLAYERS));
+        }
+        let port = Arc::new(Mutex::new(PortState::default()));
+        gb.connect_serial(Box::new(Port(port.clone())));
+        self.players.push(Player { gb, port, ahead: 0, connected: false });
+        Ok(self.players.len() - 1)
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn player(&self, index: usize) -> &GB {
+        &self.players[index].gb
+    }
+
+    pub fn player_mut(&mut self, index: usize) -> &mut GB {
+        &mut self.players[index].gb
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+
+    pub fn run_frame(&mut self) {
+        self.run_cycles(crate::gb::CYCLES_PER_FRAME);
+    }
+
+    // Runs every Game Boy and the adapter for the given number of cycles.
+    // Sessions are headless, so the Game Boys' sound is thrown away as it's
+    // made.
+    pub fn run_cycles(&mut self, cycles: u32) {
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let slice = remaining.min(SLICE);
+            // Transfers go to the players that were waiting at the end of
+            // the last slice, who then see them as soon as they run again
+            self.tick(slice);
+            for player in self.players.iter_mut() {
+                player.port.lock().unwrap().waiting = None;
+                if player.ahead >= slice {
+                    player.ahead -= slice;
+                } else {
+                    let ran = player.gb.run_cycles(slice - player.ahead);
+                    player.ahead = player.ahead + ran - slice;
+                }
+                player.gb.take_audio_samples();
+            }
+            remaining -= slice;
+        }
+    }
+
+    fn period(&self) -> u32 {
+        match self.phase {
+            Phase::Transmission => MIN_TRANSFER_PERIOD + (self.rate & 0x0F) as u32 * RATE_STEP,
+            _ => PING_PERIOD,
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.timer += cycles;
+        while self.timer >= self.period() {
+            self.timer -= self.period();
+            self.step();
+        }
+    }
+
+    fn connected_mask(&self) -> u8 {
+        self.players.iter().enumerate()
+            .filter(|(_, p)| p.connected)
+            .fold(0, |mask, (i, _)| mask | (0x10 << i))
+    }
+
+    // Clocks one byte to every player at once. Each player gets its own
+    // byte and answers with whatever it had in SB, or 0xFF if it wasn't
+    // waiting for a transfer.
+    fn transfer(&mut self, outgoing: impl Fn(usize) -> u8) -> Vec<u8> {
+        self.players.iter().enumerate().map(|(i, player)| {
+            let mut port = player.port.lock().unwrap();
+            match port.waiting.take() {
+                Some(sb) => {
+                    port.incoming = Some(outgoing(i));
+                    sb
+                }
+                None => 0xFF,
+            }
+        }).collect()
+    }
+
+    fn step(&mut self) {
+        match self.phase {
+            Phase::Ping => self.step_ping(),
+            Phase::Starting => {
+                self.transfer(|_| START_REPLY);
+                self.index += 1;
+                if self.index == PING_LEN {
+                    self.start_transmission();
+                }
+            }
+            Phase::Transmission => self.step_transmission(),
+        }
+    }
+
+    fn step_ping(&mut self) {
+        let index = self.index;
+        let mask = self.connected_mask();
+        let replies = self.transfer(|i| {
+            if index == 0 { PING_HEADER } else { mask | (i as u8 + 1) }
+        });
+        let first = replies.first().copied().unwrap_or(0xFF);
+        if index == 0 {
+            for (player, &reply) in self.players.iter_mut().zip(replies.iter()) {
+                player.connected = reply == ACK || reply == START_REQUEST;
+            }
+            self.start_requested = true;
+        }
+        self.start_requested &= first == START_REQUEST;
+        match (index, first) {
+            (_, START_REQUEST) => {}
+            (2, rate) => self.rate = rate,
+            (3, size) => self.packet_size = (size as usize).clamp(1, MAX_PACKET_SIZE),
+            _ => {}
+        }
+        self.index += 1;
+        if self.index == PING_LEN {
+            self.index = 0;
+            if self.start_requested && !self.players.is_empty() {
+                self.phase = Phase::Starting;
+            }
+        }
+    }
+
+    fn start_transmission(&mut self) {
+        self.phase = Phase::Transmission;
+        self.index = 0;
+        self.frame_out = vec![0; MAX_PLAYERS * self.packet_size];
+        self.frame_in = vec![vec![]; MAX_PLAYERS];
+    }
+
+    fn step_transmission(&mut self) {
+        let index = self.index;
+        let byte = self.frame_out[index];
+        let replies = self.transfer(|_| byte);
+        if index < self.packet_size {
+            for (packet, &reply) in self.frame_in.iter_mut().zip(replies.iter()) {
+                packet.push(reply);
+            }
+        }
+        self.index += 1;
+        if self.index < self.frame_out.len() {
+            return;
+        }
+        self.index = 0;
+        if self.frame_in[0].iter().all(|&b| b == 0xFF) {
+            self.phase = Phase::Ping;
+            self.start_requested = true;
+            return;
+        }
+        // Empty ports send nothing
+        for (i, packet) in self.frame_in.iter_mut().enumerate() {
+            if i >= self.players.len() {
+                packet.clear();
+            }
+            packet.resize(self.packet_size, 0x00);
+        }
+        self.frame_out = self.frame_in.concat();
+        for packet in self.frame_in.iter_mut() {
+            packet.clear();
+        }
+    }
+}
+
+impl Default for FourPlayerAdapter {
+    fn default() -> FourPlayerAdapter {
+        FourPlayerAdapter::new()
+    }
+}
+
+
+// Four Player Adapter Tests
+#[cfg(test)]
+fn adapter_with_players(count: usize) -> FourPlayerAdapter {
+    let mut adapter = FourPlayerAdapter::new();
+    for _ in 0..count {
+        assert!(adapter.add_player(GB::new()).is_ok());
+    }
+    adapter
+}
+
+// Has every player wait for a transfer with the given SB, runs until the
+// adapter has clocked one byte and returns what each player received
+#[cfg(test)]
+fn exchange(adapter: &mut FourPlayerAdapter, bytes: &[u8]) -> Vec<u8> {
+    for (i, &byte) in bytes.iter().enumerate() {
+        let gb = adapter.player_mut(i);
+        gb.write_byte(0xFF0F, 0);
+        gb.write_byte(0xFF01, byte);
+        gb.write_byte(0xFF02, 0x80);
+    }
+    let period = adapter.period() - adapter.timer;
+    adapter.run_cycles(period);
+    (0..bytes.len()).map(|i| {
+        let gb = adapter.player_mut(i);
+        assert_eq!(gb.read_byte(0xFF02) & 0x80, 0, "player {} didn't get a byte", i + 1);
+        assert_ne!(gb.read_byte(0xFF0F) & (1 << crate::gb::INT_SERIAL), 0);
+        gb.read_byte(0xFF01)
+    }).collect()
+}
+
+#[test]
+fn four_player_add_players() {
+    let mut adapter = adapter_with_players(4);
+    assert_eq!(adapter.player_count(), 4);
+    assert!(adapter.add_player(GB::new()).is_err());
+}
+#[test]
+fn four_player_discards_sound() {
+    let mut adapter = adapter_with_players(2);
+    adapter.run_frame();
+    adapter.run_frame();
+    assert!(adapter.player_mut(0).apu().samples().is_empty());
+    assert!(adapter.player_mut(1).apu().samples().is_empty());
+}
+#[test]
+fn four_player_ping_packets() {
+    let mut adapter = adapter_with_players(2);
+    assert_eq!(exchange(&mut adapter, &[ACK, ACK]), vec![PING_HEADER, PING_HEADER]);
+    // Both players answered the header, so both show up as connected
+    assert_eq!(exchange(&mut adapter, &[ACK, ACK]), vec![0x31, 0x32]);
+    assert_eq!(exchange(&mut adapter, &[0x02, 0x00]), vec![0x31, 0x32]);
+    assert_eq!(exchange(&mut adapter, &[0x03, 0x00]), vec![0x31, 0x32]);
+    assert_eq!(adapter.packet_size(), 3);
+    assert_eq!(exchange(&mut adapter, &[ACK, ACK]), vec![PING_HEADER, PING_HEADER]);
+}
+#[test]
+fn four_player_waiting_player_missing_ping() {
+    let mut adapter = adapter_with_players(2);
+    adapter.player_mut(0).write_byte(0xFF01, ACK);
+    adapter.player_mut(0).write_byte(0xFF02, 0x80);
+    adapter.run_cycles(PING_PERIOD);
+    assert_eq!(adapter.player_mut(0).read_byte(0xFF01), PING_HEADER);
+    // Player 2 wasn't listening, so only player 1 is connected
+    assert_eq!(exchange(&mut adapter, &[ACK, ACK]), vec![0x11, 0x12]);
+}
+#[test]
+fn four_player_transmission() {
+    let mut adapter = adapter_with_players(3);
+    // Negotiate 2 byte packets, then ask to start
+    for &bytes in [[ACK; 3], [ACK; 3], [0x00; 3], [0x02, 0x00, 0x00]].iter() {
+        exchange(&mut adapter, &bytes);
+    }
+    for _ in 0..PING_LEN {
+        exchange(&mut adapter, &[START_REQUEST; 3]);
+    }
+    assert_eq!(adapter.phase(), Phase::Starting);
+    for _ in 0..PING_LEN {
+        assert_eq!(exchange(&mut adapter, &[0; 3]), vec![START_REPLY; 3]);
+    }
+    assert_eq!(adapter.phase(), Phase::Transmission);
+
+    // First frame: nothing buffered yet, players send their packets
+    let frame: Vec<Vec<u8>> = [[0x11, 0x12, 0x13], [0x21, 0x22, 0x23], [0, 0, 0], [0, 0, 0],
+                               [0, 0, 0], [0, 0, 0], [0, 0, 0], [0, 0, 0]]
+        .iter().map(|b| exchange(&mut adapter, b)).collect();
+    assert!(frame.iter().all(|r| r == &vec![0; 3]));
+    // Second frame relays every packet to every player, with nothing from
+    // the empty fourth port
+    let frame: Vec<u8> = (0..8).map(|_| exchange(&mut adapter, &[0; 3])[2]).collect();
+    assert_eq!(frame, vec![0x11, 0x21, 0x12, 0x22, 0x13, 0x23, 0x00, 0x00]);
+
+    // A packet of 0xFF from player 1 goes back to pinging
+    for _ in 0..8 {
+        exchange(&mut adapter, &[0xFF; 3]);
+    }
+    assert_eq!(adapter.phase(), Phase::Ping);
+}
@@ -1,14 +1,83 @@
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::apu::{Apu, Channel};
+use crate::blend::FrameBlender;
+use crate::color::{self, ColorCorrection, OutputPalette};
+use crate::gif::GifRecorder;
+use crate::joypad::{Button, ButtonState, Joypad};
+use crate::midi::MidiWriter;
+use crate::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::scale::{self, Filter};
+use crate::serial::{Serial, SerialDevice};
+use crate::sgb::{Sgb, SGB_HEIGHT, SGB_WIDTH};
+use crate::stitch::MapStitcher;
+use crate::timer::Timer;
+use crate::vgm::VgmWriter;
+use crate::video::VideoWriter;
+use crate::vram_viewer::VramViewer;
+use crate::wav::WavWriter;
+
+pub const CYCLES_PER_FRAME: u32 = 70224;
+
+// Interrupt bits in IF (0xFF0F) and IE (0xFFFF)
+pub const INT_VBLANK: u8 = 0;
+pub const INT_STAT: u8 = 1;
+pub const INT_TIMER: u8 = 2;
+pub const INT_SERIAL: u8 = 3;
+pub const INT_JOYPAD: u8 = 4;
 
 pub struct GB {
-    wram: [u8; 8192],
-    vram: [u8; 8192],
+    // Bank 0 is always at 0xC000, 0xD000 holds the bank selected by SVBK
+    // (only ever bank 1 outside CGB mode)
+    wram: [[u8; 0x1000]; 8],
+    wram_bank: usize,
     cart: Cartridge,
     regs: [u8; 0x80],
-    oam: [u8; 0xA0],
     ime: u8,
     stack: [u8; 0x180],
+    joypad: Joypad,
+    timer: Timer,
+    serial: Serial,
+    ppu: Ppu,
+    color_correction: ColorCorrection,
+    output_palette: OutputPalette,
+    frame_blender: Option<FrameBlender>,
+    apu: Apu,
+    // Set from the cartridge header, enables the CGB-only hardware
+    cgb: bool,
+    double_speed: bool,
+    // KEY1 bit 0, the next STOP switches speed
+    speed_switch_armed: bool,
+    hdma_src: u16,
+    hdma_dst: u16,
+    // Blocks of 16 bytes left minus one, as read back from HDMA5
+    hdma_length: u8,
+    // An HBlank DMA is copying a block at the start of each HBlank
+    hdma_active: bool,
+    // Present when running a game that supports the Super Game Boy
+    sgb: Option<Sgb>,
+    audio_recording: Option<WavWriter<BufWriter<File>>>,
+    // How many of the APU's buffered samples are already in the recording
+    audio_recorded: usize,
+    stem_recordings: Vec<(Channel, WavWriter<BufWriter<File>>)>,
+    vgm_log: Option<VgmWriter<BufWriter<File>>>,
+    midi_recording: Option<MidiWriter<BufWriter<File>>>,
+    // Frames are upscaled with the filter and scale before being recorded
+    video_recording: Option<(VideoWriter, Filter, usize)>,
+    // How many of the APU's buffered samples are already in the video
+    video_audio_recorded: usize,
+    gif_recording: Option<GifRecorder<BufWriter<File>>>,
+    map_stitcher: Option<MapStitcher>,
+    frame_cycles: u32,
+    // Cycles since the last VBlank, to keep recordings going while the LCD
+    // is off
+    cycles_since_vblank: u32,
+    // Total cycles run, used to timestamp logged register writes
+    cycle_count: u64,
 
     af: u16,
     bc: u16,
@@ -21,13 +90,40 @@ pub struct GB {
 impl GB {
     pub fn new() -> GB {
         return GB {
-            wram: [0; 8192],
-            vram: [0; 8192],
+            wram: [[0; 0x1000]; 8],
+            wram_bank: 1,
             cart: Cartridge::new(),
             regs: [0; 0x80],
-            oam: [0; 0xA0],
             ime: 0,
             stack: [0; 0x180],
+            joypad: Joypad::new(),
+            timer: Timer::new(),
+            serial: Serial::new(),
+            ppu: Ppu::new(),
+            color_correction: ColorCorrection::default(),
+            output_palette: OutputPalette::default(),
+            frame_blender: None,
+            apu: Apu::new(),
+            cgb: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            hdma_src: 0,
+            hdma_dst: 0,
+            hdma_length: 0xFF,
+            hdma_active: false,
+            sgb: None,
+            audio_recording: None,
+            audio_recorded: 0,
+            stem_recordings: vec![],
+            vgm_log: None,
+            midi_recording: None,
+            video_recording: None,
+            video_audio_recorded: 0,
+            gif_recording: None,
+            map_stitcher: None,
+            frame_cycles: 0,
+            cycles_since_vblank: 0,
+            cycle_count: 0,
 
             af: 0,
             bc: 0,
@@ -39,60 +135,136 @@ impl GB {
     }
 }
 
+impl Drop for GB {
+    fn drop(&mut self) {
+        let _ = self.stop_audio_recording();
+        let _ = self.stop_audio_stems();
+        let _ = self.stop_vgm_log();
+        let _ = self.stop_midi_recording();
+        let _ = self.stop_video_recording();
+        let _ = self.stop_gif_recording();
+    }
+}
+
 pub struct Cartridge {
-    rom: [u8; 0x8000],
+    rom: Vec<u8>,
     ram: [u8; 0x2000],
+    // When banked, writes to 0x2000-0x3FFF select the ROM bank mapped at
+    // 0x4000-0x7FFF instead of writing to ROM
+    banked: bool,
+    rom_bank: usize,
 }
 
 impl Cartridge {
     pub fn new() -> Cartridge {
         let mut cartridge = Cartridge {
-            rom: [0; 0x8000],
+            rom: vec![0; 0x8000],
             ram: [0; 0x2000],
+            banked: false,
+            rom_bank: 1,
         };
         return cartridge;
     }
+
+    pub fn with_banked_rom(mut rom: Vec<u8>) -> Cartridge {
+        let size = rom.len().div_ceil(0x4000).max(2) * 0x4000;
+        rom.resize(size, 0);
+        return Cartridge {
+            rom,
+            ram: [0; 0x2000],
+            banked: true,
+            rom_bank: 1,
+        };
+    }
+
+    // The header flags games that use CGB features, whether or not they
+    // also run on a DMG
+    pub fn cgb_flag(&self) -> bool {
+        self.rom[0x143] & 0x80 != 0
+    }
+
+    pub fn sgb_flag(&self) -> bool {
+        self.rom[0x146] == 0x03 && self.rom[0x14B] == 0x33
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        if self.banked && addr >= 0x4000 {
+            let offset = self.rom_bank * 0x4000 + (addr as usize - 0x4000);
+            return *self.rom.get(offset).unwrap_or(&0xFF);
+        }
+        return self.rom[addr as usize];
+    }
+
+    fn write_rom(&mut self, addr: u16, val: u8) {
+        if !self.banked {
+            self.rom[addr as usize] = val;
+        } else if addr >= 0x2000 && addr <= 0x3FFF {
+            let banks = self.rom.len() / 0x4000;
+            self.rom_bank = (val as usize).max(1) % banks;
+        }
+    }
 }
 
 impl Cartridge {
-    pub fn load_application(&mut self, filename: &str) -> bool {
-        let mut file = File::open(filename).expect("File error");
-        let fsize = file.metadata().unwrap().len();
-        println!("{:X}", fsize);
-
-        let mut buffer = vec![];
-        file.read_to_end(&mut buffer).expect("couldn't read file");
-        drop(file);
-
-        if (0x8000) >= fsize {
-            for i in 0..fsize
-            {
-                self.rom[i as usize] = buffer[i as usize];
-            }
-        }
-        else {
-            panic!("ROM too big for memory");
+    pub fn load_application(&mut self, filename: &str) -> io::Result<()> {
+        let buffer = std::fs::read(filename)?;
+        if buffer.len() > self.rom.len() {
+            return Err(io::Error::other("ROM too big for memory"));
         }
-        return true;
+        self.rom[..buffer.len()].copy_from_slice(&buffer);
+        Ok(())
     }
 }
 
 impl GB {
     fn mem_write(&mut self, addr: u16, val: u8) {
         if addr <= 0x3FFF {        // ROM Bank
-            self.cart.rom[addr as usize] = val;
+            self.cart.write_rom(addr, val);
         } else if addr >= 0x4000 && addr <= 0x7FFF { // ROM Bank 1-n
-            self.cart.rom[addr as usize] = val;
+            self.cart.write_rom(addr, val);
         } else if addr >= 0x8000 && addr <= 0x9FFF { // VRAM
-            self.vram[(addr - 0x8000) as usize] = val;
+            self.ppu.write_vram(addr, val);
         } else if addr >= 0xA000 && addr <= 0xBFFF { // Cart RAM
             self.cart.ram[(addr - 0xA000) as usize] = val;
-        } else if addr >= 0xC000 && addr <= 0xDFFF { // Low RAM
-            self.wram[(addr - 0xC000) as usize] = val;
-        } else if addr >= 0xE000 && addr <= 0xFDFF { // Low RAM Duplicate
-            self.wram[(addr - 0xE000) as usize] = val;
+        } else if addr >= 0xC000 && addr <= 0xFDFF { // Low RAM and its duplicate
+            let (bank, offset) = self.wram_index(addr);
+            self.wram[bank][offset] = val;
         } else if addr >= 0xFE00 && addr <= 0xFE9F { // OAM RAM
-            self.oam[(addr - 0xFE00) as usize] = val;
+            self.ppu.write_oam(addr, val);
+        } else if addr == 0xFF00 { // Joypad
+            if let Some(sgb) = self.sgb.as_mut() {
+                sgb.write_joypad(val);
+                self.joypad.set_player_count(sgb.player_count());
+            }
+            if self.joypad.write(val) {
+                self.request_interrupt(INT_JOYPAD);
+            }
+        } else if addr >= 0xFF01 && addr <= 0xFF02 { // Serial
+            self.serial.write(addr, val);
+        } else if addr >= 0xFF04 && addr <= 0xFF07 { // Timer
+            self.timer.write(addr, val);
+        } else if addr >= 0xFF10 && addr <= 0xFF3F { // Sound
+            self.log_sound_write(addr, val);
+            self.apu.write(addr, val);
+        } else if addr == 0xFF46 { // OAM DMA
+            self.regs[0x46] = val;
+            self.oam_dma(val);
+        } else if addr >= 0xFF40 && addr <= 0xFF4B { // LCD
+            self.ppu.write(addr, val);
+        } else if addr == 0xFF4D { // CGB Speed Switch
+            if self.cgb {
+                self.speed_switch_armed = val & 0x01 != 0;
+            }
+        } else if addr == 0xFF4F || (addr >= 0xFF68 && addr <= 0xFF6B) { // CGB VRAM Bank and Palettes
+            self.ppu.write(addr, val);
+        } else if addr >= 0xFF51 && addr <= 0xFF55 { // CGB HDMA
+            if self.cgb {
+                self.write_hdma(addr, val);
+            }
+        } else if addr == 0xFF70 { // CGB WRAM Bank
+            if self.cgb {
+                self.wram_bank = ((val & 0x07) as usize).max(1);
+            }
         } else if addr >= 0xFF00 && addr <= 0xFF7F { // I/O Registers
             self.regs[(addr - 0xFF00) as usize] = val;
         } else if addr >= 0xFF80 && addr <= 0xFFFE { // High RAM (Stack)
@@ -104,19 +276,47 @@ impl GB {
 
     fn mem_read(&mut self, addr: u16) -> u8 {
         if addr <= 0x3FFF {        // ROM Bank
-            return self.cart.rom[addr as usize];
+            return self.cart.read_rom(addr);
         } else if addr >= 0x4000 && addr <= 0x7FFF { // ROM Bank 1-n
-            return self.cart.rom[addr as usize];
+            return self.cart.read_rom(addr);
         } else if addr >= 0x8000 && addr <= 0x9FFF { // VRAM
-            return self.vram[(addr - 0x8000) as usize];
+            return self.ppu.read_vram(addr);
         } else if addr >= 0xA000 && addr <= 0xBFFF { // Cart RAM
             return self.cart.ram[(addr - 0xA000) as usize];
-        } else if addr >= 0xC000 && addr <= 0xDFFF { // Low RAM
-            return self.wram[(addr - 0xC000) as usize];
-        } else if addr >= 0xE000 && addr <= 0xFDFF { // Low RAM Duplicate
-            return self.wram[(addr - 0xE000) as usize];
+        } else if addr >= 0xC000 && addr <= 0xFDFF { // Low RAM and its duplicate
+            let (bank, offset) = self.wram_index(addr);
+            return self.wram[bank][offset];
         } else if addr >= 0xFE00 && addr <= 0xFE9F { // OAM RAM
-            return self.oam[(addr - 0xFE00) as usize];
+            return self.ppu.read_oam(addr);
+        } else if addr == 0xFF00 { // Joypad
+            return self.joypad.read();
+        } else if addr >= 0xFF01 && addr <= 0xFF02 { // Serial
+            return self.serial.read(addr);
+        } else if addr >= 0xFF04 && addr <= 0xFF07 { // Timer
+            return self.timer.read(addr);
+        } else if addr >= 0xFF10 && addr <= 0xFF3F { // Sound
+            return self.apu.read(addr);
+        } else if addr == 0xFF46 { // OAM DMA
+            return self.regs[0x46];
+        } else if addr >= 0xFF40 && addr <= 0xFF4B { // LCD
+            return self.ppu.read(addr);
+        } else if addr == 0xFF4D { // CGB Speed Switch
+            if !self.cgb {
+                return 0xFF;
+            }
+            return 0x7E | ((self.double_speed as u8) << 7) | self.speed_switch_armed as u8;
+        } else if addr == 0xFF4F || (addr >= 0xFF68 && addr <= 0xFF6B) { // CGB VRAM Bank and Palettes
+            return self.ppu.read(addr);
+        } else if addr >= 0xFF51 && addr <= 0xFF55 { // CGB HDMA
+            if addr != 0xFF55 || !self.cgb {
+                return 0xFF;
+            }
+            return if self.hdma_active { self.hdma_length & 0x7F } else { 0x80 | self.hdma_length };
+        } else if addr == 0xFF70 { // CGB WRAM Bank
+            if !self.cgb {
+                return 0xFF;
+            }
+            return 0xF8 | self.wram_bank as u8;
         } else if addr >= 0xFF00 && addr <= 0xFF7F { // I/O Registers
             return self.regs[(addr - 0xFF00) as usize];
         } else if addr >= 0xFF80 && addr <= 0xFFFE { // High RAM (Stack)
@@ -127,6 +327,66 @@ impl GB {
         return 0;
     }
 
+    // 0xE000-0xFDFF mirrors 0xC000-0xDDFF
+    fn wram_index(&self, addr: u16) -> (usize, usize) {
+        let offset = (addr & 0x1FFF) as usize;
+        if offset < 0x1000 {
+            (0, offset)
+        } else {
+            (self.wram_bank, offset - 0x1000)
+        }
+    }
+
+    // Copies 160 bytes from 0xXX00 into OAM. The copy is instant rather
+    // than taking 160 M-cycles.
+    fn oam_dma(&mut self, page: u8) {
+        let src = (page as u16) << 8;
+        for i in 0..0xA0 {
+            let val = self.mem_read(src + i);
+            self.ppu.write_oam(0xFE00 + i, val);
+        }
+    }
+
+    fn write_hdma(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF51 => self.hdma_src = (self.hdma_src & 0x00F0) | ((val as u16) << 8),
+            0xFF52 => self.hdma_src = (self.hdma_src & 0xFF00) | (val & 0xF0) as u16,
+            0xFF53 => self.hdma_dst = (self.hdma_dst & 0x00F0) | (((val & 0x1F) as u16) << 8),
+            0xFF54 => self.hdma_dst = (self.hdma_dst & 0x1F00) | (val & 0xF0) as u16,
+            _ => {
+                // Writing with bit 7 clear during an HBlank DMA stops it
+                if self.hdma_active && val & 0x80 == 0 {
+                    self.hdma_active = false;
+                    return;
+                }
+                self.hdma_length = val & 0x7F;
+                if val & 0x80 != 0 {
+                    self.hdma_active = true;
+                    return;
+                }
+                // General purpose DMA copies everything at once while the
+                // CPU waits 8 M-cycles per block (16 in double speed)
+                while self.hdma_length != 0xFF {
+                    self.hdma_block();
+                    self.tick(if self.double_speed { 64 } else { 32 });
+                }
+            }
+        }
+    }
+
+    fn hdma_block(&mut self) {
+        for _ in 0..0x10 {
+            let val = self.mem_read(self.hdma_src);
+            self.ppu.write_vram(0x8000 | (self.hdma_dst & 0x1FFF), val);
+            self.hdma_src = self.hdma_src.wrapping_add(1);
+            self.hdma_dst = (self.hdma_dst + 1) & 0x1FFF;
+        }
+        self.hdma_length = self.hdma_length.wrapping_sub(1);
+        if self.hdma_length == 0xFF {
+            self.hdma_active = false;
+        }
+    }
+
     pub fn print_memory(&mut self) {
         for i in 0..0x200/0x10 {
             let mut line = format!("{:#4X}0: ", i);
@@ -137,7 +397,7 @@ impl GB {
                 );
                 line.push_str(&address);
             }
-            println!("{}", line);
+            eprintln!("{}", line);
         }
     }
 
@@ -183,14 +443,521 @@ impl GB {
 }
 
 impl GB {
-    pub fn load_application(&mut self, filename: &str) -> bool {
-        self.cart.load_application(filename)
+    pub fn load_application(&mut self, filename: &str) -> io::Result<()> {
+        self.cart.load_application(filename)?;
+        self.set_hardware_from_header();
+        Ok(())
+    }
+
+    pub fn load_banked_rom(&mut self, rom: Vec<u8>) {
+        self.cart = Cartridge::with_banked_rom(rom);
+        self.set_hardware_from_header();
+    }
+
+    // Colour games run as on a CGB, games with SGB support as on an SGB
+    fn set_hardware_from_header(&mut self) {
+        let cgb = self.cart.cgb_flag();
+        self.set_cgb_mode(cgb);
+        self.sgb = if !cgb && self.cart.sgb_flag() { Some(Sgb::new()) } else { None };
+        self.joypad.set_player_count(1);
+    }
+
+    fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        self.ppu.set_cgb_mode(cgb);
+        self.wram_bank = 1;
+        self.double_speed = false;
+        self.speed_switch_armed = false;
+        self.hdma_active = false;
+        // Games check for A = 0x11, left there by the CGB boot ROM, to
+        // decide whether to use colour features
+        if cgb {
+            self.set_a(0x11);
+        }
+    }
+
+    pub fn is_cgb(&self) -> bool { self.cgb }
+    pub fn is_double_speed(&self) -> bool { self.double_speed }
+    pub fn is_sgb(&self) -> bool { self.sgb.is_some() }
+
+    pub fn sgb(&self) -> Option<&Sgb> {
+        self.sgb.as_ref()
+    }
+
+    // Size of the picture from frame_rgba, which includes the border on
+    // the SGB
+    pub fn screen_size(&self) -> (usize, usize) {
+        match self.sgb {
+            Some(_) => (SGB_WIDTH, SGB_HEIGHT),
+            None => (SCREEN_WIDTH, SCREEN_HEIGHT),
+        }
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    // Debug views of VRAM and OAM in the current output colours
+    pub fn vram_viewer(&self) -> VramViewer<'_> {
+        VramViewer::new(&self.ppu, self.color_correction, self.output_palette)
+    }
+
+    // Stitches the background into a picture of the whole level as it
+    // scrolls, starting over if already stitching
+    pub fn start_map_stitching(&mut self) {
+        self.map_stitcher = Some(MapStitcher::new());
+    }
+
+    pub fn map_stitcher(&mut self) -> Option<&mut MapStitcher> {
+        self.map_stitcher.as_mut()
+    }
+
+    pub fn stop_map_stitching(&mut self) -> Option<MapStitcher> {
+        self.map_stitcher.take()
+    }
+
+    // The last frame drawn, see Ppu for what the pixel values mean
+    pub fn frame(&self) -> &[u16] {
+        self.ppu.frame()
+    }
+
+    // Only affects CGB colours
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_correction = correction;
+    }
+
+    // Colours the DMG shades are shown in
+    pub fn set_output_palette(&mut self, palette: OutputPalette) {
+        self.output_palette = palette;
+    }
+
+    pub fn output_palette(&self) -> &OutputPalette {
+        &self.output_palette
+    }
+
+    fn blend_frame(&mut self) {
+        if self.frame_blender.is_some() {
+            let frame = self.unblended_frame_rgba();
+            if let Some(blender) = self.frame_blender.as_mut() {
+                blender.push(frame);
+            }
+        }
+    }
+
+    // Blends each frame with the ones before it to simulate LCD ghosting,
+    // or turns blending off with None. A GIF recording in progress keeps
+    // the unblended frames.
+    pub fn set_frame_blending(&mut self, blender: Option<FrameBlender>) {
+        self.frame_blender = blender;
+        self.blend_frame();
+    }
+
+    // The last frame drawn as 8-bit RGBA, after frame blending
+    pub fn frame_rgba(&self) -> Vec<u8> {
+        match self.frame_blender.as_ref().and_then(FrameBlender::output) {
+            Some(blended) => blended,
+            None => self.unblended_frame_rgba(),
+        }
+    }
+
+    fn unblended_frame_rgba(&self) -> Vec<u8> {
+        match self.sgb.as_ref() {
+            // The SGB's colours go to a TV as they are
+            Some(sgb) => {
+                let frame = sgb.render(self.ppu.frame());
+                color::frame_to_rgba(&frame, true, ColorCorrection::Raw, &self.output_palette)
+            }
+            None => color::frame_to_rgba(self.ppu.frame(), self.cgb, self.color_correction, &self.output_palette),
+        }
+    }
+
+    pub fn read_byte(&mut self, addr: u16) -> u8 { self.mem_read(addr) }
+    pub fn write_byte(&mut self, addr: u16, val: u8) { self.mem_write(addr, val) }
+
+    pub fn set_sp(&mut self, sp: u16) { self.sp = sp }
+    pub fn get_pc(&self) -> u16 { return self.pc }
+    pub fn set_pc(&mut self, pc: u16) { self.pc = pc }
+
+    // Calls the routine at `addr` as if from a CALL instruction and runs
+    // it until it returns or max_cycles elapse. Returns the cycles used.
+    pub fn call_routine(&mut self, addr: u16, max_cycles: u32) -> u32 {
+        // Returning to an address that never holds code marks completion
+        const RETURN_ADDR: u16 = 0xFFFD;
+        self.push_r16(RETURN_ADDR);
+        self.pc = addr;
+        let mut cycles = 0;
+        while self.pc != RETURN_ADDR && cycles < max_cycles {
+            cycles += self.emulate_cycle();
+        }
+        return cycles;
+    }
+
+    // Advances the hardware without running the CPU, e.g. while halted
+    pub fn idle(&mut self, cycles: u32) {
+        self.tick(cycles);
+    }
+
+    pub fn request_interrupt(&mut self, bit: u8) {
+        self.regs[0x0F] |= 1 << bit;
+    }
+
+    // Input is sampled by the game whenever it reads P1, so frontends update
+    // it between frames.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_button(button, pressed) {
+            self.request_interrupt(INT_JOYPAD);
+        }
+    }
+
+    pub fn set_buttons(&mut self, buttons: ButtonState) {
+        if self.joypad.set_buttons(buttons) {
+            self.request_interrupt(INT_JOYPAD);
+        }
+    }
+
+    pub fn buttons(&self) -> ButtonState {
+        self.joypad.buttons()
+    }
+
+    // Buttons of the other controllers in SGB multiplayer mode, counting
+    // from 0 for the first player
+    pub fn set_player_buttons(&mut self, player: usize, buttons: ButtonState) {
+        if self.joypad.set_player_buttons(player, buttons) {
+            self.request_interrupt(INT_JOYPAD);
+        }
+    }
+
+    // Plugs a device into the link port, returning the previous one. Nothing
+    // is connected by default.
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        self.serial.connect(device)
+    }
+
+    pub fn disconnect_serial(&mut self) -> Box<dyn SerialDevice> {
+        self.serial.disconnect()
+    }
+
+    pub fn set_audio_sample_rate(&mut self, rate: u32) {
+        self.apu.set_sample_rate(rate);
+    }
+
+    // Interleaved stereo samples generated since the last call, or the
+    // last second or two of them if it's called less often than that
+    pub fn take_audio_samples(&mut self) -> Vec<i16> {
+        self.flush_audio_recording();
+        self.flush_video_audio();
+        self.audio_recorded = 0;
+        self.video_audio_recorded = 0;
+        self.apu.take_samples()
+    }
+
+    // Records the mixed stereo output to a 16-bit PCM WAV file until
+    // stop_audio_recording is called or the GB is dropped.
+    pub fn start_audio_recording<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.stop_audio_recording()?;
+        self.audio_recording = Some(WavWriter::create(path, self.apu.sample_rate(), 2)?);
+        self.audio_recorded = self.apu.samples().len();
+        Ok(())
+    }
+
+    pub fn stop_audio_recording(&mut self) -> io::Result<()> {
+        self.flush_audio_recording();
+        if let Some(mut wav) = self.audio_recording.take() {
+            wav.finalize()?;
+        }
+        Ok(())
+    }
+
+    // Records each sound channel to its own WAV file in `dir`, named after
+    // the channel (square1.wav, square2.wav, wave.wav, noise.wav)
+    pub fn start_audio_stems<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<()> {
+        self.stop_audio_stems()?;
+        std::fs::create_dir_all(&dir)?;
+        for &channel in Channel::ALL.iter() {
+            let path = dir.as_ref().join(format!("{}.wav", channel.name()));
+            let wav = WavWriter::create(path, self.apu.sample_rate(), 2)?;
+            self.stem_recordings.push((channel, wav));
+        }
+        self.apu.set_stems_enabled(true);
+        Ok(())
+    }
+
+    pub fn stop_audio_stems(&mut self) -> io::Result<()> {
+        self.flush_audio_recording();
+        self.apu.set_stems_enabled(false);
+        for (_, mut wav) in self.stem_recordings.drain(..) {
+            wav.finalize()?;
+        }
+        Ok(())
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    // Logs every write to the sound registers and wave RAM to a VGM file
+    // until stop_vgm_log is called or the GB is dropped. The log starts
+    // with writes recreating the current sound state.
+    pub fn start_vgm_log<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.stop_vgm_log()?;
+        let mut vgm = VgmWriter::create(path, self.cycle_count)?;
+        for (addr, val) in self.apu.state_writes() {
+            vgm.write_register(self.cycle_count, addr, val)?;
+        }
+        self.vgm_log = Some(vgm);
+        Ok(())
+    }
+
+    pub fn stop_vgm_log(&mut self) -> io::Result<()> {
+        if let Some(mut vgm) = self.vgm_log.take() {
+            vgm.wait_until(self.cycle_count)?;
+            vgm.finalize()?;
+        }
+        Ok(())
+    }
+
+    // Transcribes the sound channels into a MIDI file, written out when
+    // stop_midi_recording is called or the GB is dropped
+    pub fn start_midi_recording<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.stop_midi_recording()?;
+        self.midi_recording = Some(MidiWriter::create(path, self.cycle_count)?);
+        Ok(())
+    }
+
+    pub fn stop_midi_recording(&mut self) -> io::Result<()> {
+        if let Some(mut midi) = self.midi_recording.take() {
+            midi.update(self.cycle_count, &self.apu);
+            midi.finalize()?;
+        }
+        Ok(())
+    }
+
+    // Records the screen to a video file, captured at each VBlank, until
+    // stop_video_recording is called or the GB is dropped. Files ending in
+    // .avi get uncompressed RGB video with the sound, anything else is
+    // written as YUV4MPEG2 without sound.
+    pub fn start_video_recording<P: AsRef<Path>>(&mut self, path: P, filter: Filter, scale: usize) -> io::Result<()> {
+        self.stop_video_recording()?;
+        if !scale.is_multiple_of(filter.factor()) {
+            return Err(io::Error::other(format!("{:?} needs a scale that is a multiple of {}", filter, filter.factor())));
+        }
+        let (width, height) = self.screen_size();
+        let video = VideoWriter::create(path, width * scale, height * scale, self.apu.sample_rate())?;
+        self.video_recording = Some((video, filter, scale));
+        self.video_audio_recorded = self.apu.samples().len();
+        Ok(())
+    }
+
+    pub fn stop_video_recording(&mut self) -> io::Result<()> {
+        self.flush_video_audio();
+        if let Some((mut video, _, _)) = self.video_recording.take() {
+            video.finalize()?;
+        }
+        Ok(())
+    }
+
+    fn record_video_frame(&mut self) {
+        let (width, height) = self.screen_size();
+        let frame = self.video_recording.as_ref()
+            .map(|&(_, filter, scale)| scale::scale_rgba(&self.frame_rgba(), width, height, filter, scale));
+        if let (Some((video, _, _)), Some(frame)) = (self.video_recording.as_mut(), frame) {
+            let result = frame.map_err(io::Error::other).and_then(|rgba| video.write_frame(&rgba));
+            if let Err(e) = result {
+                eprintln!("video recording failed: {}", e);
+                self.video_recording = None;
+                return;
+            }
+        }
+        self.flush_video_audio();
+    }
+
+    fn flush_video_audio(&mut self) {
+        if let Some((video, _, _)) = self.video_recording.as_mut() {
+            let samples = self.apu.samples();
+            if let Err(e) = video.write_audio(&samples[self.video_audio_recorded..]) {
+                eprintln!("video recording failed: {}", e);
+                self.video_recording = None;
+            }
+            self.video_audio_recorded = samples.len();
+        }
+    }
+
+    // Records the screen as an animated GIF in the output palette's four
+    // shades, keeping one frame in every skip + 1, until stop_gif_recording
+    // is called or the GB is dropped. CGB colours don't fit in the shades,
+    // and neither do blended frames, so CGB games and frame blending can't
+    // be recorded. SGB games are recorded without their border or colours.
+    pub fn start_gif_recording<P: AsRef<Path>>(&mut self, path: P, skip: u32) -> io::Result<()> {
+        self.stop_gif_recording()?;
+        if self.cgb {
+            return Err(io::Error::other("GIF recording doesn't support CGB games"));
+        }
+        if self.frame_blender.is_some() {
+            return Err(io::Error::other("GIF recording doesn't support frame blending"));
+        }
+        let palette = self.output_palette.colors;
+        self.gif_recording = Some(GifRecorder::create(path, SCREEN_WIDTH, SCREEN_HEIGHT, &palette, skip)?);
+        Ok(())
+    }
+
+    pub fn stop_gif_recording(&mut self) -> io::Result<()> {
+        if let Some(mut gif) = self.gif_recording.take() {
+            gif.finalize()?;
+        }
+        Ok(())
+    }
+
+    fn record_gif_frame(&mut self) {
+        if let Some(gif) = self.gif_recording.as_mut() {
+            let shades: Vec<u8> = self.ppu.frame().iter().map(|&shade| shade as u8 & 0x03).collect();
+            if let Err(e) = gif.add_frame(&shades) {
+                eprintln!("GIF recording failed: {}", e);
+                self.gif_recording = None;
+            }
+        }
+    }
+
+    fn log_sound_write(&mut self, addr: u16, val: u8) {
+        if let Some(vgm) = self.vgm_log.as_mut() {
+            if let Err(e) = vgm.write_register(self.cycle_count, addr, val) {
+                eprintln!("VGM log failed: {}", e);
+                self.vgm_log = None;
+            }
+        }
+    }
+
+    fn flush_audio_recording(&mut self) {
+        let apu = &mut self.apu;
+        self.stem_recordings.retain_mut(|(channel, wav)| {
+            let samples = apu.take_stem_samples(*channel);
+            if let Err(e) = wav.write_samples(&samples) {
+                eprintln!("audio stem recording failed: {}", e);
+                return false;
+            }
+            true
+        });
+        if let Some(wav) = self.audio_recording.as_mut() {
+            let samples = self.apu.samples();
+            if let Err(e) = wav.write_samples(&samples[self.audio_recorded..]) {
+                eprintln!("audio recording failed: {}", e);
+                self.audio_recording = None;
+            }
+            self.audio_recorded = samples.len();
+        }
+        self.trim_audio_samples();
+    }
+
+    // Samples wait in the APU for take_audio_samples, but if it isn't
+    // called they'd pile up forever. Once two seconds are waiting, all but
+    // the last second are dropped, though never any a recording still has
+    // to write.
+    fn trim_audio_samples(&mut self) {
+        let second = self.apu.sample_rate() as usize * 2;
+        let buffered = self.apu.samples().len();
+        if buffered <= 2 * second {
+            return;
+        }
+        let mut discard = buffered - second;
+        if self.audio_recording.is_some() {
+            discard = discard.min(self.audio_recorded);
+        }
+        if self.video_recording.is_some() {
+            discard = discard.min(self.video_audio_recorded);
+        }
+        self.apu.discard_samples(discard);
+        self.audio_recorded = self.audio_recorded.saturating_sub(discard);
+        self.video_audio_recorded = self.video_audio_recorded.saturating_sub(discard);
+    }
+
+    // Runs instructions for at least the given number of cycles and returns
+    // how many actually ran
+    pub fn run_cycles(&mut self, cycles: u32) -> u32 {
+        let mut ran = 0;
+        while ran < cycles {
+            ran += self.emulate_cycle();
+        }
+        self.flush_audio_recording();
+        return ran;
+    }
+
+    // Runs instructions until a full frame's worth of cycles has elapsed
+    pub fn run_frame(&mut self) {
+        while self.frame_cycles < CYCLES_PER_FRAME {
+            let cycles = self.emulate_cycle();
+            self.frame_cycles += self.normal_speed_cycles(cycles);
+        }
+        self.frame_cycles -= CYCLES_PER_FRAME;
+        self.flush_audio_recording();
     }
 }
 
 impl GB {
 
     pub fn emulate_cycle(&mut self) -> u32 {
+        let cycles = self.execute_opcode();
+        self.tick(cycles);
+        return cycles;
+    }
+
+    // In double speed the CPU, timer and serial port run twice as fast as
+    // the LCD and sound
+    fn normal_speed_cycles(&self, cycles: u32) -> u32 {
+        if self.double_speed { cycles / 2 } else { cycles }
+    }
+
+    // Advances the hardware alongside the CPU
+    fn tick(&mut self, cycles: u32) {
+        if self.timer.tick(cycles) {
+            self.request_interrupt(INT_TIMER);
+        }
+        if self.serial.tick(cycles) {
+            self.request_interrupt(INT_SERIAL);
+        }
+        let cycles = self.normal_speed_cycles(cycles);
+        self.cycle_count += cycles as u64;
+        let events = self.ppu.tick(cycles);
+        if events.vblank_interrupt {
+            self.request_interrupt(INT_VBLANK);
+            if let Some(sgb) = self.sgb.as_mut() {
+                sgb.vblank(&self.ppu);
+            }
+            self.blend_frame();
+            self.record_video_frame();
+            self.record_gif_frame();
+            if let Some(stitcher) = self.map_stitcher.as_mut() {
+                stitcher.capture(&self.ppu, self.color_correction, self.output_palette);
+            }
+            self.cycles_since_vblank = 0;
+        } else {
+            // With the LCD off there are no VBlanks, so the last frame is
+            // repeated to keep recordings in time with the sound
+            self.cycles_since_vblank += cycles;
+            if self.cycles_since_vblank >= CYCLES_PER_FRAME {
+                self.cycles_since_vblank -= CYCLES_PER_FRAME;
+                self.record_video_frame();
+                self.record_gif_frame();
+            }
+        }
+        if events.stat_interrupt {
+            self.request_interrupt(INT_STAT);
+        }
+        if events.hblank && self.hdma_active {
+            self.hdma_block();
+        }
+        // The frame sequencer follows a higher DIV bit in double speed so
+        // it stays at 512 Hz
+        let div = self.timer.div_counter() >> self.double_speed as u16;
+        self.apu.tick(cycles, div);
+        if let Some(midi) = self.midi_recording.as_mut() {
+            midi.update(self.cycle_count, &self.apu);
+        }
+    }
+
+    fn execute_opcode(&mut self) -> u32 {
         let mut pc_inc: u16 = 0;
         let opcode = (self.mem_read(self.pc), self.mem_read(self.pc+1));
         match opcode {
@@ -485,6 +1252,8 @@ impl GB {
 
             // NOP
             (0x00, _) => { return 4; }
+            // STOP
+            (0x10, _) => { self.stop() }
 
             // LD r16, d16
             (0x01, _) => { self.ld_bc_d16() }
@@ -1497,9 +2266,6 @@ impl GB {
         let mut val = self.mem_read(self.hl);
         let (result, _) = val.overflowing_sub(1);
         self.mem_write(self.hl, result);
-        println!("HL: {:#X}", self.hl);
-        println!("Val: {:#X}", val);
-        println!("Result: {:#X}", result);
 
         // Calculate Z
         if result == 0 {
@@ -1828,6 +2594,16 @@ impl GB {
         self.pc+= 1;
         return 4;
     }
+    // Low power mode isn't emulated, STOP only matters for the CGB speed
+    // switch
+    fn stop(&mut self) -> u32 {
+        if self.cgb && self.speed_switch_armed {
+            self.double_speed = !self.double_speed;
+            self.speed_switch_armed = false;
+        }
+        self.pc += 2;
+        return 4;
+    }
     fn scf(&mut self) -> u32 {
         self.set_cy(1);
         self.set_n(0);
@@ -2495,34 +3271,34 @@ fn ld_a_mem_hl_dec_test() {
 #[test]
 fn ldh_mem_a8_r8_test() {
     let mut gb = GB::new();
-    gb.mem_write(0xFF20, 0x00);
+    gb.mem_write(0xFF90, 0x00);
     gb.set_a(0x11);
-    gb.ldh_mem_a8_r8(0x20, &GB::get_a);
-    assert_eq!(gb.mem_read(0xFF20), 0x11);
+    gb.ldh_mem_a8_r8(0x90, &GB::get_a);
+    assert_eq!(gb.mem_read(0xFF90), 0x11);
 }
 #[test]
 fn ldh_r8_mem_a8_test() {
     let mut gb = GB::new();
-    gb.mem_write(0xFF20, 0x11);
+    gb.mem_write(0xFF90, 0x11);
     gb.set_a(0x00);
-    gb.ldh_r8_mem_a8(&GB::set_a, 0x20);
+    gb.ldh_r8_mem_a8(&GB::set_a, 0x90);
     assert_eq!(gb.get_a(), 0x11);
 }
 #[test]
 fn ld_mem_r8_r8_test() {
     let mut gb = GB::new();
-    gb.mem_write(0xFF20, 0x00);
+    gb.mem_write(0xFF90, 0x00);
     gb.set_a(0x11);
-    gb.set_c(0x20);
+    gb.set_c(0x90);
     gb.ld_mem_r8_r8(&GB::get_c, &GB::get_a);
-    assert_eq!(gb.mem_read(0xFF20), 0x11);
+    assert_eq!(gb.mem_read(0xFF90), 0x11);
 }
 #[test]
 fn ld_r8_mem_r8_test() {
     let mut gb = GB::new();
-    gb.mem_write(0xFF20, 0x11);
+    gb.mem_write(0xFF90, 0x11);
     gb.set_a(0x00);
-    gb.set_c(0x20);
+    gb.set_c(0x90);
     gb.ld_r8_mem_r8(&GB::set_a, &GB::get_c);
     assert_eq!(gb.get_a(), 0x11);
 }
@@ -3067,3 +3843,532 @@ fn daa_hf_sub_test() {
     gb.daa();
     assert_eq!(gb.get_a(), 0x01);
 }
+
+// Joypad Tests
+#[test]
+fn joypad_read_through_p1() {
+    let mut gb = GB::new();
+    gb.set_button(Button::Start, true);
+    gb.mem_write(0xFF00, 0x10);
+    assert_eq!(gb.mem_read(0xFF00), 0xD7);
+    gb.mem_write(0xFF00, 0x20);
+    assert_eq!(gb.mem_read(0xFF00), 0xEF);
+}
+#[test]
+fn joypad_press_requests_interrupt() {
+    let mut gb = GB::new();
+    gb.mem_write(0xFF00, 0x20);
+    gb.set_button(Button::Left, true);
+    assert_eq!(gb.mem_read(0xFF0F) & (1 << INT_JOYPAD), 1 << INT_JOYPAD);
+}
+#[test]
+fn joypad_set_buttons() {
+    let mut gb = GB::new();
+    let mut buttons = ButtonState::new();
+    buttons.set(Button::A, true);
+    buttons.set(Button::B, true);
+    gb.set_buttons(buttons);
+    assert_eq!(gb.buttons(), buttons);
+    gb.mem_write(0xFF00, 0x10);
+    assert_eq!(gb.mem_read(0xFF00), 0xDC);
+}
+
+// Timer Tests
+#[test]
+fn timer_registers_mapped() {
+    let mut gb = GB::new();
+    gb.mem_write(0xFF06, 0x42);
+    gb.mem_write(0xFF07, 0x05);
+    assert_eq!(gb.mem_read(0xFF06), 0x42);
+    assert_eq!(gb.mem_read(0xFF07), 0xFD);
+}
+#[test]
+fn timer_interrupt_requested() {
+    let mut gb = GB::new();
+    gb.mem_write(0xFF06, 0x00);
+    gb.mem_write(0xFF05, 0xFF);
+    gb.mem_write(0xFF07, 0x05);
+    gb.tick(20);
+    assert_eq!(gb.mem_read(0xFF0F) & (1 << INT_TIMER), 1 << INT_TIMER);
+}
+
+// Serial Tests
+#[test]
+fn serial_transfer_requests_interrupt() {
+    let mut gb = GB::new();
+    gb.mem_write(0xFF01, 0x42);
+    gb.mem_write(0xFF02, 0x81);
+    gb.tick(4096);
+    assert_eq!(gb.mem_read(0xFF01), 0xFF);
+    assert_eq!(gb.mem_read(0xFF02), 0x7F);
+    assert_eq!(gb.mem_read(0xFF0F) & (1 << INT_SERIAL), 1 << INT_SERIAL);
+}
+
+// APU Tests
+#[test]
+fn apu_registers_mapped() {
+    let mut gb = GB::new();
+    gb.mem_write(0xFF26, 0x80);
+    gb.mem_write(0xFF24, 0x77);
+    gb.mem_write(0xFF3F, 0x5A);
+    assert_eq!(gb.mem_read(0xFF24), 0x77);
+    assert_eq!(gb.mem_read(0xFF3F), 0x5A);
+    assert_eq!(gb.mem_read(0xFF26), 0xF0);
+}
+#[test]
+fn apu_generates_samples_while_running() {
+    let mut gb = GB::new();
+    gb.set_audio_sample_rate(44100);
+    gb.tick(CYCLES_PER_FRAME);
+    assert_eq!(gb.take_audio_samples().len(), 2 * 738);
+}
+
+// Audio Recording Tests
+#[test]
+fn audio_recording_writes_wav() {
+    let path = std::env::temp_dir().join("gb_emu_audio_recording_test.wav");
+    let mut gb = GB::new();
+    gb.set_audio_sample_rate(32768);
+    gb.start_audio_recording(&path).unwrap();
+    gb.tick(4194304 / 64);
+    let taken = gb.take_audio_samples();
+    gb.tick(4194304 / 64);
+    gb.stop_audio_recording().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(taken.len(), 1024);
+    assert_eq!(bytes.len(), 44 + 2 * 2 * 1024);
+    assert_eq!(&bytes[40..44], &4096u32.to_le_bytes());
+}
+#[test]
+fn audio_samples_are_trimmed_once_recorded() {
+    let path = std::env::temp_dir().join("gb_emu_audio_trim_test.wav");
+    let mut gb = GB::new();
+    gb.set_audio_sample_rate(8192);
+    gb.start_audio_recording(&path).unwrap();
+    let mut ran = 0;
+    for _ in 0..6 {
+        ran += gb.run_cycles(4194304 / 2);
+        // Never more than two seconds wait to be taken
+        assert!(gb.apu().samples().len() <= 2 * 2 * 8192);
+    }
+    gb.stop_audio_recording().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // The recording still has every sample, give or take the unfinished batch
+    let recorded = (bytes.len() - 44) / 4;
+    let expected = ran as usize * 8192 / 4194304;
+    assert!(recorded <= expected && recorded + 32 >= expected);
+    // The last second or two are kept for take_audio_samples
+    let kept = gb.take_audio_samples().len();
+    assert!(kept >= 2 * 8192 && kept <= 2 * 2 * 8192);
+}
+#[test]
+fn audio_stems_write_one_file_per_channel() {
+    let dir = std::env::temp_dir().join("gb_emu_audio_stems_test");
+    let mut gb = GB::new();
+    gb.set_audio_sample_rate(32768);
+    gb.start_audio_stems(&dir).unwrap();
+    gb.tick(4194304 / 64);
+    gb.stop_audio_stems().unwrap();
+    for name in ["square1", "square2", "wave", "noise"].iter() {
+        let bytes = std::fs::read(dir.join(format!("{}.wav", name))).unwrap();
+        assert_eq!(bytes.len(), 44 + 2 * 2 * 512);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(!gb.apu().stems_enabled());
+}
+
+// VGM Log Tests
+#[test]
+fn vgm_log_records_sound_writes() {
+    let path = std::env::temp_dir().join("gb_emu_vgm_log_test.vgm");
+    let mut gb = GB::new();
+    gb.mem_write(0xFF26, 0x80);
+    gb.start_vgm_log(&path).unwrap();
+    gb.tick(CYCLES_PER_FRAME);
+    gb.mem_write(0xFF24, 0x77);
+    gb.mem_write(0xFF40, 0x91);
+    gb.stop_vgm_log().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // NR52, 22 registers less NR15/NR1F, and 16 bytes of wave RAM
+    let state_len = 3 * (1 + 20 + 16);
+    let log = &bytes[0x100 + state_len..];
+    assert_eq!(&bytes[0x100..0x103], &[0xB3, 0x16, 0x80]);
+    // A frame is 738 samples at 44100 Hz
+    assert_eq!(log, &[0x61, 0xE2, 0x02, 0xB3, 0x14, 0x77, 0x66]);
+}
+
+// MIDI Recording Tests
+#[test]
+fn midi_recording_writes_smf() {
+    let path = std::env::temp_dir().join("gb_emu_midi_recording_test.mid");
+    let mut gb = GB::new();
+    gb.mem_write(0xFF26, 0x80);
+    gb.start_midi_recording(&path).unwrap();
+    gb.mem_write(0xFF17, 0xF0);
+    gb.mem_write(0xFF19, 0x87);
+    gb.tick(CYCLES_PER_FRAME);
+    gb.stop_midi_recording().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(&bytes[0..4], b"MThd");
+    // Note on for square 2
+    assert!(bytes.windows(3).any(|w| w == [0x91, 72, 100]));
+}
+
+// Video Recording Tests
+#[test]
+fn video_recording_captures_each_vblank() {
+    let path = std::env::temp_dir().join("gb_emu_video_recording_test.y4m");
+    let mut gb = GB::new();
+    gb.mem_write(0xFF40, 0x91);
+    gb.start_video_recording(&path, Filter::Nearest, 2).unwrap();
+    for _ in 0..3 {
+        gb.tick(CYCLES_PER_FRAME);
+    }
+    gb.stop_video_recording().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let header = b"YUV4MPEG2 W320 H288 F4194304:70224 Ip A1:1 C444\n";
+    assert_eq!(&bytes[..header.len()], &header[..]);
+    assert_eq!(bytes.len(), header.len() + 3 * (6 + 320 * 288 * 3));
+    assert!(gb.start_video_recording(&path, Filter::Scale3x, 2).is_err());
+}
+#[test]
+fn video_recording_repeats_frames_with_lcd_off() {
+    let path = std::env::temp_dir().join("gb_emu_video_recording_lcd_off_test.y4m");
+    let mut gb = GB::new();
+    gb.mem_write(0xFF40, 0x91);
+    gb.start_video_recording(&path, Filter::Nearest, 1).unwrap();
+    gb.tick(CYCLES_PER_FRAME);
+    gb.mem_write(0xFF40, 0x00);
+    for _ in 0..4 {
+        gb.tick(CYCLES_PER_FRAME / 2);
+    }
+    gb.stop_video_recording().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let frames = bytes.windows(6).filter(|w| w == b"FRAME\n").count();
+    assert_eq!(frames, 3);
+}
+#[test]
+fn video_recording_interleaves_sound_in_avi() {
+    let path = std::env::temp_dir().join("gb_emu_video_recording_test.avi");
+    let mut gb = GB::new();
+    gb.set_audio_sample_rate(32768);
+    gb.mem_write(0xFF40, 0x91);
+    gb.start_video_recording(&path, Filter::Nearest, 1).unwrap();
+    for _ in 0..2 {
+        gb.tick(CYCLES_PER_FRAME);
+    }
+    let taken = gb.take_audio_samples();
+    gb.stop_video_recording().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let idx = bytes.windows(4).position(|w| w == b"idx1").unwrap();
+    let movi = &bytes[..idx];
+    let frames = movi.windows(4).filter(|w| w == b"00db").count();
+    let audio: usize = movi.windows(8)
+        .filter(|w| &w[..4] == b"01wb")
+        .map(|w| u32::from_le_bytes([w[4], w[5], w[6], w[7]]) as usize)
+        .sum();
+    assert_eq!(frames, 2);
+    assert_eq!(audio, 2 * taken.len());
+}
+
+// GIF Recording Tests
+#[test]
+fn gif_recording_writes_dmg_shades() {
+    let path = std::env::temp_dir().join("gb_emu_gif_recording_test.gif");
+    let mut gb = GB::new();
+    gb.set_output_palette(OutputPalette::CLASSIC_GREEN);
+    gb.mem_write(0xFF40, 0x91);
+    gb.start_gif_recording(&path, 0).unwrap();
+    for _ in 0..3 {
+        gb.tick(CYCLES_PER_FRAME);
+    }
+    gb.stop_gif_recording().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(&bytes[..10], b"GIF89a\xA0\x00\x90\x00");
+    assert_eq!(&bytes[13..16], &[0x9B, 0xBC, 0x0F]);
+    assert_eq!(bytes[bytes.len() - 1], 0x3B);
+
+    gb.cgb = true;
+    assert!(gb.start_gif_recording(&path, 0).is_err());
+    gb.cgb = false;
+    gb.set_frame_blending(Some(FrameBlender::parse("0.5").unwrap()));
+    assert!(gb.start_gif_recording(&path, 0).is_err());
+}
+#[test]
+fn gif_recording_sgb_game_in_dmg_shades() {
+    let path = std::env::temp_dir().join("gb_emu_gif_recording_sgb_test.gif");
+    let mut rom = vec![0; 0x8000];
+    rom[0x146] = 0x03;
+    rom[0x14B] = 0x33;
+    let mut gb = GB::new();
+    gb.load_banked_rom(rom);
+    assert!(gb.sgb.is_some());
+    gb.mem_write(0xFF40, 0x91);
+    gb.start_gif_recording(&path, 0).unwrap();
+    gb.tick(CYCLES_PER_FRAME);
+    gb.stop_gif_recording().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // The Game Boy screen without the SGB border
+    assert_eq!(&bytes[..10], b"GIF89a\xA0\x00\x90\x00");
+}
+#[test]
+fn gif_recording_keeps_time_with_lcd_off() {
+    let path = std::env::temp_dir().join("gb_emu_gif_recording_lcd_off_test.gif");
+    let mut gb = GB::new();
+    gb.mem_write(0xFF40, 0x91);
+    gb.start_gif_recording(&path, 0).unwrap();
+    gb.tick(CYCLES_PER_FRAME);
+    gb.mem_write(0xFF40, 0x00);
+    for _ in 0..59 {
+        gb.tick(CYCLES_PER_FRAME);
+    }
+    gb.stop_gif_recording().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // One unchanging frame lasting the whole second
+    let delays: Vec<u16> = bytes.windows(6)
+        .filter(|w| w[..4] == [0x21, 0xF9, 0x04, 0x00])
+        .map(|w| u16::from_le_bytes([w[4], w[5]]))
+        .collect();
+    assert_eq!(delays, vec![100]);
+}
+
+// Map Stitching Tests
+#[test]
+fn map_stitching_captures_each_vblank() {
+    let mut gb = GB::new();
+    gb.mem_write(0xFF40, 0x91);
+    gb.start_map_stitching();
+    for scx in 0..3 {
+        gb.mem_write(0xFF43, scx * 8);
+        gb.tick(CYCLES_PER_FRAME);
+    }
+    let stitcher = gb.stop_map_stitching().unwrap();
+    assert_eq!(stitcher.frames(), 3);
+    assert_eq!(stitcher.image().width, 160 + 2 * 8);
+    assert!(gb.map_stitcher().is_none());
+}
+
+// Cartridge Tests
+#[test]
+fn banked_rom_switches_banks() {
+    let mut rom = vec![0; 0x10000];
+    rom[0x4000] = 1;
+    rom[0x8000] = 2;
+    rom[0xC000] = 3;
+    let mut gb = GB::new();
+    gb.load_banked_rom(rom);
+    assert_eq!(gb.mem_read(0x4000), 1);
+    gb.mem_write(0x2000, 3);
+    assert_eq!(gb.mem_read(0x4000), 3);
+    assert_eq!(gb.mem_read(0x0000), 0);
+    // Bank 0 maps to bank 1
+    gb.mem_write(0x2000, 0);
+    assert_eq!(gb.mem_read(0x4000), 1);
+}
+#[test]
+fn call_routine_runs_until_return() {
+    let mut gb = GB::new();
+    let mut rom = vec![0; 0x8000];
+    rom[0x200] = 0x3C; // INC A
+    rom[0x201] = 0x3C; // INC A
+    rom[0x202] = 0xC9; // RET
+    gb.load_banked_rom(rom);
+    gb.set_a(0);
+    let cycles = gb.call_routine(0x200, 1000);
+    assert_eq!(gb.get_a(), 2);
+    assert_eq!(cycles, 4 + 4 + 16);
+}
+
+// CGB Tests
+#[cfg(test)]
+fn cgb_gb() -> GB {
+    let mut rom = vec![0; 0x8000];
+    rom[0x143] = 0x80;
+    let mut gb = GB::new();
+    gb.load_banked_rom(rom);
+    gb
+}
+
+#[test]
+fn cgb_mode_from_header() {
+    let gb = cgb_gb();
+    assert!(gb.is_cgb());
+    assert_eq!(gb.af >> 8, 0x11);
+    let mut gb = GB::new();
+    gb.load_banked_rom(vec![0; 0x8000]);
+    assert!(!gb.is_cgb());
+    assert_eq!(gb.mem_read(0xFF70), 0xFF);
+    assert_eq!(gb.mem_read(0xFF4D), 0xFF);
+}
+#[test]
+fn cgb_wram_banks() {
+    let mut gb = cgb_gb();
+    gb.mem_write(0xC000, 0x10);
+    for bank in 1..8 {
+        gb.mem_write(0xFF70, bank);
+        gb.mem_write(0xD000, bank);
+    }
+    // Bank 0 selects bank 1
+    gb.mem_write(0xFF70, 0);
+    assert_eq!(gb.mem_read(0xFF70), 0xF9);
+    assert_eq!(gb.mem_read(0xD000), 1);
+    gb.mem_write(0xFF70, 5);
+    assert_eq!(gb.mem_read(0xD000), 5);
+    assert_eq!(gb.mem_read(0xF000), 5);
+    assert_eq!(gb.mem_read(0xC000), 0x10);
+}
+#[test]
+fn dmg_ignores_wram_bank_select() {
+    let mut gb = GB::new();
+    gb.mem_write(0xD000, 1);
+    gb.mem_write(0xFF70, 2);
+    assert_eq!(gb.mem_read(0xD000), 1);
+}
+#[test]
+fn cgb_speed_switch() {
+    let mut gb = cgb_gb();
+    gb.mem_write(0xFF4D, 0x01);
+    assert_eq!(gb.mem_read(0xFF4D), 0x7F);
+    gb.cart.rom[0x100] = 0x10; // STOP
+    gb.pc = 0x100;
+    gb.emulate_cycle();
+    assert_eq!(gb.pc, 0x102);
+    assert!(gb.is_double_speed());
+    assert_eq!(gb.mem_read(0xFF4D), 0xFE);
+    // The LCD now only advances half a cycle per CPU cycle
+    gb.mem_write(0xFF40, 0x80);
+    gb.tick(2 * 456);
+    assert_eq!(gb.mem_read(0xFF44), 1);
+}
+#[test]
+fn cgb_general_purpose_hdma() {
+    let mut gb = cgb_gb();
+    for i in 0..0x20 {
+        gb.mem_write(0xC000 + i, i as u8);
+    }
+    gb.mem_write(0xFF4F, 1);
+    gb.mem_write(0xFF51, 0xC0);
+    gb.mem_write(0xFF52, 0x00);
+    gb.mem_write(0xFF53, 0x81);
+    gb.mem_write(0xFF54, 0x00);
+    gb.mem_write(0xFF55, 0x01);
+    assert_eq!(gb.mem_read(0xFF55), 0xFF);
+    assert_eq!(gb.mem_read(0x8100), 0x00);
+    assert_eq!(gb.mem_read(0x811F), 0x1F);
+    assert_eq!(gb.ppu().vram(0)[0x100], 0);
+    assert_eq!(gb.cycle_count, 64);
+}
+#[test]
+fn cgb_hblank_hdma() {
+    let mut gb = cgb_gb();
+    for i in 0..0x30 {
+        gb.mem_write(0xC000 + i, 0xA0 + i as u8);
+    }
+    gb.mem_write(0xFF51, 0xC0);
+    gb.mem_write(0xFF52, 0x00);
+    gb.mem_write(0xFF53, 0x00);
+    gb.mem_write(0xFF54, 0x00);
+    gb.mem_write(0xFF55, 0x82);
+    assert_eq!(gb.mem_read(0xFF55), 0x02);
+    assert_eq!(gb.mem_read(0x8000), 0);
+    gb.mem_write(0xFF40, 0x80);
+    // One block per line
+    gb.tick(456);
+    assert_eq!(gb.mem_read(0xFF55), 0x01);
+    assert_eq!(gb.mem_read(0x800F), 0xAF);
+    assert_eq!(gb.mem_read(0x8010), 0);
+    // Stopping it leaves the remaining length readable
+    gb.mem_write(0xFF55, 0x00);
+    assert_eq!(gb.mem_read(0xFF55), 0x81);
+    gb.tick(456);
+    assert_eq!(gb.mem_read(0x8010), 0);
+}
+#[test]
+fn oam_dma_copies_to_oam() {
+    let mut gb = GB::new();
+    for i in 0..0xA0 {
+        gb.mem_write(0xC100 + i, i as u8);
+    }
+    gb.mem_write(0xFF46, 0xC1);
+    assert_eq!(gb.mem_read(0xFE00), 0);
+    assert_eq!(gb.mem_read(0xFE9F), 0x9F);
+}
+#[test]
+fn lcd_interrupts_requested() {
+    let mut gb = GB::new();
+    gb.mem_write(0xFF40, 0x80);
+    gb.mem_write(0xFF41, 0x08);
+    gb.tick(252);
+    assert_eq!(gb.mem_read(0xFF0F) & (1 << INT_STAT), 1 << INT_STAT);
+    gb.tick(144 * 456);
+    assert_eq!(gb.mem_read(0xFF0F) & (1 << INT_VBLANK), 1 << INT_VBLANK);
+}
+
+// SGB Tests
+#[test]
+fn sgb_mode_from_header() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x146] = 0x03;
+    rom[0x14B] = 0x33;
+    let mut gb = GB::new();
+    gb.load_banked_rom(rom.clone());
+    assert!(gb.is_sgb());
+    assert_eq!(gb.screen_size(), (256, 224));
+    assert_eq!(gb.frame_rgba().len(), 256 * 224 * 4);
+    // Colour games run in CGB mode instead
+    rom[0x143] = 0xC0;
+    gb.load_banked_rom(rom);
+    assert!(!gb.is_sgb());
+    assert_eq!(gb.screen_size(), (160, 144));
+}
+#[test]
+fn sgb_multiplayer_through_p1() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x146] = 0x03;
+    rom[0x14B] = 0x33;
+    let mut gb = GB::new();
+    gb.load_banked_rom(rom);
+    // MLT_REQ for two players
+    let mut packet = [0u8; 16];
+    packet[0] = 0x11 << 3 | 1;
+    packet[1] = 0x01;
+    gb.mem_write(0xFF00, 0x00);
+    gb.mem_write(0xFF00, 0x30);
+    for i in 0..128 {
+        let bit = (packet[i / 8] >> (i % 8)) & 1;
+        gb.mem_write(0xFF00, if bit == 1 { 0x10 } else { 0x20 });
+        gb.mem_write(0xFF00, 0x30);
+    }
+    gb.mem_write(0xFF00, 0x20);
+    gb.mem_write(0xFF00, 0x30);
+    assert_eq!(gb.mem_read(0xFF00) & 0x0F, 0x0F);
+    gb.mem_write(0xFF00, 0x10);
+    gb.mem_write(0xFF00, 0x30);
+    assert_eq!(gb.mem_read(0xFF00) & 0x0F, 0x0E);
+}
+
+// Frame Blending Tests
+#[test]
+fn frame_blending_mixes_with_previous_frame() {
+    let mut gb = GB::new();
+    gb.mem_write(0xFF47, 0x00);
+    gb.set_frame_blending(Some(FrameBlender::new(vec![1.0, 1.0]).unwrap()));
+    assert_eq!(&gb.frame_rgba()[0..4], &[0xFF, 0xFF, 0xFF, 0xFF]);
+    // Draw a black frame
+    gb.mem_write(0xFF47, 0xFF);
+    gb.mem_write(0xFF40, 0x81);
+    gb.tick(CYCLES_PER_FRAME);
+    assert_eq!(&gb.frame_rgba()[0..4], &[0x80, 0x80, 0x80, 0xFF]);
+    gb.set_frame_blending(None);
+    assert_eq!(&gb.frame_rgba()[0..4], &[0x00, 0x00, 0x00, 0xFF]);
+}
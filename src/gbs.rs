@@ -0,0 +1,262 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::apu::CPU_CLOCK;
+use crate::gb::{CYCLES_PER_FRAME, GB};
+
+const HEADER_LEN: usize = 0x70;
+// Upper bound on a single INIT or PLAY call so a runaway routine can't hang
+// the player
+const MAX_ROUTINE_CYCLES: u32 = CPU_CLOCK;
+
+pub struct GbsHeader {
+    pub song_count: u8,
+    pub first_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    // Number of cycles between PLAY calls: either the timer overflow rate
+    // described by TMA/TAC, or VBlank if the timer isn't used
+    pub fn play_period(&self) -> u32 {
+        if self.timer_control & 0x04 == 0 {
+            return CYCLES_PER_FRAME;
+        }
+        let input_clock = match self.timer_control & 0x03 {
+            0 => 4096,
+            1 => 262144,
+            2 => 65536,
+            _ => 16384,
+        };
+        let period = CPU_CLOCK / input_clock * (256 - self.timer_modulo as u32);
+        // Bit 7 requests CGB double speed
+        if self.timer_control & 0x80 != 0 {
+            period / 2
+        } else {
+            period
+        }
+    }
+}
+
+pub struct Gbs {
+    pub header: GbsHeader,
+    data: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn parse(bytes: &[u8]) -> Result<Gbs, String> {
+        if bytes.len() < HEADER_LEN || &bytes[0..3] != b"GBS" {
+            return Err("not a GBS file".to_string());
+        }
+        if bytes[3] != 1 {
+            return Err(format!("unsupported GBS version {}", bytes[3]));
+        }
+        let word = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let text = |i: usize| {
+            let field = &bytes[i..i + 32];
+            let end = field.iter().position(|&b| b == 0).unwrap_or(32);
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+        let header = GbsHeader {
+            song_count: bytes[4],
+            first_song: bytes[5],
+            load_addr: word(0x06),
+            init_addr: word(0x08),
+            play_addr: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+        };
+        if header.load_addr < 0x400 || header.load_addr >= 0x8000 {
+            return Err(format!("invalid load address {:#06X}", header.load_addr));
+        }
+        Ok(Gbs { header, data: bytes[HEADER_LEN..].to_vec() })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Gbs> {
+        let bytes = fs::read(path)?;
+        Gbs::parse(&bytes).map_err(io::Error::other)
+    }
+
+    // Builds the ROM image the code expects: the data placed at the load
+    // address, with the RST vectors redirected to load address + vector
+    fn rom_image(&self) -> Vec<u8> {
+        let load = self.header.load_addr as usize;
+        let mut rom = vec![0; load + self.data.len()];
+        rom[load..].copy_from_slice(&self.data);
+        for vector in (0..0x40).step_by(8) {
+            let target = (load + vector) as u16;
+            rom[vector] = 0xC3; // JP a16
+            rom[vector + 1] = target as u8;
+            rom[vector + 2] = (target >> 8) as u8;
+        }
+        rom
+    }
+}
+
+// Runs a GBS rip on the CPU core: INIT once per track, then PLAY at the
+// rate given by the header while the rest of the hardware keeps running.
+pub struct GbsPlayer {
+    gb: GB,
+    gbs: Gbs,
+    play_period: u32,
+    // Cycles the last run() played past what it was asked for, which the
+    // next run() counts towards its own length
+    overshoot: u64,
+}
+
+impl GbsPlayer {
+    pub fn new(gbs: Gbs) -> GbsPlayer {
+        let play_period = gbs.header.play_period();
+        GbsPlayer { gb: GB::new(), gbs, play_period, overshoot: 0 }
+    }
+
+    pub fn header(&self) -> &GbsHeader {
+        &self.gbs.header
+    }
+
+    pub fn gb_mut(&mut self) -> &mut GB {
+        &mut self.gb
+    }
+
+    // Tracks are numbered from 0
+    pub fn start_track(&mut self, track: u8) -> Result<(), String> {
+        if track >= self.gbs.header.song_count {
+            return Err(format!("track {} out of range (0-{})", track, self.gbs.header.song_count.saturating_sub(1)));
+        }
+        self.gb.load_banked_rom(self.gbs.rom_image());
+        self.gb.write_byte(0xFF26, 0x80);
+        self.gb.write_byte(0xFF25, 0xFF);
+        self.gb.write_byte(0xFF24, 0x77);
+        self.gb.write_byte(0xFF06, self.gbs.header.timer_modulo);
+        self.gb.write_byte(0xFF07, self.gbs.header.timer_control & 0x07);
+        self.gb.set_sp(self.gbs.header.stack_pointer);
+        self.gb.set_a(track);
+        self.gb.call_routine(self.gbs.header.init_addr, MAX_ROUTINE_CYCLES);
+        self.overshoot = 0;
+        Ok(())
+    }
+
+    // Plays for at least `cycles` cycles, calling PLAY once per period.
+    // Whole periods are played, so consecutive calls keep to the total
+    // length asked for rather than each rounding up.
+    pub fn run(&mut self, cycles: u64) {
+        let mut elapsed = self.overshoot;
+        while elapsed < cycles {
+            let used = self.gb.call_routine(self.gbs.header.play_addr, MAX_ROUTINE_CYCLES);
+            if used < self.play_period {
+                self.gb.idle(self.play_period - used);
+            }
+            elapsed += self.play_period.max(used) as u64;
+        }
+        self.overshoot = elapsed - cycles;
+    }
+
+    pub fn render_to_wav<P: AsRef<Path>>(&mut self, path: P, seconds: u32) -> io::Result<()> {
+        self.gb.start_audio_recording(path)?;
+        for _ in 0..seconds {
+            self.run(CPU_CLOCK as u64);
+            self.gb.take_audio_samples();
+        }
+        self.gb.stop_audio_recording()
+    }
+}
+
+
+// GBS Tests
+#[cfg(test)]
+fn test_gbs(code: &[u8], timer_control: u8) -> Vec<u8> {
+    let mut bytes = vec![0; HEADER_LEN];
+    bytes[0..3].copy_from_slice(b"GBS");
+    bytes[3] = 1;
+    bytes[4] = 3;
+    bytes[5] = 1;
+    bytes[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+    bytes[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+    bytes[0x0A..0x0C].copy_from_slice(&0x0410u16.to_le_bytes());
+    bytes[0x0C..0x0E].copy_from_slice(&0xDFFFu16.to_le_bytes());
+    bytes[0x0E] = 0xC0;
+    bytes[0x0F] = timer_control;
+    bytes[0x10..0x15].copy_from_slice(b"Title");
+    bytes.extend_from_slice(code);
+    bytes
+}
+
+#[test]
+fn gbs_parse_header() {
+    let gbs = Gbs::parse(&test_gbs(&[0xC9], 0)).unwrap();
+    assert_eq!(gbs.header.song_count, 3);
+    assert_eq!(gbs.header.first_song, 1);
+    assert_eq!(gbs.header.load_addr, 0x400);
+    assert_eq!(gbs.header.play_addr, 0x410);
+    assert_eq!(gbs.header.stack_pointer, 0xDFFF);
+    assert_eq!(gbs.header.title, "Title");
+    assert_eq!(gbs.header.author, "");
+}
+#[test]
+fn gbs_parse_rejects_bad_magic() {
+    assert!(Gbs::parse(&[0; HEADER_LEN]).is_err());
+}
+#[test]
+fn gbs_play_period() {
+    let vblank = Gbs::parse(&test_gbs(&[], 0x00)).unwrap();
+    assert_eq!(vblank.header.play_period(), CYCLES_PER_FRAME);
+    // 4096 Hz / (256 - 0xC0)
+    let timer = Gbs::parse(&test_gbs(&[], 0x04)).unwrap();
+    assert_eq!(timer.header.play_period(), 1024 * 64);
+    let double = Gbs::parse(&test_gbs(&[], 0x84)).unwrap();
+    assert_eq!(double.header.play_period(), 512 * 64);
+}
+#[test]
+fn gbs_rom_image_places_code() {
+    let gbs = Gbs::parse(&test_gbs(&[0xAA, 0xBB], 0)).unwrap();
+    let rom = gbs.rom_image();
+    assert_eq!(&rom[0x400..0x402], &[0xAA, 0xBB]);
+    assert_eq!(&rom[0x08..0x0B], &[0xC3, 0x08, 0x04]);
+}
+#[test]
+fn gbs_track_range_checked() {
+    let gbs = Gbs::parse(&test_gbs(&[0xC9], 0)).unwrap();
+    let mut player = GbsPlayer::new(gbs);
+    assert!(player.start_track(3).is_err());
+}
+#[test]
+fn gbs_init_receives_track() {
+    // INIT copies the track number from A into B where the test can see it
+    let mut code = vec![0x47, 0xC9]; // LD B, A; RET
+    code.resize(0x10, 0);
+    code.push(0xC9); // PLAY: RET
+    let gbs = Gbs::parse(&test_gbs(&code, 0)).unwrap();
+    let mut player = GbsPlayer::new(gbs);
+    player.start_track(2).unwrap();
+    assert_eq!(player.gb_mut().get_b(), 2);
+}
+#[test]
+fn gbs_run_keeps_overshoot_between_calls() {
+    // PLAY counts its calls in HL
+    let mut code = vec![0xC9];
+    code.resize(0x10, 0);
+    code.extend_from_slice(&[0x23, 0xC9]); // INC HL; RET
+    let gbs = Gbs::parse(&test_gbs(&code, 0)).unwrap();
+    let mut player = GbsPlayer::new(gbs);
+    player.start_track(0).unwrap();
+    player.gb_mut().set_hl(0);
+    for _ in 0..10 {
+        player.run(CPU_CLOCK as u64);
+    }
+    // 10 seconds is 597.3 frames, not 10 lots of 60
+    let calls = (CPU_CLOCK as u64 * 10).div_ceil(CYCLES_PER_FRAME as u64);
+    assert_eq!(player.gb_mut().get_hl() as u64, calls);
+}
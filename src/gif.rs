@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::apu::CPU_CLOCK;
+use crate::gb::CYCLES_PER_FRAME;
+
+// Viewers slow down anything shorter than 2 centiseconds, so frames that
+// would be shown for less are dropped and their time given to the next
+const MIN_DELAY: u64 = 2;
+const MAX_CODE_SIZE: u8 = 12;
+
+// Writes frames of palette indices as an animated GIF that loops forever.
+//
+// Each frame added stands for one frame of the LCD, and frame delays are
+// rounded from the running total of time so the animation keeps to 59.73
+// Hz overall. Only every (skip + 1)th frame is kept, and a frame that is
+// the same as the one before just extends its delay.
+pub struct GifRecorder<W: Write> {
+    out: W,
+    width: usize,
+    height: usize,
+    min_code_size: u8,
+    skip: u32,
+    // Frames added so far
+    frames: u64,
+    // The last frame kept, written once its delay is known
+    pending: Option<Vec<u8>>,
+    // Centiseconds of delay written so far
+    written: u64,
+    finished: bool,
+}
+
+impl GifRecorder<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, width: usize, height: usize, palette: &[[u8; 3]], skip: u32) -> io::Result<GifRecorder<BufWriter<File>>> {
+        GifRecorder::new(BufWriter::new(File::create(path)?), width, height, palette, skip)
+    }
+}
+
+impl<W: Write> GifRecorder<W> {
+    pub fn new(mut out: W, width: usize, height: usize, palette: &[[u8; 3]], skip: u32) -> io::Result<GifRecorder<W>> {
+        if palette.is_empty() || palette.len() > 256 {
+            return Err(io::Error::other("a GIF palette needs 1 to 256 colours"));
+        }
+        if width > 0xFFFF || height > 0xFFFF {
+            return Err(io::Error::other("picture too large for a GIF"));
+        }
+        // The colour table holds a power of two colours, at least 2
+        let mut table_bits = 1;
+        while 1 << table_bits < palette.len() {
+            table_bits += 1;
+        }
+
+        out.write_all(b"GIF89a")?;
+        out.write_all(&(width as u16).to_le_bytes())?;
+        out.write_all(&(height as u16).to_le_bytes())?;
+        out.write_all(&[0x80 | ((table_bits - 1) << 4) | (table_bits - 1), 0, 0])?;
+        for i in 0..1 << table_bits {
+            out.write_all(palette.get(i).unwrap_or(&[0; 3]))?;
+        }
+        // Loop forever
+        out.write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00")?;
+        Ok(GifRecorder {
+            out,
+            width,
+            height,
+            min_code_size: table_bits.max(2),
+            skip,
+            frames: 0,
+            pending: None,
+            written: 0,
+            finished: false,
+        })
+    }
+
+    pub fn add_frame(&mut self, indices: &[u8]) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other("GIF recording already finished"));
+        }
+        if indices.len() != self.width * self.height {
+            return Err(io::Error::other("frame doesn't match GIF size"));
+        }
+        let frame = self.frames;
+        self.frames += 1;
+        if !frame.is_multiple_of(self.skip as u64 + 1) {
+            return Ok(());
+        }
+        if let Some(previous) = self.pending.take() {
+            if previous == indices {
+                self.pending = Some(previous);
+                return Ok(());
+            }
+            let delay = centiseconds(frame) - self.written;
+            if delay >= MIN_DELAY {
+                self.write_frame(&previous, delay)?;
+            }
+        }
+        self.pending = Some(indices.to_vec());
+        Ok(())
+    }
+
+    fn write_frame(&mut self, indices: &[u8], delay: u64) -> io::Result<()> {
+        self.written += delay;
+        let delay = delay.min(0xFFFF) as u16;
+        // Graphic control extension with the delay, then the image
+        self.out.write_all(&[0x21, 0xF9, 0x04, 0x00])?;
+        self.out.write_all(&delay.to_le_bytes())?;
+        self.out.write_all(&[0x00, 0x00, 0x2C, 0, 0, 0, 0])?;
+        self.out.write_all(&(self.width as u16).to_le_bytes())?;
+        self.out.write_all(&(self.height as u16).to_le_bytes())?;
+        self.out.write_all(&[0x00, self.min_code_size])?;
+        for block in lzw_encode(indices, self.min_code_size).chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0x00])
+    }
+
+    // Writes the last frame and ends the file. Runs on drop.
+    pub fn finalize(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        if let Some(frame) = self.pending.take() {
+            let delay = centiseconds(self.frames).saturating_sub(self.written).max(MIN_DELAY);
+            self.write_frame(&frame, delay)?;
+        }
+        self.out.write_all(&[0x3B])?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Drop for GifRecorder<W> {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+// When the given frame starts, rounded to centiseconds
+fn centiseconds(frame: u64) -> u64 {
+    let cycles = frame * CYCLES_PER_FRAME as u64 * 100;
+    (cycles + CPU_CLOCK as u64 / 2) / CPU_CLOCK as u64
+}
+
+// Packs codes least significant bit first
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.bits |= (code as u32) << self.count;
+        self.count += size;
+        while self.count >= 8 {
+            self.bytes.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.bits as u8);
+        }
+        self.bytes
+    }
+}
+
+// Variable length LZW as used by GIF
+fn lzw_encode(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    let mut codes: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut code_size = min_code_size + 1;
+    let mut out = BitWriter { bytes: vec![], bits: 0, count: 0 };
+
+    out.write(clear, code_size);
+    let mut prefix = match indices.first() {
+        Some(&first) => first as u16,
+        None => {
+            out.write(end, code_size);
+            return out.finish();
+        }
+    };
+    for &index in indices[1..].iter() {
+        if let Some(&code) = codes.get(&(prefix, index)) {
+            prefix = code;
+            continue;
+        }
+        out.write(prefix, code_size);
+        if next < 1 << MAX_CODE_SIZE {
+            codes.insert((prefix, index), next);
+            // The decoder adds each code a step behind, so it widens its
+            // codes once the one after 1 << code_size is assigned
+            if next == 1 << code_size && code_size < MAX_CODE_SIZE {
+                code_size += 1;
+            }
+            next += 1;
+        } else {
+            out.write(clear, code_size);
+            codes.clear();
+            next = end + 1;
+            code_size = min_code_size + 1;
+        }
+        prefix = index as u16;
+    }
+    out.write(prefix, code_size);
+    out.write(end, code_size);
+    out.finish()
+}
+
+
+// GIF Tests
+#[cfg(test)]
+fn lzw_decode(data: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1usize << min_code_size;
+    let mut table: Vec<Vec<u8>> = vec![];
+    let mut code_size = min_code_size + 1;
+    let mut previous: Option<usize> = None;
+    let mut out = vec![];
+    let mut pos = 0;
+    loop {
+        let mut code = 0;
+        for i in 0..code_size as usize {
+            code |= ((data[(pos + i) / 8] >> ((pos + i) % 8)) as usize & 1) << i;
+        }
+        pos += code_size as usize;
+        if code == clear {
+            table = (0..clear).map(|i| vec![i as u8]).collect();
+            table.push(vec![]);
+            table.push(vec![]);
+            code_size = min_code_size + 1;
+            previous = None;
+            continue;
+        }
+        if code == clear + 1 {
+            return out;
+        }
+        let entry = match previous {
+            Some(p) if code == table.len() => {
+                let mut entry = table[p].clone();
+                entry.push(table[p][0]);
+                entry
+            }
+            _ => table[code].clone(),
+        };
+        if let Some(p) = previous {
+            if table.len() < 1 << MAX_CODE_SIZE {
+                let mut new = table[p].clone();
+                new.push(entry[0]);
+                table.push(new);
+                if table.len() == 1 << code_size && code_size < MAX_CODE_SIZE {
+                    code_size += 1;
+                }
+            }
+        }
+        out.extend_from_slice(&entry);
+        previous = Some(code);
+    }
+}
+
+#[test]
+fn gif_lzw_round_trip() {
+    assert_eq!(lzw_decode(&lzw_encode(&[], 2), 2), vec![]);
+    let repeating: Vec<u8> = (0..1000).map(|i| (i / 7 % 4) as u8).collect();
+    assert_eq!(lzw_decode(&lzw_encode(&repeating, 2), 2), repeating);
+    // Enough noise to fill the code table and start over
+    let mut seed = 1u32;
+    let noise: Vec<u8> = (0..40000).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as u8
+    }).collect();
+    assert_eq!(lzw_decode(&lzw_encode(&noise, 8), 8), noise);
+}
+#[test]
+fn gif_header_and_palette() {
+    let mut bytes = vec![];
+    {
+        let palette = [[0xFF, 0xFF, 0xFF], [0xAA, 0xAA, 0xAA], [0x55, 0x55, 0x55], [0, 0, 0]];
+        let mut gif = GifRecorder::new(&mut bytes, 2, 1, &palette, 0).unwrap();
+        gif.add_frame(&[0, 3]).unwrap();
+        assert!(gif.add_frame(&[0]).is_err());
+    }
+    assert_eq!(&bytes[..13], b"GIF89a\x02\x00\x01\x00\x91\x00\x00");
+    assert_eq!(&bytes[13..25], &[0xFF, 0xFF, 0xFF, 0xAA, 0xAA, 0xAA, 0x55, 0x55, 0x55, 0, 0, 0]);
+    assert_eq!(&bytes[25..44], b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+    assert_eq!(&bytes[44..48], &[0x21, 0xF9, 0x04, 0x00]);
+    assert_eq!(bytes[bytes.len() - 1], 0x3B);
+}
+#[cfg(test)]
+fn gif_delays(bytes: &[u8]) -> Vec<u16> {
+    bytes.windows(6)
+        .filter(|w| w[..4] == [0x21, 0xF9, 0x04, 0x00])
+        .map(|w| u16::from_le_bytes([w[4], w[5]]))
+        .collect()
+}
+#[test]
+fn gif_delays_keep_to_frame_rate() {
+    let mut bytes = vec![];
+    {
+        let mut gif = GifRecorder::new(&mut bytes, 1, 1, &[[0; 3], [0xFF; 3]], 0).unwrap();
+        for i in 0..60 {
+            gif.add_frame(&[i % 2]).unwrap();
+        }
+    }
+    let delays = gif_delays(&bytes);
+    // Frames shorter than the minimum delay are dropped
+    assert_eq!(delays.len(), 41);
+    assert!(delays.iter().all(|&d| (2..=3).contains(&d)));
+    // Only the last frame's minimum delay can run over
+    let total: u64 = delays.iter().map(|&d| d as u64).sum();
+    assert!(total >= centiseconds(60) && total < centiseconds(60) + MIN_DELAY);
+}
+#[test]
+fn gif_skips_and_merges_frames() {
+    let mut bytes = vec![];
+    {
+        let mut gif = GifRecorder::new(&mut bytes, 1, 1, &[[0; 3], [0xFF; 3]], 2).unwrap();
+        for i in 0..12 {
+            gif.add_frame(&[(i / 6) as u8]).unwrap();
+        }
+    }
+    // Frames 0 and 3 match, as do 6 and 9
+    assert_eq!(gif_delays(&bytes), vec![10, 10]);
+}
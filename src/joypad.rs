@@ -0,0 +1,250 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right, Button::Left, Button::Up, Button::Down,
+        Button::A, Button::B, Button::Select, Button::Start,
+    ];
+
+    // Bits 0-3 are the direction keys and bits 4-7 the action keys, in the
+    // same order the hardware reports them in P1.
+    pub fn bit(self) -> u8 {
+        match self {
+            Button::Right => 0,
+            Button::Left => 1,
+            Button::Up => 2,
+            Button::Down => 3,
+            Button::A => 4,
+            Button::B => 5,
+            Button::Select => 6,
+            Button::Start => 7,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Button> {
+        match name.to_ascii_lowercase().as_str() {
+            "right" => Some(Button::Right),
+            "left" => Some(Button::Left),
+            "up" => Some(Button::Up),
+            "down" => Some(Button::Down),
+            "a" => Some(Button::A),
+            "b" => Some(Button::B),
+            "select" => Some(Button::Select),
+            "start" => Some(Button::Start),
+            _ => None,
+        }
+    }
+}
+
+// Set of currently pressed buttons, one bit per button (1 = pressed).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ButtonState {
+    pressed: u8,
+}
+
+impl ButtonState {
+    pub fn new() -> ButtonState {
+        ButtonState { pressed: 0 }
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.pressed |= 1 << button.bit();
+        } else {
+            self.pressed &= !(1 << button.bit());
+        }
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        (self.pressed >> button.bit()) & 1 == 1
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.pressed
+    }
+}
+
+
+// P1/JOYP register. Bit 4 low selects the direction keys and bit 5 low the
+// action keys; the low nibble reads 0 for every pressed key in a selected
+// group.
+//
+// With the SGB in multiplayer mode each rising edge of P15 moves on to the
+// next controller, and with neither group selected the low nibble reads
+// 0xF minus the current controller.
+pub struct Joypad {
+    select: u8,
+    players: [ButtonState; 4],
+    player_count: usize,
+    player: usize,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: 0x30,
+            players: [ButtonState::new(); 4],
+            player_count: 1,
+            player: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    // Returns true if the write caused a joypad interrupt
+    pub fn write(&mut self, val: u8) -> bool {
+        let before = self.lines();
+        if self.player_count > 1 && self.select & 0x20 == 0 && val & 0x20 != 0 {
+            self.player = (self.player + 1) % self.player_count;
+        }
+        self.select = val & 0x30;
+        falling_edge(before, self.lines())
+    }
+
+    // Returns true if the change caused a joypad interrupt
+    pub fn set_buttons(&mut self, buttons: ButtonState) -> bool {
+        self.set_player_buttons(0, buttons)
+    }
+
+    pub fn set_player_buttons(&mut self, player: usize, buttons: ButtonState) -> bool {
+        let before = self.lines();
+        self.players[player] = buttons;
+        falling_edge(before, self.lines())
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+        let mut buttons = self.players[0];
+        buttons.set(button, pressed);
+        self.set_buttons(buttons)
+    }
+
+    pub fn buttons(&self) -> ButtonState {
+        self.players[0]
+    }
+
+    // 1, 2 or 4 controllers, as requested by the SGB MLT_REQ command
+    pub fn set_player_count(&mut self, count: usize) {
+        if count != self.player_count {
+            self.player_count = count;
+            self.player = 0;
+        }
+    }
+
+    // State of the P10-P13 input lines (active low)
+    fn lines(&self) -> u8 {
+        let buttons = self.players[self.player];
+        let mut lines = 0x0F;
+        if self.select & 0x10 == 0 {
+            lines &= !(buttons.bits() & 0x0F);
+        }
+        if self.select & 0x20 == 0 {
+            lines &= !(buttons.bits() >> 4);
+        }
+        if self.select == 0x30 && self.player_count > 1 {
+            lines = 0x0F - self.player as u8;
+        }
+        lines
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad::new()
+    }
+}
+
+fn falling_edge(before: u8, after: u8) -> bool {
+    before & !after & 0x0F != 0
+}
+
+// ButtonState Tests
+#[test]
+fn button_state_set_and_clear() {
+    let mut state = ButtonState::new();
+    state.set(Button::A, true);
+    state.set(Button::Down, true);
+    assert!(state.is_pressed(Button::A));
+    assert!(state.is_pressed(Button::Down));
+    assert!(!state.is_pressed(Button::B));
+    assert_eq!(state.bits(), 0b0001_1000);
+    state.set(Button::A, false);
+    assert_eq!(state.bits(), 0b0000_1000);
+}
+#[test]
+fn button_from_name() {
+    assert_eq!(Button::from_name("Start"), Some(Button::Start));
+    assert_eq!(Button::from_name("select"), Some(Button::Select));
+    assert_eq!(Button::from_name("turbo"), None);
+}
+
+// Joypad Register Tests
+#[test]
+fn joypad_nothing_selected_reads_high() {
+    let mut joypad = Joypad::new();
+    joypad.set_button(Button::A, true);
+    assert_eq!(joypad.read(), 0xFF);
+}
+#[test]
+fn joypad_direction_group() {
+    let mut joypad = Joypad::new();
+    joypad.write(0x20);
+    joypad.set_button(Button::Down, true);
+    joypad.set_button(Button::A, true);
+    assert_eq!(joypad.read(), 0xE7);
+}
+#[test]
+fn joypad_action_group() {
+    let mut joypad = Joypad::new();
+    joypad.write(0x10);
+    joypad.set_button(Button::Start, true);
+    joypad.set_button(Button::Left, true);
+    assert_eq!(joypad.read(), 0xD7);
+}
+#[test]
+fn joypad_interrupt_on_press_only() {
+    let mut joypad = Joypad::new();
+    joypad.write(0x10);
+    assert!(joypad.set_button(Button::B, true));
+    assert!(!joypad.set_button(Button::B, true));
+    assert!(!joypad.set_button(Button::B, false));
+    // Not selected, so no line changes
+    assert!(!joypad.set_button(Button::Up, true));
+}
+#[test]
+fn joypad_interrupt_on_select_with_held_key() {
+    let mut joypad = Joypad::new();
+    joypad.set_button(Button::Right, true);
+    assert!(joypad.write(0x20));
+    assert!(!joypad.write(0x20));
+}
+#[test]
+fn joypad_multiplayer_ids() {
+    let mut joypad = Joypad::new();
+    joypad.set_player_count(4);
+    joypad.set_player_buttons(1, ButtonState { pressed: 0x01 });
+    assert_eq!(joypad.read() & 0x0F, 0x0F);
+    // Pulsing P15 selects the next controller
+    joypad.write(0x10);
+    joypad.write(0x30);
+    assert_eq!(joypad.read() & 0x0F, 0x0E);
+    joypad.write(0x20);
+    assert_eq!(joypad.read() & 0x0F, 0x0E);
+    joypad.write(0x30);
+    for _ in 0..3 {
+        joypad.write(0x10);
+        joypad.write(0x30);
+    }
+    assert_eq!(joypad.read() & 0x0F, 0x0F);
+}
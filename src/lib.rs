@@ -0,0 +1,25 @@
+pub mod apu;
+pub mod blend;
+pub mod blip;
+pub mod color;
+pub mod four_player;
+pub mod gif;
+pub mod gb;
+pub mod gbs;
+pub mod joypad;
+pub mod link;
+pub mod midi;
+pub mod png;
+pub mod ppu;
+pub mod printer;
+pub mod serial;
+pub mod scale;
+pub mod sgb;
+pub mod stitch;
+pub mod terminal;
+pub mod timer;
+pub mod vgm;
+pub mod video;
+pub mod vram_viewer;
+pub mod wav;
+mod tests;
@@ -0,0 +1,259 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::serial::SerialDevice;
+
+// Both ends stop and exchange state every QUANTUM cycles, so neither Game
+// Boy can run more than one quantum ahead of the other. A byte transfer
+// takes 4096 cycles, so this keeps transfers well inside their real
+// timing.
+const QUANTUM: u32 = 1024;
+
+const HANDSHAKE: &[u8; 5] = b"GBLK\x01";
+
+// Sync message flags
+const SENT_BYTE: u8 = 0x01;
+const WAITING: u8 = 0x02;
+
+pub trait LinkStream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> LinkStream for T {}
+
+// A link cable to another emulator over a socket, kept in lockstep.
+//
+// At every quantum boundary each side sends three bytes: flags, its last
+// known SB and the byte it started shifting out as clock master during
+// the quantum, if any. A master transfer is answered with the other side's
+// SB as of the previous boundary, and delivered to the other side at the
+// next boundary if it is waiting for an external clock by then.
+pub struct LinkCable {
+    stream: Option<Box<dyn LinkStream>>,
+    cycles: u32,
+    // Local state reported at the next boundary
+    sb: u8,
+    waiting: bool,
+    outgoing: Option<u8>,
+    // Peer state as of the last boundary
+    peer_sb: u8,
+    peer_waiting: bool,
+    incoming: Option<u8>,
+}
+
+impl LinkCable {
+    pub fn new<S: LinkStream + 'static>(mut stream: S) -> io::Result<LinkCable> {
+        stream.write_all(HANDSHAKE)?;
+        stream.flush()?;
+        let mut reply = [0; 5];
+        stream.read_exact(&mut reply)?;
+        if &reply != HANDSHAKE {
+            return Err(io::Error::other("peer is not a compatible gb-emu link"));
+        }
+        Ok(LinkCable {
+            stream: Some(Box::new(stream)),
+            cycles: 0,
+            sb: 0xFF,
+            waiting: false,
+            outgoing: None,
+            peer_sb: 0xFF,
+            peer_waiting: false,
+            incoming: None,
+        })
+    }
+
+    // Waits for the other emulator to connect. Addresses starting with
+    // "unix:" or containing a '/' are Unix domain sockets, anything else
+    // is a TCP host:port.
+    pub fn listen(addr: &str) -> io::Result<LinkCable> {
+        match unix_path(addr) {
+            Some(path) => {
+                let _ = std::fs::remove_file(path);
+                let (stream, _) = UnixListener::bind(path)?.accept()?;
+                LinkCable::new(stream)
+            }
+            None => {
+                let (stream, _) = TcpListener::bind(addr)?.accept()?;
+                stream.set_nodelay(true)?;
+                LinkCable::new(stream)
+            }
+        }
+    }
+
+    pub fn connect(addr: &str) -> io::Result<LinkCable> {
+        match unix_path(addr) {
+            Some(path) => LinkCable::new(UnixStream::connect(path)?),
+            None => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nodelay(true)?;
+                LinkCable::new(stream)
+            }
+        }
+    }
+
+    // Two ends of a cable for linking Game Boys in the same process, each
+    // of which has to run on its own thread
+    pub fn pair() -> io::Result<(LinkCable, LinkCable)> {
+        let (a, b) = UnixStream::pair()?;
+        let handle = std::thread::spawn(move || LinkCable::new(b));
+        let a = LinkCable::new(a)?;
+        let b = handle.join().map_err(|_| io::Error::other("link handshake panicked"))??;
+        Ok((a, b))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn sync(&mut self) {
+        let stream = match self.stream.as_mut() {
+            Some(s) => s,
+            None => return,
+        };
+        let mut flags = 0;
+        if self.outgoing.is_some() { flags |= SENT_BYTE; }
+        if self.waiting { flags |= WAITING; }
+        let message = [flags, self.sb, self.outgoing.unwrap_or(0xFF)];
+        let mut reply = [0; 3];
+        let result = stream.write_all(&message)
+            .and_then(|_| stream.flush())
+            .and_then(|_| read_reply(stream, &mut reply));
+        if let Err(e) = result {
+            eprintln!("link cable disconnected: {}", e);
+            self.stream = None;
+            self.peer_sb = 0xFF;
+            self.peer_waiting = false;
+            self.incoming = None;
+            return;
+        }
+        self.outgoing = None;
+        // A byte that arrived while this side wasn't ready is lost
+        if !self.waiting {
+            self.incoming = None;
+        }
+        self.waiting = false;
+        self.peer_waiting = reply[0] & WAITING != 0;
+        self.peer_sb = reply[1];
+        if reply[0] & SENT_BYTE != 0 {
+            self.incoming = Some(reply[2]);
+        }
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.sb = byte;
+        self.outgoing = Some(byte);
+        if self.peer_waiting { self.peer_sb } else { 0xFF }
+    }
+
+    fn external_clock(&mut self, byte: u8) -> Option<u8> {
+        match self.incoming.take() {
+            Some(received) => {
+                self.sb = received;
+                self.waiting = false;
+                Some(received)
+            }
+            None => {
+                self.sb = byte;
+                self.waiting = true;
+                None
+            }
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles;
+        while self.cycles >= QUANTUM {
+            self.cycles -= QUANTUM;
+            self.sync();
+        }
+    }
+}
+
+// Like read_exact, except that a signal interrupting the wait is an error
+// instead of being retried, so a peer that hangs can't keep the emulator
+// from stopping on SIGINT or SIGTERM
+fn read_reply<R: Read + ?Sized>(stream: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.read(&mut buf[filled..])? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    Ok(())
+}
+
+fn unix_path(addr: &str) -> Option<&str> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        Some(path)
+    } else if addr.contains('/') {
+        Some(addr)
+    } else {
+        None
+    }
+}
+
+
+// Link Cable Tests
+#[test]
+fn link_unix_path() {
+    assert_eq!(unix_path("unix:gb.sock"), Some("gb.sock"));
+    assert_eq!(unix_path("/tmp/gb.sock"), Some("/tmp/gb.sock"));
+    assert_eq!(unix_path("127.0.0.1:5000"), None);
+}
+#[test]
+fn link_reply_gives_up_when_interrupted() {
+    struct Interrupted(Vec<u8>);
+    impl Read for Interrupted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop() {
+                Some(byte) => { buf[0] = byte; Ok(1) }
+                None => Err(io::ErrorKind::Interrupted.into()),
+            }
+        }
+    }
+    let mut reply = [0; 3];
+    assert!(read_reply(&mut Interrupted(vec![3, 2, 1]), &mut reply).is_ok());
+    assert_eq!(reply, [1, 2, 3]);
+    let error = read_reply(&mut Interrupted(vec![1]), &mut reply).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::Interrupted);
+    let error = read_reply(&mut &[1u8][..], &mut reply).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+}
+#[test]
+fn link_transfer_between_two_game_boys() {
+    use crate::gb::{GB, INT_SERIAL};
+
+    let (a, b) = LinkCable::pair().unwrap();
+    let run = |cable: LinkCable, sb: u8, sc: u8, delay: u32| {
+        std::thread::spawn(move || {
+            let mut gb = GB::new();
+            gb.connect_serial(Box::new(cable));
+            gb.write_byte(0xFF01, sb);
+            for _ in 0..delay {
+                gb.idle(QUANTUM);
+            }
+            gb.write_byte(0xFF02, sc);
+            for _ in delay..16 {
+                gb.idle(QUANTUM);
+            }
+            (gb.read_byte(0xFF01), gb.read_byte(0xFF0F) & (1 << INT_SERIAL) != 0)
+        })
+    };
+    // The master only starts once it has seen the slave waiting
+    let slave = run(b, 0x34, 0x80, 0);
+    let master = run(a, 0x12, 0x81, 2);
+    assert_eq!(master.join().unwrap(), (0x34, true));
+    assert_eq!(slave.join().unwrap(), (0x12, true));
+}
+#[test]
+fn link_disconnect_behaves_like_nothing_connected() {
+    let (mut a, b) = LinkCable::pair().unwrap();
+    drop(b);
+    a.tick(QUANTUM);
+    assert!(!a.is_connected());
+    assert_eq!(a.exchange(0x12), 0xFF);
+    assert_eq!(a.external_clock(0x12), None);
+}
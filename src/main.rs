@@ -1,24 +1,451 @@
-mod gb;
-mod tests;
-
 use std::env;
+use std::io;
+use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gb_emu::apu::{Channel, CPU_CLOCK};
+use gb_emu::blend::FrameBlender;
+use gb_emu::color::{ColorCorrection, OutputPalette};
+use gb_emu::gb;
+use gb_emu::gbs::{Gbs, GbsPlayer};
+use gb_emu::link::LinkCable;
+use gb_emu::png::{self, ColorType};
+use gb_emu::printer::Printer;
+use gb_emu::scale::{self, Filter};
+use gb_emu::terminal::{self, KeyBindings, TerminalInput, TerminalScreen};
+use gb_emu::wav;
+
+struct Options {
+    rom_file: String,
+    bindings: KeyBindings,
+    record_audio: Option<String>,
+    audio_stems: Option<String>,
+    log_vgm: Option<String>,
+    record_midi: Option<String>,
+    audio_stdout: bool,
+    link_listen: Option<String>,
+    link_connect: Option<String>,
+    printer: Option<String>,
+    color_correction: ColorCorrection,
+    palette: OutputPalette,
+    ghosting: Option<FrameBlender>,
+    screenshot: Option<String>,
+    record_video: Option<String>,
+    record_gif: Option<String>,
+    gif_skip: u32,
+    stitch_map: Option<String>,
+    filter: Filter,
+    scale: usize,
+    mute: Vec<Channel>,
+    solo: Vec<Channel>,
+    frames: Option<u64>,
+}
+
+fn usage() {
+    eprintln!("syntax: gb_emu [options] [rom_file]");
+    eprintln!("  --keys button=key,...   override keyboard bindings");
+    eprintln!("                          (run in a terminal of at least 160x72 to play there)");
+    eprintln!("  --record-audio FILE     record sound output to a WAV file");
+    eprintln!("  --audio-stems DIR       record each sound channel to DIR/<channel>.wav");
+    eprintln!("  --log-vgm FILE          log sound register writes to a VGM file");
+    eprintln!("  --record-midi FILE      transcribe the sound channels to a MIDI file");
+    eprintln!("  --audio-stdout          stream 48 kHz 16-bit stereo PCM to stdout");
+    eprintln!("  --mute CH,...           mute sound channels (1-4 or square1, square2, wave, noise)");
+    eprintln!("  --solo CH,...           only play the given sound channels");
+    eprintln!("  --link-listen ADDR      wait for another emulator to plug in a link cable");
+    eprintln!("  --link-connect ADDR     link to an emulator started with --link-listen");
+    eprintln!("                          (ADDR is host:port, or a Unix socket path)");
+    eprintln!("  --printer DIR           plug in a Game Boy Printer, saving pages to DIR");
+    eprintln!("                          (only one of the link options can be given)");
+    eprintln!("  --color-correction C    CGB colours: raw, accurate (default) or reduced-contrast");
+    eprintln!("  --palette P             DMG colours: green, pocket (default), light, or four");
+    eprintln!("                          hex colours from lightest to darkest, e.g. e0f8d0,88c070,346856,081820");
+    eprintln!("  --ghosting CURVE        blend frames like a slow LCD: the share of each older frame");
+    eprintln!("                          (e.g. 0.5), or weights from the current frame back (e.g. 2,1)");
+    eprintln!("  --screenshot FILE       save the last frame as a PNG on exit");
+    eprintln!("  --record-video FILE     record the screen to a video: FILE.avi for RGB video with");
+    eprintln!("                          sound, anything else for YUV4MPEG2 (y4m)");
+    eprintln!("  --record-gif FILE       record the screen to an animated GIF (not for CGB games or ghosting)");
+    eprintln!("  --gif-skip N            keep one frame in every N + 1 of the GIF (default 0)");
+    eprintln!("  --stitch-map FILE       save the scrolled background as one large PNG on exit");
+    eprintln!("  --filter F              upscaling filter for screenshots and video: nearest (default),");
+    eprintln!("                          scale2x, scale3x, hq2x or xbr");
+    eprintln!("  --scale N               upscaling factor (default: the filter's own factor)");
+    eprintln!("  --frames N              exit after N frames");
+    eprintln!("syntax: gb_emu gbs [options] gbs_file");
+    eprintln!("  --track N               track to render, starting from 1");
+    eprintln!("  --seconds S             length to render (default 120)");
+    eprintln!("  --out FILE              output WAV file (default track<N>.wav)");
+    eprintln!("syntax: gb_emu dump-vram [options] rom_file");
+    eprintln!("  --frame N               frame to dump VRAM and OAM at (default 60)");
+    eprintln!("  --out DIR               directory for the images (default .)");
+}
+
+struct GbsOptions {
+    gbs_file: String,
+    track: Option<u8>,
+    seconds: u32,
+    out: Option<String>,
+}
+
+fn parse_gbs_args(args: &[String]) -> Result<GbsOptions, String> {
+    let mut gbs_file = None;
+    let mut track = None;
+    let mut seconds = 120;
+    let mut out = None;
+    let mut i = 2;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match (args[i].as_str(), value) {
+            ("--track", Some(v)) => {
+                track = Some(v.parse().ok().filter(|&t| t > 0).ok_or(format!("invalid track '{}'", v))?);
+                i += 1;
+            }
+            ("--seconds", Some(v)) => {
+                seconds = v.parse().map_err(|_| format!("invalid length '{}'", v))?;
+                i += 1;
+            }
+            ("--out", Some(v)) => { out = Some(v); i += 1; }
+            (arg, _) if !arg.starts_with("--") && gbs_file.is_none() => {
+                gbs_file = Some(arg.to_string());
+            }
+            (arg, _) => return Err(format!("unexpected argument '{}'", arg)),
+        }
+        i += 1;
+    }
+    Ok(GbsOptions { gbs_file: gbs_file.ok_or("no gbs file given")?, track, seconds, out })
+}
+
+fn render_gbs(options: GbsOptions) -> Result<(), String> {
+    let gbs = Gbs::load(&options.gbs_file).map_err(|e| format!("couldn't load {}: {}", options.gbs_file, e))?;
+    let track = options.track.unwrap_or(gbs.header.first_song);
+    eprintln!("{} - {} ({})", gbs.header.title, gbs.header.author, gbs.header.copyright);
+    eprintln!("track {} of {}", track, gbs.header.song_count);
+    let out = options.out.unwrap_or(format!("track{}.wav", track));
+    let mut player = GbsPlayer::new(gbs);
+    player.start_track(track.saturating_sub(1))?;
+    player.render_to_wav(&out, options.seconds).map_err(|e| format!("couldn't write {}: {}", out, e))
+}
+
+struct DumpVramOptions {
+    rom_file: String,
+    frame: u64,
+    out: String,
+}
+
+fn parse_dump_vram_args(args: &[String]) -> Result<DumpVramOptions, String> {
+    let mut rom_file = None;
+    let mut frame = 60;
+    let mut out = ".".to_string();
+    let mut i = 2;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match (args[i].as_str(), value) {
+            ("--frame", Some(v)) => {
+                frame = v.parse().map_err(|_| format!("invalid frame '{}'", v))?;
+                i += 1;
+            }
+            ("--out", Some(v)) => { out = v; i += 1; }
+            (arg, _) if !arg.starts_with("--") && rom_file.is_none() => {
+                rom_file = Some(arg.to_string());
+            }
+            (arg, _) => return Err(format!("unexpected argument '{}'", arg)),
+        }
+        i += 1;
+    }
+    Ok(DumpVramOptions { rom_file: rom_file.ok_or("no rom file given")?, frame, out })
+}
+
+// Runs the game to the given frame and saves images of VRAM and OAM
+fn dump_vram(options: DumpVramOptions) -> Result<(), String> {
+    let mut gb = gb::GB::new();
+    gb.load_application(&options.rom_file).map_err(|e| format!("couldn't load {}: {}", options.rom_file, e))?;
+    for _ in 0..options.frame {
+        gb.run_frame();
+    }
+    gb.vram_viewer().save_all(&options.out).map_err(|e| format!("couldn't write to {}: {}", options.out, e))
+}
+
+fn parse_channels(list: &str) -> Result<Vec<Channel>, String> {
+    list.split(',')
+        .map(|c| Channel::parse(c.trim()).ok_or(format!("unknown sound channel '{}'", c)))
+        .collect()
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut rom_file = None;
+    let mut bindings = KeyBindings::new();
+    let mut record_audio = None;
+    let mut audio_stems = None;
+    let mut log_vgm = None;
+    let mut record_midi = None;
+    let mut audio_stdout = false;
+    let mut link_listen = None;
+    let mut link_connect = None;
+    let mut printer = None;
+    let mut color_correction = ColorCorrection::default();
+    let mut palette = OutputPalette::default();
+    let mut ghosting = None;
+    let mut screenshot = None;
+    let mut record_video = None;
+    let mut record_gif = None;
+    let mut gif_skip = 0;
+    let mut stitch_map = None;
+    let mut filter = Filter::Nearest;
+    let mut scale = None;
+    let mut mute = vec![];
+    let mut solo = vec![];
+    let mut frames = None;
+    let mut i = 1;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match (args[i].as_str(), value) {
+            ("--keys", Some(v)) => { bindings = KeyBindings::parse(&v)?; i += 1; }
+            ("--record-audio", Some(v)) => { record_audio = Some(v); i += 1; }
+            ("--audio-stems", Some(v)) => { audio_stems = Some(v); i += 1; }
+            ("--log-vgm", Some(v)) => { log_vgm = Some(v); i += 1; }
+            ("--record-midi", Some(v)) => { record_midi = Some(v); i += 1; }
+            ("--audio-stdout", _) => { audio_stdout = true; }
+            ("--mute", Some(v)) => { mute.extend(parse_channels(&v)?); i += 1; }
+            ("--solo", Some(v)) => { solo.extend(parse_channels(&v)?); i += 1; }
+            ("--link-listen", Some(v)) => { link_listen = Some(v); i += 1; }
+            ("--link-connect", Some(v)) => { link_connect = Some(v); i += 1; }
+            ("--printer", Some(v)) => { printer = Some(v); i += 1; }
+            ("--color-correction", Some(v)) => {
+                color_correction = ColorCorrection::parse(&v).ok_or(format!("unknown colour correction '{}'", v))?;
+                i += 1;
+            }
+            ("--palette", Some(v)) => { palette = OutputPalette::parse(&v)?; i += 1; }
+            ("--ghosting", Some(v)) => { ghosting = Some(FrameBlender::parse(&v)?); i += 1; }
+            ("--screenshot", Some(v)) => { screenshot = Some(v); i += 1; }
+            ("--record-video", Some(v)) => { record_video = Some(v); i += 1; }
+            ("--record-gif", Some(v)) => { record_gif = Some(v); i += 1; }
+            ("--stitch-map", Some(v)) => { stitch_map = Some(v); i += 1; }
+            ("--gif-skip", Some(v)) => {
+                gif_skip = v.parse().map_err(|_| format!("invalid frame skip '{}'", v))?;
+                i += 1;
+            }
+            ("--filter", Some(v)) => {
+                filter = Filter::parse(&v).ok_or(format!("unknown filter '{}'", v))?;
+                i += 1;
+            }
+            ("--scale", Some(v)) => {
+                scale = Some(v.parse().ok().filter(|&s| s > 0).ok_or(format!("invalid scale '{}'", v))?);
+                i += 1;
+            }
+            ("--frames", Some(v)) => {
+                frames = Some(v.parse().map_err(|_| format!("invalid frame count '{}'", v))?);
+                i += 1;
+            }
+            (arg, _) if !arg.starts_with("--") && rom_file.is_none() => {
+                rom_file = Some(arg.to_string());
+            }
+            (arg, _) => return Err(format!("unexpected argument '{}'", arg)),
+        }
+        i += 1;
+    }
+    let scale = scale.unwrap_or(filter.factor());
+    if !scale.is_multiple_of(filter.factor()) {
+        return Err(format!("{:?} needs a scale that is a multiple of {}", filter, filter.factor()));
+    }
+    if ghosting.is_some() && record_gif.is_some() {
+        return Err("--ghosting blends colours a GIF of the four shades can't show".to_string());
+    }
+    // There's only one link port, so only one thing can be plugged into it
+    let link_options = [("--link-listen", &link_listen), ("--link-connect", &link_connect), ("--printer", &printer)];
+    let mut plugged = link_options.iter().filter(|(_, value)| value.is_some()).map(|(flag, _)| *flag);
+    if let (Some(first), Some(second)) = (plugged.next(), plugged.next()) {
+        return Err(format!("{} and {} both need the link port", first, second));
+    }
+    Ok(Options {
+        rom_file: rom_file.ok_or("no rom file given")?,
+        bindings,
+        record_audio,
+        audio_stems,
+        log_vgm,
+        record_midi,
+        audio_stdout,
+        link_listen,
+        link_connect,
+        printer,
+        color_correction,
+        palette,
+        ghosting,
+        screenshot,
+        record_video,
+        record_gif,
+        gif_skip,
+        stitch_map,
+        filter,
+        scale,
+        mute,
+        solo,
+        frames,
+    })
+}
+
+// The current picture as RGBA, upscaled for saving
+fn scaled_frame(gb: &gb::GB, filter: Filter, scale: usize) -> Result<(Vec<u8>, usize, usize), String> {
+    let (width, height) = gb.screen_size();
+    let rgba = scale::scale_rgba(&gb.frame_rgba(), width, height, filter, scale)?;
+    Ok((rgba, width * scale, height * scale))
+}
+
+fn save_screenshot(gb: &gb::GB, path: &str, filter: Filter, scale: usize) -> Result<(), String> {
+    let (rgba, width, height) = scaled_frame(gb, filter, scale)?;
+    png::save(path, width as u32, height as u32, ColorType::Rgba, &rgba)
+        .map_err(|e| format!("couldn't save {}: {}", path, e))
+}
+
+// Starts every recording asked for, stopping at the first that can't be
+// created
+fn start_recordings(gb: &mut gb::GB, options: &Options) -> Result<(), String> {
+    let failed = |what: &str, path: &str, e: io::Error| format!("couldn't create {} {}: {}", what, path, e);
+    if let Some(path) = &options.record_audio {
+        gb.start_audio_recording(path).map_err(|e| failed("audio recording", path, e))?;
+    }
+    if let Some(dir) = &options.audio_stems {
+        gb.start_audio_stems(dir).map_err(|e| failed("audio stems in", dir, e))?;
+    }
+    if let Some(path) = &options.log_vgm {
+        gb.start_vgm_log(path).map_err(|e| failed("VGM log", path, e))?;
+    }
+    if let Some(path) = &options.record_midi {
+        gb.start_midi_recording(path).map_err(|e| failed("MIDI recording", path, e))?;
+    }
+    if let Some(path) = &options.record_video {
+        gb.start_video_recording(path, options.filter, options.scale).map_err(|e| failed("video recording", path, e))?;
+    }
+    if let Some(path) = &options.record_gif {
+        gb.start_gif_recording(path, options.gif_skip).map_err(|e| failed("GIF recording", path, e))?;
+    }
+    Ok(())
+}
 
 fn main() {
-    let mut timer = 0;
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2
-    {
-        println!("syntax: gb_emu [rom_file]");
+    if args.get(1).map(String::as_str) == Some("gbs") {
+        let result = parse_gbs_args(&args).and_then(render_gbs);
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            usage();
+        }
         return;
     }
+    if args.get(1).map(String::as_str) == Some("dump-vram") {
+        match parse_dump_vram_args(&args) {
+            Ok(options) => {
+                if let Err(e) = dump_vram(options) {
+                    eprintln!("{}", e);
+                }
+            }
+            Err(e) => { eprintln!("{}", e); usage(); }
+        }
+        return;
+    }
+    let mut options = match parse_args(&args) {
+        Ok(o) => o,
+        Err(e) => { eprintln!("{}", e); usage(); return; }
+    };
+
     let mut gb = gb::GB::new();
-    if !gb.load_application(&args[1]){
-        panic!("failed load rom");
+    if let Err(e) = gb.load_application(&options.rom_file) {
+        eprintln!("couldn't load {}: {}", options.rom_file, e);
+        return;
+    }
+    if let Some(addr) = &options.link_listen {
+        eprintln!("waiting for link cable on {}", addr);
+        match LinkCable::listen(addr) {
+            Ok(cable) => { gb.connect_serial(Box::new(cable)); }
+            Err(e) => { eprintln!("couldn't accept link cable on {}: {}", addr, e); return; }
+        }
+    } else if let Some(addr) = &options.link_connect {
+        match LinkCable::connect(addr) {
+            Ok(cable) => { gb.connect_serial(Box::new(cable)); }
+            Err(e) => { eprintln!("couldn't connect link cable to {}: {}", addr, e); return; }
+        }
+    } else if let Some(dir) = &options.printer {
+        gb.connect_serial(Box::new(Printer::new(Some(dir.into()))));
+    }
+    gb.set_color_correction(options.color_correction);
+    gb.set_output_palette(options.palette);
+    gb.set_frame_blending(options.ghosting.take());
+    if let Err(e) = start_recordings(&mut gb, &options) {
+        eprintln!("{}", e);
+        return;
+    }
+    if options.stitch_map.is_some() {
+        gb.start_map_stitching();
+    }
+    for &channel in options.mute.iter() {
+        gb.apu_mut().set_muted(channel, true);
+    }
+    for &channel in options.solo.iter() {
+        gb.apu_mut().set_solo(channel, true);
     }
 
     gb.print_memory();
 
-    'main: loop {
-        gb.emulate_cycle();
+    // Keyboard input is only available when attached to a terminal, and
+    // then the game is drawn there too and runs at its real speed
+    let mut input = TerminalInput::new(options.bindings).ok();
+    let mut screen = None;
+    if input.is_some() && !options.audio_stdout && io::stdout().is_terminal() {
+        screen = TerminalScreen::new(io::stdout()).ok();
+    }
+    terminal::catch_stop_signals();
+    let stdout = io::stdout();
+    let frame_time = Duration::from_nanos(gb::CYCLES_PER_FRAME as u64 * 1_000_000_000 / CPU_CLOCK as u64);
+    let start = Instant::now();
+    let mut frame = 0;
+    while options.frames.is_none_or(|n| frame < n) && !terminal::stop_requested() {
+        if let Some(input) = input.as_mut() {
+            let buttons = input.poll();
+            if input.quit_requested() {
+                break;
+            }
+            gb.set_buttons(buttons);
+        }
+        gb.run_frame();
+        if let Some(screen) = screen.as_mut() {
+            let (width, height) = gb.screen_size();
+            let _ = screen.draw(&gb.frame_rgba(), width, height);
+        }
+        if input.is_some() {
+            let due = start + frame_time * (frame as u32 + 1);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+        let samples = gb.take_audio_samples();
+        if options.audio_stdout {
+            let mut out = stdout.lock();
+            // Stop once whatever is reading the stream goes away
+            if wav::write_pcm(&mut out, &samples).and_then(|_| out.flush()).is_err() {
+                break;
+            }
+        }
+        frame += 1;
+    }
+    drop(screen);
+    if let Some(path) = &options.screenshot {
+        if let Err(e) = save_screenshot(&gb, path, options.filter, options.scale) {
+            eprintln!("{}", e);
+        }
+    }
+    if let (Some(path), Some(stitcher)) = (&options.stitch_map, gb.stop_map_stitching()) {
+        if stitcher.frames() == 0 {
+            eprintln!("no background was shown, so there was no map to save");
+        } else if let Err(e) = stitcher.image().save(path) {
+            eprintln!("couldn't save {}: {}", path, e);
+        }
     }
+    gb.stop_audio_recording().expect("couldn't finish audio recording");
+    gb.stop_audio_stems().expect("couldn't finish audio stems");
+    gb.stop_vgm_log().expect("couldn't finish VGM log");
+    gb.stop_midi_recording().expect("couldn't finish MIDI recording");
+    gb.stop_video_recording().expect("couldn't finish video recording");
+    gb.stop_gif_recording().expect("couldn't finish GIF recording");
 }
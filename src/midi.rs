@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::apu::{Apu, Channel, CPU_CLOCK};
+
+// 480 ticks per quarter note at the default 120 BPM
+const TICKS_PER_QUARTER: u16 = 480;
+const TICKS_PER_SECOND: u64 = 960;
+const TEMPO: u32 = 500_000;
+
+const DRUM_CHANNEL: u8 = 9;
+const NOTE_VELOCITY: u8 = 100;
+const CC_VOLUME: u8 = 7;
+
+// Percussion notes used for the noise channel
+const KICK: u8 = 36;
+const SNARE: u8 = 38;
+const HI_HAT: u8 = 42;
+
+// Events for one sound channel, in delta-time order
+struct Track {
+    channel: Channel,
+    events: Vec<u8>,
+    last_tick: u64,
+    note: Option<u8>,
+    volume: Option<u8>,
+    triggers: u32,
+}
+
+impl Track {
+    fn new(channel: Channel) -> Track {
+        Track { channel, events: vec![], last_tick: 0, note: None, volume: None, triggers: 0 }
+    }
+
+    fn midi_channel(&self) -> u8 {
+        match self.channel {
+            Channel::Noise => DRUM_CHANNEL,
+            channel => channel.index() as u8,
+        }
+    }
+
+    fn event(&mut self, tick: u64, bytes: &[u8]) {
+        write_var_len(&mut self.events, (tick - self.last_tick) as u32);
+        self.events.extend_from_slice(bytes);
+        self.last_tick = tick;
+    }
+
+    fn note_on(&mut self, tick: u64, note: u8) {
+        let status = 0x90 | self.midi_channel();
+        self.event(tick, &[status, note, NOTE_VELOCITY]);
+        self.note = Some(note);
+    }
+
+    fn note_off(&mut self, tick: u64) {
+        if let Some(note) = self.note.take() {
+            let status = 0x80 | self.midi_channel();
+            self.event(tick, &[status, note, 0]);
+        }
+    }
+}
+
+// Transcribes the APU's channels into a Standard MIDI File: format 1, a
+// tempo track followed by one track per sound channel. Triggers and pitch
+// changes become notes, envelope and wave volume changes become channel
+// volume controllers. Noise is mapped to General MIDI percussion. The
+// file is written by finalize(), which also runs on drop.
+pub struct MidiWriter<W: Write> {
+    out: W,
+    start_cycle: u64,
+    tick: u64,
+    tracks: Vec<Track>,
+    finished: bool,
+}
+
+impl MidiWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, start_cycle: u64) -> io::Result<MidiWriter<BufWriter<File>>> {
+        Ok(MidiWriter::new(BufWriter::new(File::create(path)?), start_cycle))
+    }
+}
+
+impl<W: Write> MidiWriter<W> {
+    pub fn new(out: W, start_cycle: u64) -> MidiWriter<W> {
+        let mut tracks: Vec<Track> = Channel::ALL.iter().map(|&c| Track::new(c)).collect();
+        for track in tracks.iter_mut() {
+            let name = track.channel.name().as_bytes();
+            let mut meta = vec![0xFF, 0x03, name.len() as u8];
+            meta.extend_from_slice(name);
+            track.event(0, &meta);
+            // Square Lead for the pulse channels, Synth Bass for the wave
+            // channel; the percussion channel needs no program
+            let program = match track.channel {
+                Channel::Square1 | Channel::Square2 => Some(80),
+                Channel::Wave => Some(38),
+                Channel::Noise => None,
+            };
+            if let Some(program) = program {
+                let status = 0xC0 | track.midi_channel();
+                track.event(0, &[status, program]);
+            }
+        }
+        MidiWriter { out, start_cycle, tick: 0, tracks, finished: false }
+    }
+
+    // Records any changes in the channels' state up to the given cycle
+    pub fn update(&mut self, cycle: u64, apu: &Apu) {
+        let tick = cycle.saturating_sub(self.start_cycle) * TICKS_PER_SECOND / CPU_CLOCK as u64;
+        self.tick = tick;
+        for track in self.tracks.iter_mut() {
+            let state = apu.channel_state(track.channel);
+            let note = if state.playing {
+                Some(match track.channel {
+                    Channel::Noise => drum_note(state.frequency),
+                    _ => midi_note(state.frequency),
+                })
+            } else {
+                None
+            };
+            let volume = (state.volume as u32 * 127 / 15) as u8;
+            if state.playing && track.volume != Some(volume) {
+                let status = 0xB0 | track.midi_channel();
+                track.event(tick, &[status, CC_VOLUME, volume]);
+                track.volume = Some(volume);
+            }
+            let retriggered = state.triggers != track.triggers;
+            track.triggers = state.triggers;
+            if note != track.note || retriggered {
+                track.note_off(tick);
+                if let Some(note) = note {
+                    track.note_on(tick, note);
+                }
+            }
+        }
+    }
+
+    // Ends any sounding notes and writes the file. Later updates are
+    // ignored.
+    pub fn finalize(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let tick = self.tick;
+        self.out.write_all(b"MThd")?;
+        self.out.write_all(&6u32.to_be_bytes())?;
+        self.out.write_all(&1u16.to_be_bytes())?;
+        self.out.write_all(&(self.tracks.len() as u16 + 1).to_be_bytes())?;
+        self.out.write_all(&TICKS_PER_QUARTER.to_be_bytes())?;
+
+        let tempo = TEMPO.to_be_bytes();
+        let tempo_track = [0x00, 0xFF, 0x51, 0x03, tempo[1], tempo[2], tempo[3], 0x00, 0xFF, 0x2F, 0x00];
+        write_track(&mut self.out, &tempo_track)?;
+        for track in self.tracks.iter_mut() {
+            track.note_off(tick);
+            track.event(tick, &[0xFF, 0x2F, 0x00]);
+            write_track(&mut self.out, &track.events)?;
+        }
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Drop for MidiWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+fn write_track<W: Write>(out: &mut W, events: &[u8]) -> io::Result<()> {
+    out.write_all(b"MTrk")?;
+    out.write_all(&(events.len() as u32).to_be_bytes())?;
+    out.write_all(events)
+}
+
+// MIDI variable-length quantity: 7 bits per byte, most significant first
+fn write_var_len(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+// Nearest equal-tempered note, with A4 (440 Hz) as note 69
+pub fn midi_note(frequency: f32) -> u8 {
+    let note = 69.0 + 12.0 * (frequency / 440.0).log2();
+    note.round().clamp(0.0, 127.0) as u8
+}
+
+fn drum_note(lfsr_rate: f32) -> u8 {
+    if lfsr_rate >= 100_000.0 {
+        HI_HAT
+    } else if lfsr_rate >= 20_000.0 {
+        SNARE
+    } else {
+        KICK
+    }
+}
+
+
+// MIDI Tests
+#[test]
+fn midi_var_len() {
+    let mut out = vec![];
+    write_var_len(&mut out, 0);
+    write_var_len(&mut out, 0x7F);
+    write_var_len(&mut out, 0x80);
+    write_var_len(&mut out, 0x3FFF);
+    assert_eq!(out, vec![0x00, 0x7F, 0x81, 0x00, 0xFF, 0x7F]);
+}
+#[test]
+fn midi_note_from_frequency() {
+    assert_eq!(midi_note(440.0), 69);
+    assert_eq!(midi_note(261.6), 60);
+    assert_eq!(midi_note(439.8), 69);
+    assert_eq!(midi_note(1.0), 0);
+}
+#[test]
+fn midi_file_structure() {
+    let mut bytes = vec![];
+    {
+        let mut midi = MidiWriter::new(&mut bytes, 0);
+        let mut apu = Apu::new();
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0xD6);
+        apu.write(0xFF14, 0x86);
+        midi.update(0, &apu);
+        apu.write(0xFF12, 0x00);
+        midi.update(CPU_CLOCK as u64, &apu);
+    }
+    assert_eq!(&bytes[0..4], b"MThd");
+    assert_eq!(&bytes[8..14], &[0, 1, 0, 5, 0x01, 0xE0]);
+    assert_eq!(&bytes[14..18], b"MTrk");
+    assert_eq!(&bytes[18..22], &11u32.to_be_bytes());
+    // Square 1: name, program, volume and note on, then a second (960
+    // ticks) later switching the DAC off ends the note
+    let track = &bytes[33..];
+    assert_eq!(&track[0..4], b"MTrk");
+    assert_eq!(&track[4..8], &31u32.to_be_bytes());
+    assert_eq!(&track[8..19], b"\x00\xFF\x03\x07square1");
+    assert_eq!(&track[19..43], &[
+        0x00, 0xC0, 80,
+        0x00, 0xB0, 7, 127,
+        0x00, 0x90, 69, 100,
+        0x87, 0x40, 0x80, 69, 0,
+        0x00, 0xFF, 0x2F, 0x00,
+        b'M', b'T', b'r', b'k',
+    ]);
+}
+#[test]
+fn midi_retrigger_restarts_note() {
+    let mut bytes = vec![];
+    let mut midi = MidiWriter::new(&mut bytes, 0);
+    let mut apu = Apu::new();
+    apu.write(0xFF26, 0x80);
+    apu.write(0xFF17, 0xF0);
+    apu.write(0xFF19, 0x87);
+    midi.update(0, &apu);
+    apu.write(0xFF19, 0x87);
+    midi.update(4370, &apu);
+    let events = &midi.tracks[1].events;
+    assert_eq!(&events[events.len() - 8..], &[0x01, 0x81, 72, 0, 0x00, 0x91, 72, 100]);
+}
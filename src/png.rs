@@ -0,0 +1,187 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+// Largest block a stored (uncompressed) deflate block can hold
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorType {
+    // One byte per pixel
+    Gray,
+    // Three bytes per pixel, red, green, blue
+    Rgb,
+    // Red, green, blue and alpha
+    Rgba,
+}
+
+impl ColorType {
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            ColorType::Gray => 1,
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            ColorType::Gray => 0,
+            ColorType::Rgb => 2,
+            ColorType::Rgba => 6,
+        }
+    }
+}
+
+pub fn save<P: AsRef<Path>>(path: P, width: u32, height: u32, color: ColorType, pixels: &[u8]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write(&mut out, width, height, color, pixels)?;
+    out.flush()
+}
+
+// Writes an 8-bit PNG. The image data is stored without compression, which
+// keeps the encoder tiny; the images this emulator produces are small.
+pub fn write<W: Write>(out: &mut W, width: u32, height: u32, color: ColorType, pixels: &[u8]) -> io::Result<()> {
+    let stride = width as usize * color.bytes_per_pixel();
+    if pixels.len() != stride * height as usize {
+        return Err(io::Error::other("pixel data doesn't match image size"));
+    }
+    out.write_all(&SIGNATURE)?;
+
+    let mut header = vec![];
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, color.code(), 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    // Every scanline starts with filter type 0 (none)
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+    for row in pixels.chunks(stride.max(1)).take(height as usize) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&crc.finish().to_be_bytes())
+}
+
+// A zlib stream of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(if last { 1 } else { 0 });
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+pub struct Crc32 {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        let mut table = [0; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        Crc32 { table, crc: 0xFFFFFFFF }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc = self.table[((self.crc ^ byte as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        self.crc ^ 0xFFFFFFFF
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+
+// PNG Tests
+#[test]
+fn png_crc32() {
+    let mut crc = Crc32::new();
+    crc.update(b"123456789");
+    assert_eq!(crc.finish(), 0xCBF43926);
+}
+#[test]
+fn png_adler32() {
+    assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+}
+#[test]
+fn png_zlib_stored_blocks() {
+    let data = vec![7; MAX_STORED_BLOCK + 10];
+    let z = zlib_stored(&data);
+    assert_eq!(&z[0..2], &[0x78, 0x01]);
+    assert_eq!(&z[2..7], &[0x00, 0xFF, 0xFF, 0x00, 0x00]);
+    let second = 7 + MAX_STORED_BLOCK;
+    assert_eq!(&z[second..second + 5], &[0x01, 10, 0, 0xF5, 0xFF]);
+    assert_eq!(z.len(), 2 + 5 + MAX_STORED_BLOCK + 5 + 10 + 4);
+}
+#[test]
+fn png_file_layout() {
+    let mut bytes = vec![];
+    write(&mut bytes, 2, 1, ColorType::Rgb, &[255, 0, 0, 0, 0, 255]).unwrap();
+    assert_eq!(&bytes[0..8], &SIGNATURE);
+    assert_eq!(&bytes[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+    assert_eq!(&bytes[16..29], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+    // Header CRC
+    assert_eq!(&bytes[29..33], &[0x7B, 0x40, 0xE8, 0xDD]);
+    assert_eq!(&bytes[bytes.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+}
+#[test]
+fn png_rejects_wrong_size() {
+    let mut bytes = vec![];
+    assert!(write(&mut bytes, 2, 2, ColorType::Gray, &[0; 3]).is_err());
+}
+#[test]
+fn png_rgba_header() {
+    let mut bytes = vec![];
+    write(&mut bytes, 1, 1, ColorType::Rgba, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(bytes[25], 6);
+    assert!(write(&mut bytes, 1, 1, ColorType::Rgba, &[1, 2, 3]).is_err());
+}
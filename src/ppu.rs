@@ -0,0 +1,602 @@
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const LINE_CYCLES: u32 = 456;
+const OAM_SCAN_CYCLES: u32 = 80;
+// Mode 3 really lasts 172-289 cycles depending on scrolling and sprites;
+// the picture is drawn in one go so only the shortest length is used
+const TRANSFER_END: u32 = OAM_SCAN_CYCLES + 172;
+const LINES: u8 = 154;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+// STAT modes
+const MODE_HBLANK: u8 = 0;
+const MODE_VBLANK: u8 = 1;
+const MODE_OAM_SCAN: u8 = 2;
+const MODE_TRANSFER: u8 = 3;
+
+// What happened during a tick
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PpuEvents {
+    pub vblank_interrupt: bool,
+    pub stat_interrupt: bool,
+    // A visible line was drawn and HBlank started, which drives HBlank DMA
+    pub hblank: bool,
+}
+
+// Colour index and priority of the background pixel under a sprite
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    color: u8,
+    priority: bool,
+}
+
+// The LCD controller. Each line is drawn in one go at the end of mode 3.
+//
+// The frame holds one entry per pixel: the shade (0-3) picked by BGP or
+// OBP0/OBP1 in DMG mode, or an RGB555 colour from palette RAM in CGB mode.
+pub struct Ppu {
+    cgb: bool,
+    vram: [[u8; 0x2000]; 2],
+    vram_bank: usize,
+    oam: [u8; 0xA0],
+    // Eight palettes of four little-endian RGB555 colours each
+    bg_palettes: [u8; 64],
+    obj_palettes: [u8; 64],
+    bcps: u8,
+    ocps: u8,
+
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+
+    mode: u8,
+    line_cycles: u32,
+    // Line of the window drawn next, which only advances on lines where
+    // the window was visible
+    window_line: u8,
+    // The STAT interrupt fires on rising edges of all enabled conditions
+    // ORed together
+    stat_signal: bool,
+    frame: Vec<u16>,
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            cgb: false,
+            vram: [[0; 0x2000]; 2],
+            vram_bank: 0,
+            oam: [0; 0xA0],
+            bg_palettes: [0xFF; 64],
+            obj_palettes: [0xFF; 64],
+            bcps: 0,
+            ocps: 0,
+
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+
+            mode: MODE_HBLANK,
+            line_cycles: 0,
+            window_line: 0,
+            stat_signal: false,
+            frame: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        self.vram_bank = 0;
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.cgb
+    }
+
+    pub fn frame(&self) -> &[u16] {
+        &self.frame
+    }
+
+    pub fn vram(&self, bank: usize) -> &[u8; 0x2000] {
+        &self.vram[bank]
+    }
+
+    pub fn oam(&self) -> &[u8; 0xA0] {
+        &self.oam
+    }
+
+    pub fn read_vram(&self, addr: u16) -> u8 {
+        self.vram[self.vram_bank][(addr - 0x8000) as usize]
+    }
+
+    pub fn write_vram(&mut self, addr: u16, val: u8) {
+        self.vram[self.vram_bank][(addr - 0x8000) as usize] = val;
+    }
+
+    pub fn read_oam(&self, addr: u16) -> u8 {
+        self.oam[(addr - 0xFE00) as usize]
+    }
+
+    pub fn write_oam(&mut self, addr: u16, val: u8) {
+        self.oam[(addr - 0xFE00) as usize] = val;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF40 => self.lcdc,
+            0xFF41 => 0x80 | self.stat | self.coincidence_bit() | self.mode,
+            0xFF42 => self.scy,
+            0xFF43 => self.scx,
+            0xFF44 => self.ly,
+            0xFF45 => self.lyc,
+            0xFF47 => self.bgp,
+            0xFF48 => self.obp0,
+            0xFF49 => self.obp1,
+            0xFF4A => self.wy,
+            0xFF4B => self.wx,
+            0xFF4F if self.cgb => 0xFE | self.vram_bank as u8,
+            0xFF68 if self.cgb => 0x40 | self.bcps,
+            0xFF69 if self.cgb => self.bg_palettes[(self.bcps & 0x3F) as usize],
+            0xFF6A if self.cgb => 0x40 | self.ocps,
+            0xFF6B if self.cgb => self.obj_palettes[(self.ocps & 0x3F) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF40 => {
+                let was_on = self.lcd_enabled();
+                self.lcdc = val;
+                if was_on && !self.lcd_enabled() {
+                    self.ly = 0;
+                    self.line_cycles = 0;
+                    self.mode = MODE_HBLANK;
+                    self.window_line = 0;
+                } else if !was_on && self.lcd_enabled() {
+                    self.mode = MODE_OAM_SCAN;
+                }
+            }
+            0xFF41 => self.stat = val & 0x78,
+            0xFF42 => self.scy = val,
+            0xFF43 => self.scx = val,
+            0xFF45 => self.lyc = val,
+            0xFF47 => self.bgp = val,
+            0xFF48 => self.obp0 = val,
+            0xFF49 => self.obp1 = val,
+            0xFF4A => self.wy = val,
+            0xFF4B => self.wx = val,
+            0xFF4F if self.cgb => self.vram_bank = (val & 0x01) as usize,
+            0xFF68 if self.cgb => self.bcps = val & 0xBF,
+            0xFF69 if self.cgb => {
+                self.bg_palettes[(self.bcps & 0x3F) as usize] = val;
+                self.bcps = auto_increment(self.bcps);
+            }
+            0xFF6A if self.cgb => self.ocps = val & 0xBF,
+            0xFF6B if self.cgb => {
+                self.obj_palettes[(self.ocps & 0x3F) as usize] = val;
+                self.ocps = auto_increment(self.ocps);
+            }
+            _ => {}
+        }
+    }
+
+    fn lcd_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    fn coincidence_bit(&self) -> u8 {
+        if self.ly == self.lyc { 0x04 } else { 0 }
+    }
+
+    // Advances the LCD by the given number of cycles at the normal speed
+    pub fn tick(&mut self, cycles: u32) -> PpuEvents {
+        let mut events = PpuEvents::default();
+        if !self.lcd_enabled() {
+            return events;
+        }
+        let mut cycles = cycles;
+        while cycles > 0 {
+            let boundary = match self.mode {
+                MODE_OAM_SCAN => OAM_SCAN_CYCLES,
+                MODE_TRANSFER => TRANSFER_END,
+                _ => LINE_CYCLES,
+            };
+            let step = cycles.min(boundary - self.line_cycles);
+            self.line_cycles += step;
+            cycles -= step;
+            if self.line_cycles == boundary {
+                self.next_mode(&mut events);
+            }
+            self.update_stat_signal(&mut events);
+        }
+        events
+    }
+
+    fn next_mode(&mut self, events: &mut PpuEvents) {
+        match self.mode {
+            MODE_OAM_SCAN => self.mode = MODE_TRANSFER,
+            MODE_TRANSFER => {
+                self.render_line();
+                self.mode = MODE_HBLANK;
+                events.hblank = true;
+            }
+            _ => {
+                self.line_cycles = 0;
+                self.ly = (self.ly + 1) % LINES;
+                if self.ly == SCREEN_HEIGHT as u8 {
+                    self.mode = MODE_VBLANK;
+                    events.vblank_interrupt = true;
+                } else if self.ly == 0 {
+                    self.mode = MODE_OAM_SCAN;
+                    self.window_line = 0;
+                } else if self.mode == MODE_HBLANK {
+                    self.mode = MODE_OAM_SCAN;
+                }
+            }
+        }
+    }
+
+    fn update_stat_signal(&mut self, events: &mut PpuEvents) {
+        let signal = (self.stat & 0x40 != 0 && self.ly == self.lyc)
+            || (self.stat & 0x20 != 0 && self.mode == MODE_OAM_SCAN)
+            // Entering VBlank also triggers the mode 2 condition
+            || (self.stat & 0x20 != 0 && self.mode == MODE_VBLANK && self.ly == SCREEN_HEIGHT as u8 && self.line_cycles == 0)
+            || (self.stat & 0x10 != 0 && self.mode == MODE_VBLANK)
+            || (self.stat & 0x08 != 0 && self.mode == MODE_HBLANK);
+        if signal && !self.stat_signal {
+            events.stat_interrupt = true;
+        }
+        self.stat_signal = signal;
+    }
+
+    fn render_line(&mut self) {
+        let y = self.ly as usize;
+        let mut bg = [BgPixel::default(); SCREEN_WIDTH];
+        let mut line = [0u16; SCREEN_WIDTH];
+
+        // In CGB mode LCDC bit 0 only takes away the background's priority
+        // over sprites; in DMG mode it blanks the background and window
+        let bg_enabled = self.cgb || self.lcdc & 0x01 != 0;
+        let window_visible = bg_enabled && self.lcdc & 0x20 != 0
+            && self.ly >= self.wy && self.wx <= 166;
+        if bg_enabled {
+            for (x, pixel) in bg.iter_mut().enumerate() {
+                let in_window = window_visible && x + 7 >= self.wx as usize;
+                let (map, map_x, map_y) = if in_window {
+                    let map = if self.lcdc & 0x40 != 0 { 0x1C00 } else { 0x1800 };
+                    (map, (x + 7 - self.wx as usize) as u8, self.window_line)
+                } else {
+                    let map = if self.lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+                    (map, (x as u8).wrapping_add(self.scx), self.ly.wrapping_add(self.scy))
+                };
+                let (color, attributes) = self.bg_pixel(map, map_x, map_y);
+                *pixel = BgPixel { color, priority: attributes & 0x80 != 0 };
+                line[x] = if self.cgb {
+                    self.palette_color(&self.bg_palettes, attributes & 0x07, color)
+                } else {
+                    shade(self.bgp, color)
+                };
+            }
+        }
+        if window_visible {
+            self.window_line += 1;
+        }
+
+        if self.lcdc & 0x02 != 0 {
+            self.render_sprites(&bg, &mut line);
+        }
+        self.frame[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH].copy_from_slice(&line);
+    }
+
+    // Colour index and CGB attributes of a background or window pixel
+    fn bg_pixel(&self, map: usize, x: u8, y: u8) -> (u8, u8) {
+        let entry = map + (y as usize / 8) * 32 + x as usize / 8;
+        let tile = self.vram[0][entry];
+        let attributes = if self.cgb { self.vram[1][entry] } else { 0 };
+        let tile_addr = self.bg_tile_addr(tile);
+        let mut row = y as usize % 8;
+        let mut column = x as usize % 8;
+        if attributes & 0x40 != 0 { row = 7 - row; }
+        if attributes & 0x20 != 0 { column = 7 - column; }
+        let bank = ((attributes >> 3) & 1) as usize;
+        (self.tile_pixel(bank, tile_addr, row, column), attributes)
+    }
+
+    // Offset in VRAM of a background or window tile, which LCDC bit 4
+    // switches between unsigned numbers from 0x8000 and signed numbers
+    // around 0x9000
+    pub fn bg_tile_addr(&self, tile: u8) -> usize {
+        if self.lcdc & 0x10 != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + (tile as i8 as isize) * 16) as usize
+        }
+    }
+
+    fn tile_pixel(&self, bank: usize, tile_addr: usize, row: usize, column: usize) -> u8 {
+        let low = self.vram[bank][tile_addr + row * 2];
+        let high = self.vram[bank][tile_addr + row * 2 + 1];
+        let bit = 7 - column;
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+    }
+
+    fn render_sprites(&self, bg: &[BgPixel; SCREEN_WIDTH], line: &mut [u16; SCREEN_WIDTH]) {
+        let height = if self.lcdc & 0x04 != 0 { 16 } else { 8 };
+        let ly = self.ly as i32;
+        let mut sprites: Vec<usize> = (0..40)
+            .filter(|&i| {
+                let top = self.oam[i * 4] as i32 - 16;
+                ly >= top && ly < top + height
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+        // On DMG the sprite with the lower X is drawn on top, with ties
+        // going to the lower OAM index. CGB goes by OAM index alone.
+        if !self.cgb {
+            sprites.sort_by_key(|&i| self.oam[i * 4 + 1]);
+        }
+
+        // Draw the lowest priority sprite first so the others cover it
+        for &i in sprites.iter().rev() {
+            let sprite = &self.oam[i * 4..i * 4 + 4];
+            let top = sprite[0] as i32 - 16;
+            let left = sprite[1] as i32 - 8;
+            let attributes = sprite[3];
+            let mut tile = sprite[2] as usize;
+            if height == 16 { tile &= 0xFE; }
+            let mut row = (ly - top) as usize;
+            if attributes & 0x40 != 0 { row = height as usize - 1 - row; }
+            let bank = if self.cgb { ((attributes >> 3) & 1) as usize } else { 0 };
+
+            for column in 0..8 {
+                let x = left + column as i32;
+                if x < 0 || x >= SCREEN_WIDTH as i32 {
+                    continue;
+                }
+                let x = x as usize;
+                let tile_column = if attributes & 0x20 != 0 { 7 - column } else { column };
+                let color = self.tile_pixel(bank, tile * 16, row, tile_column);
+                if color == 0 || self.bg_covers_sprite(bg[x], attributes) {
+                    continue;
+                }
+                line[x] = if self.cgb {
+                    self.palette_color(&self.obj_palettes, attributes & 0x07, color)
+                } else if attributes & 0x10 != 0 {
+                    shade(self.obp1, color)
+                } else {
+                    shade(self.obp0, color)
+                };
+            }
+        }
+    }
+
+    fn bg_covers_sprite(&self, bg: BgPixel, attributes: u8) -> bool {
+        if bg.color == 0 || (self.cgb && self.lcdc & 0x01 == 0) {
+            return false;
+        }
+        attributes & 0x80 != 0 || bg.priority
+    }
+
+    // RGB555 colours from CGB palette RAM
+    pub fn bg_palette_color(&self, palette: u8, color: u8) -> u16 {
+        self.palette_color(&self.bg_palettes, palette, color)
+    }
+
+    pub fn obj_palette_color(&self, palette: u8, color: u8) -> u16 {
+        self.palette_color(&self.obj_palettes, palette, color)
+    }
+
+    fn palette_color(&self, palettes: &[u8; 64], palette: u8, color: u8) -> u16 {
+        let i = (palette * 8 + color * 2) as usize;
+        u16::from_le_bytes([palettes[i], palettes[i + 1]]) & 0x7FFF
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Ppu {
+        Ppu::new()
+    }
+}
+
+// The shade BGP, OBP0 or OBP1 gives a colour number
+pub fn shade(palette: u8, color: u8) -> u16 {
+    ((palette >> (color * 2)) & 0x03) as u16
+}
+
+// BCPS/OCPS step to the next palette byte after a data write when bit 7
+// is set
+fn auto_increment(spec: u8) -> u8 {
+    if spec & 0x80 != 0 {
+        0x80 | ((spec + 1) & 0x3F)
+    } else {
+        spec
+    }
+}
+
+
+// PPU Tests
+#[cfg(test)]
+fn ppu_with_tile(cgb: bool) -> Ppu {
+    let mut ppu = Ppu::new();
+    ppu.set_cgb_mode(cgb);
+    // Tile 1 is solid colour 3 in bank 0 and solid colour 1 in bank 1
+    for i in 0..8 {
+        ppu.vram[0][16 + i * 2] = 0xFF;
+        ppu.vram[0][16 + i * 2 + 1] = 0xFF;
+        ppu.vram[1][16 + i * 2] = 0xFF;
+    }
+    ppu.write(0xFF47, 0xE4);
+    ppu
+}
+
+#[cfg(test)]
+fn run_frame(ppu: &mut Ppu) -> Vec<PpuEvents> {
+    (0..LINES as u32 * LINE_CYCLES / 4).map(|_| ppu.tick(4)).collect()
+}
+
+#[test]
+fn ppu_line_timing() {
+    let mut ppu = Ppu::new();
+    ppu.write(0xFF40, 0x80);
+    assert_eq!(ppu.read(0xFF41) & 0x03, MODE_OAM_SCAN);
+    ppu.tick(OAM_SCAN_CYCLES);
+    assert_eq!(ppu.read(0xFF41) & 0x03, MODE_TRANSFER);
+    assert!(ppu.tick(172).hblank);
+    assert_eq!(ppu.read(0xFF41) & 0x03, MODE_HBLANK);
+    ppu.tick(LINE_CYCLES - TRANSFER_END);
+    assert_eq!(ppu.read(0xFF44), 1);
+    assert_eq!(ppu.read(0xFF41) & 0x03, MODE_OAM_SCAN);
+}
+#[test]
+fn ppu_vblank_once_per_frame() {
+    let mut ppu = Ppu::new();
+    ppu.write(0xFF40, 0x80);
+    let events = run_frame(&mut ppu);
+    assert_eq!(events.iter().filter(|e| e.vblank_interrupt).count(), 1);
+    assert_eq!(events.iter().filter(|e| e.hblank).count(), SCREEN_HEIGHT);
+    assert_eq!(ppu.read(0xFF44), 0);
+}
+#[test]
+fn ppu_lyc_stat_interrupt() {
+    let mut ppu = Ppu::new();
+    ppu.write(0xFF40, 0x80);
+    ppu.write(0xFF45, 10);
+    ppu.write(0xFF41, 0x40);
+    let events = run_frame(&mut ppu);
+    let first = events.iter().position(|e| e.stat_interrupt).unwrap();
+    assert_eq!((first as u32 + 1) * 4, 10 * LINE_CYCLES);
+    assert_eq!(events.iter().filter(|e| e.stat_interrupt).count(), 1);
+}
+#[test]
+fn ppu_dmg_background_uses_bgp() {
+    let mut ppu = ppu_with_tile(false);
+    ppu.vram[0][0x1800] = 1;
+    ppu.write(0xFF47, 0x1B);
+    ppu.write(0xFF40, 0x91);
+    run_frame(&mut ppu);
+    assert_eq!(ppu.frame()[0], 0);
+    assert_eq!(ppu.frame()[8], 3);
+}
+#[test]
+fn ppu_cgb_registers_ignored_in_dmg_mode() {
+    let mut ppu = Ppu::new();
+    ppu.write(0xFF4F, 1);
+    assert_eq!(ppu.read(0xFF4F), 0xFF);
+    assert_eq!(ppu.read(0xFF68), 0xFF);
+}
+#[test]
+fn ppu_cgb_palette_auto_increment() {
+    let mut ppu = Ppu::new();
+    ppu.set_cgb_mode(true);
+    ppu.write(0xFF68, 0x80 | 0x3E);
+    ppu.write(0xFF69, 0x12);
+    ppu.write(0xFF69, 0x34);
+    ppu.write(0xFF69, 0x56);
+    assert_eq!(ppu.read(0xFF68), 0xC1);
+    ppu.write(0xFF68, 0x3E);
+    assert_eq!(ppu.read(0xFF69), 0x12);
+    ppu.write(0xFF68, 0x00);
+    assert_eq!(ppu.read(0xFF69), 0x56);
+}
+#[test]
+fn ppu_cgb_vram_banks() {
+    let mut ppu = Ppu::new();
+    ppu.set_cgb_mode(true);
+    ppu.write_vram(0x8000, 0x11);
+    ppu.write(0xFF4F, 1);
+    assert_eq!(ppu.read(0xFF4F), 0xFF);
+    assert_eq!(ppu.read_vram(0x8000), 0x00);
+    ppu.write_vram(0x8000, 0x22);
+    ppu.write(0xFF4F, 0);
+    assert_eq!(ppu.read(0xFF4F), 0xFE);
+    assert_eq!(ppu.read_vram(0x8000), 0x11);
+}
+#[test]
+fn ppu_cgb_map_attributes() {
+    let mut ppu = ppu_with_tile(true);
+    // Palette 2 colour 1 is red, colour 3 is blue
+    ppu.bg_palettes[2 * 8 + 2..2 * 8 + 4].copy_from_slice(&0x001Fu16.to_le_bytes());
+    ppu.bg_palettes[2 * 8 + 6..2 * 8 + 8].copy_from_slice(&0x7C00u16.to_le_bytes());
+    ppu.vram[0][0x1800] = 1;
+    ppu.vram[0][0x1801] = 1;
+    ppu.vram[1][0x1800] = 0x02;
+    // Second tile from bank 1
+    ppu.vram[1][0x1801] = 0x0A;
+    ppu.write(0xFF40, 0x91);
+    run_frame(&mut ppu);
+    assert_eq!(ppu.frame()[0], 0x7C00);
+    assert_eq!(ppu.frame()[8], 0x001F);
+}
+#[test]
+fn ppu_cgb_flip_attributes() {
+    let mut ppu = ppu_with_tile(true);
+    ppu.bg_palettes[2..4].copy_from_slice(&0x001Fu16.to_le_bytes());
+    ppu.bg_palettes[0..2].copy_from_slice(&0x0000u16.to_le_bytes());
+    // Tile 2 only has its top-left pixel set
+    ppu.vram[0][32] = 0x80;
+    ppu.vram[0][0x1800] = 2;
+    ppu.vram[1][0x1800] = 0x60;
+    ppu.write(0xFF40, 0x91);
+    run_frame(&mut ppu);
+    assert_eq!(ppu.frame()[0], 0);
+    assert_eq!(ppu.frame()[7 * SCREEN_WIDTH + 7], 0x001F);
+}
+#[test]
+fn ppu_sprite_priority() {
+    let mut ppu = ppu_with_tile(false);
+    ppu.write(0xFF48, 0xE4);
+    ppu.write(0xFF49, 0x00);
+    // Two overlapping sprites; on DMG the one further left wins
+    ppu.oam[0..4].copy_from_slice(&[16, 12, 1, 0x10]);
+    ppu.oam[4..8].copy_from_slice(&[16, 8, 1, 0x00]);
+    ppu.write(0xFF40, 0x83);
+    run_frame(&mut ppu);
+    assert_eq!(ppu.frame()[0], 3);
+    assert_eq!(ppu.frame()[4], 3);
+    assert_eq!(ppu.frame()[8], 0);
+}
+#[test]
+fn ppu_sprite_behind_background() {
+    let mut ppu = ppu_with_tile(false);
+    ppu.write(0xFF47, 0x54);
+    ppu.write(0xFF48, 0xFC);
+    ppu.vram[0][0x1801] = 1;
+    ppu.oam[0..4].copy_from_slice(&[16, 12, 1, 0x80]);
+    ppu.write(0xFF40, 0x93);
+    run_frame(&mut ppu);
+    // Behind colour 0 the sprite shows, behind colours 1-3 it doesn't
+    assert_eq!(ppu.frame()[4], 3);
+    assert_eq!(ppu.frame()[8], 1);
+}
+#[test]
+fn ppu_window_line_counter() {
+    let mut ppu = ppu_with_tile(false);
+    // Window map row 1 uses tile 1
+    for i in 0..32 {
+        ppu.vram[0][0x1C00 + 32 + i] = 1;
+    }
+    ppu.write(0xFF4A, 4);
+    ppu.write(0xFF4B, 7);
+    ppu.write(0xFF40, 0xF1);
+    run_frame(&mut ppu);
+    assert_eq!(ppu.frame()[11 * SCREEN_WIDTH], 0);
+    assert_eq!(ppu.frame()[12 * SCREEN_WIDTH], 3);
+}
@@ -0,0 +1,421 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::png::{self, ColorType};
+use crate::serial::SerialDevice;
+
+pub const PAGE_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PAGE_WIDTH / 8;
+const TILE_BYTES: usize = 16;
+// The printer's 8 KiB of RAM holds at most this many bytes of tile data
+const BUFFER_LIMIT: usize = 0x2000;
+// Pixel rows fed per unit of margin
+const FEED_LINES: usize = 8;
+// How many status requests a print keeps the printer busy for
+const PRINT_BUSY_POLLS: u8 = 4;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const DEVICE_ID: u8 = 0x81;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0F;
+
+// Status byte bits
+pub const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+pub const STATUS_PRINTING: u8 = 0x02;
+pub const STATUS_IMAGE_FULL: u8 = 0x04;
+pub const STATUS_UNPROCESSED: u8 = 0x08;
+pub const STATUS_PACKET_ERROR: u8 = 0x10;
+
+// Gray level for each shade, lightest first
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+// The printer treats a palette of 0 as the usual 0b11100100
+const DEFAULT_PALETTE: u8 = 0xE4;
+
+// Where the next byte falls in a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    Magic(usize),
+    Command,
+    Compression,
+    Length(usize),
+    Data,
+    Checksum(usize),
+    DeviceId,
+    Status,
+}
+
+// A finished page: PAGE_WIDTH pixels per row, each a shade from 0 (white)
+// to 3 (black)
+pub struct Page {
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Page {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let gray: Vec<u8> = self.pixels.iter().map(|&s| SHADES[s as usize & 3]).collect();
+        png::save(path, PAGE_WIDTH as u32, self.height as u32, ColorType::Gray, &gray)
+    }
+}
+
+// The Game Boy Printer. The Game Boy always drives the clock and sends
+// packets of the form
+//
+//   0x88 0x33 command compression length(2) data... checksum(2) 0x00 0x00
+//
+// where the checksum is the 16-bit sum of every byte from the command to
+// the end of the data. The printer answers 0x00 to everything except the
+// last two bytes, which get its device ID and status.
+//
+// Printed strips are added to a page until a print feeds paper after the
+// image; the page is then finished and, with an output directory, saved
+// as print_NNN.png.
+pub struct Printer {
+    output_dir: Option<PathBuf>,
+    phase: Phase,
+    command: u8,
+    compressed: bool,
+    length: usize,
+    data: Vec<u8>,
+    checksum: u16,
+    expected_checksum: u16,
+    status: u8,
+    busy_polls: u8,
+    // Decompressed tile data waiting to be printed
+    buffer: Vec<u8>,
+    page: Vec<u8>,
+    pages: Vec<Page>,
+    saved: usize,
+}
+
+impl Printer {
+    pub fn new(output_dir: Option<PathBuf>) -> Printer {
+        Printer {
+            output_dir,
+            phase: Phase::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: vec![],
+            checksum: 0,
+            expected_checksum: 0,
+            status: 0,
+            busy_polls: 0,
+            buffer: vec![],
+            page: vec![],
+            pages: vec![],
+            saved: 0,
+        }
+    }
+
+    // Pages finished so far
+    pub fn pages(&self) -> &[Page] {
+        &self.pages
+    }
+
+    // Finishes the page being printed, if anything has been printed on it
+    pub fn feed_page(&mut self) -> io::Result<()> {
+        if self.page.is_empty() {
+            return Ok(());
+        }
+        let pixels = std::mem::take(&mut self.page);
+        let page = Page { height: pixels.len() / PAGE_WIDTH, pixels };
+        self.pages.push(page);
+        self.save_pages()
+    }
+
+    fn save_pages(&mut self) -> io::Result<()> {
+        let dir = match self.output_dir.as_ref() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        std::fs::create_dir_all(dir)?;
+        while self.saved < self.pages.len() {
+            let path = dir.join(format!("print_{:03}.png", self.saved + 1));
+            self.pages[self.saved].save(path)?;
+            self.saved += 1;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, byte: u8) -> u8 {
+        match self.phase {
+            Phase::Magic(i) => {
+                self.phase = if byte != MAGIC[i] {
+                    Phase::Magic(if byte == MAGIC[0] { 1 } else { 0 })
+                } else if i == 0 {
+                    Phase::Magic(1)
+                } else {
+                    Phase::Command
+                };
+            }
+            Phase::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                self.phase = Phase::Compression;
+            }
+            Phase::Compression => {
+                self.compressed = byte & 1 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.phase = Phase::Length(0);
+            }
+            Phase::Length(i) => {
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if i == 0 {
+                    self.length = byte as usize;
+                    self.phase = Phase::Length(1);
+                } else {
+                    self.length |= (byte as usize) << 8;
+                    self.data.clear();
+                    self.phase = if self.length == 0 { Phase::Checksum(0) } else { Phase::Data };
+                }
+            }
+            Phase::Data => {
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.push(byte);
+                if self.data.len() == self.length {
+                    self.phase = Phase::Checksum(0);
+                }
+            }
+            Phase::Checksum(i) => {
+                if i == 0 {
+                    self.expected_checksum = byte as u16;
+                    self.phase = Phase::Checksum(1);
+                } else {
+                    self.expected_checksum |= (byte as u16) << 8;
+                    self.phase = Phase::DeviceId;
+                }
+            }
+            Phase::DeviceId => {
+                self.run_command();
+                self.phase = Phase::Status;
+                return DEVICE_ID;
+            }
+            Phase::Status => {
+                self.phase = Phase::Magic(0);
+                return self.status;
+            }
+        }
+        0x00
+    }
+
+    fn run_command(&mut self) {
+        if self.checksum != self.expected_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+        match self.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            CMD_DATA => {
+                let data = std::mem::take(&mut self.data);
+                if self.compressed {
+                    decompress(&data, &mut self.buffer);
+                } else {
+                    self.buffer.extend_from_slice(&data);
+                }
+                self.buffer.truncate(BUFFER_LIMIT);
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                // An empty data packet marks the end of the image
+                if data.is_empty() || self.buffer.len() == BUFFER_LIMIT {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            }
+            CMD_PRINT if self.data.len() >= 4 => {
+                let margins = self.data[1];
+                let palette = self.data[2];
+                self.print(margins >> 4, margins & 0x0F, palette);
+                self.status &= !STATUS_UNPROCESSED;
+                self.status |= STATUS_PRINTING;
+                self.busy_polls = PRINT_BUSY_POLLS;
+            }
+            CMD_STATUS => {
+                if self.busy_polls > 0 {
+                    self.busy_polls -= 1;
+                    if self.busy_polls == 0 {
+                        self.status &= !(STATUS_PRINTING | STATUS_IMAGE_FULL);
+                    }
+                }
+            }
+            _ => self.status |= STATUS_PACKET_ERROR,
+        }
+    }
+
+    fn print(&mut self, margin_before: u8, margin_after: u8, palette: u8) {
+        let feed = |page: &mut Vec<u8>, units: u8| {
+            page.resize(page.len() + units as usize * FEED_LINES * PAGE_WIDTH, 0);
+        };
+        feed(&mut self.page, margin_before);
+        let palette = if palette == 0 { DEFAULT_PALETTE } else { palette };
+        let tile_rows = self.buffer.len() / (TILES_PER_ROW * TILE_BYTES);
+        for tile_row in 0..tile_rows {
+            for line in 0..8 {
+                for tile in 0..TILES_PER_ROW {
+                    let offset = (tile_row * TILES_PER_ROW + tile) * TILE_BYTES + line * 2;
+                    let (lo, hi) = (self.buffer[offset], self.buffer[offset + 1]);
+                    for bit in (0..8).rev() {
+                        let color = ((lo >> bit) & 1) | (((hi >> bit) & 1) << 1);
+                        self.page.push((palette >> (color * 2)) & 0x03);
+                    }
+                }
+            }
+        }
+        self.buffer.clear();
+        if margin_after > 0 {
+            feed(&mut self.page, margin_after);
+            if let Err(e) = self.feed_page() {
+                eprintln!("couldn't save printed page: {}", e);
+            }
+        }
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.receive(byte)
+    }
+
+    // The printer never drives the clock
+    fn external_clock(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        let _ = self.feed_page();
+    }
+}
+
+// Run-length encoding used by DATA packets: a control byte with bit 7 set
+// repeats the next byte (control & 0x7F) + 2 times, otherwise the next
+// control + 1 bytes are literal.
+fn decompress(data: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            if let Some(&byte) = data.get(i) {
+                out.extend(std::iter::repeat_n(byte, (control & 0x7F) as usize + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+}
+
+
+// Printer Tests
+#[cfg(test)]
+fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> (u8, u8) {
+    let mut packet = vec![command, compressed as u8, data.len() as u8, (data.len() >> 8) as u8];
+    packet.extend_from_slice(data);
+    let checksum = packet.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+    for &byte in MAGIC.iter().chain(packet.iter()).chain(checksum.to_le_bytes().iter()) {
+        assert_eq!(printer.exchange(byte), 0x00);
+    }
+    (printer.exchange(0x00), printer.exchange(0x00))
+}
+
+#[test]
+fn printer_answers_status() {
+    let mut printer = Printer::new(None);
+    assert_eq!(send_packet(&mut printer, CMD_INIT, false, &[]), (0x81, 0x00));
+    assert_eq!(send_packet(&mut printer, CMD_STATUS, false, &[]), (0x81, 0x00));
+}
+#[test]
+fn printer_checksum_error() {
+    let mut printer = Printer::new(None);
+    for &byte in [0x88, 0x33, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00].iter() {
+        printer.exchange(byte);
+    }
+    assert_eq!(printer.exchange(0), 0x81);
+    assert_eq!(printer.exchange(0), STATUS_CHECKSUM_ERROR);
+}
+#[test]
+fn printer_resyncs_on_magic() {
+    let mut printer = Printer::new(None);
+    printer.exchange(0x12);
+    printer.exchange(0x88);
+    assert_eq!(send_packet(&mut printer, CMD_STATUS, false, &[]), (0x81, 0x00));
+}
+#[test]
+fn printer_decompress() {
+    let mut out = vec![];
+    decompress(&[0x81, 0xAA, 0x01, 0x12, 0x34], &mut out);
+    assert_eq!(out, vec![0xAA, 0xAA, 0xAA, 0x12, 0x34]);
+}
+#[test]
+fn printer_prints_page() {
+    let mut printer = Printer::new(None);
+    send_packet(&mut printer, CMD_INIT, false, &[]);
+    // Two tile rows: every pixel color 1 in the first, color 3 in the second
+    let mut tiles = vec![];
+    for _ in 0..TILES_PER_ROW {
+        tiles.extend_from_slice(&[0xFF, 0x00].repeat(8));
+    }
+    for _ in 0..TILES_PER_ROW {
+        tiles.extend_from_slice(&[0xFF, 0xFF].repeat(8));
+    }
+    let (_, status) = send_packet(&mut printer, CMD_DATA, false, &tiles);
+    assert_eq!(status, STATUS_UNPROCESSED);
+    let (_, status) = send_packet(&mut printer, CMD_DATA, false, &[]);
+    assert_eq!(status, STATUS_UNPROCESSED | STATUS_IMAGE_FULL);
+    // One feed before, one after, palette 0b11100100
+    let (_, status) = send_packet(&mut printer, CMD_PRINT, false, &[0x01, 0x11, 0xE4, 0x40]);
+    assert_eq!(status, STATUS_PRINTING | STATUS_IMAGE_FULL);
+    for _ in 0..PRINT_BUSY_POLLS - 1 {
+        assert_ne!(send_packet(&mut printer, CMD_STATUS, false, &[]).1 & STATUS_PRINTING, 0);
+    }
+    assert_eq!(send_packet(&mut printer, CMD_STATUS, false, &[]).1, 0x00);
+
+    assert_eq!(printer.pages().len(), 1);
+    let page = &printer.pages()[0];
+    assert_eq!(page.height, 8 + 16 + 8);
+    assert_eq!(page.pixels[0], 0);
+    assert_eq!(page.pixels[8 * PAGE_WIDTH], 1);
+    assert_eq!(page.pixels[16 * PAGE_WIDTH + 159], 3);
+}
+#[test]
+fn printer_joins_strips_without_margins() {
+    let mut printer = Printer::new(None);
+    let tiles = vec![0xFF; TILES_PER_ROW * TILE_BYTES];
+    send_packet(&mut printer, CMD_DATA, false, &tiles);
+    send_packet(&mut printer, CMD_PRINT, false, &[0x01, 0x00, 0xE4, 0x40]);
+    assert!(printer.pages().is_empty());
+    send_packet(&mut printer, CMD_DATA, true, &[0x80 | 0x7F, 0xFF, 0x80 | 0x7F, 0xFF, 0x80 | 0x3C, 0xFF]);
+    send_packet(&mut printer, CMD_PRINT, false, &[0x01, 0x01, 0xE4, 0x40]);
+    assert_eq!(printer.pages().len(), 1);
+    assert_eq!(printer.pages()[0].height, 8 + 8 + 8);
+}
+#[test]
+fn printer_zero_palette_is_default() {
+    let mut printer = Printer::new(None);
+    let tiles = [0xFF, 0x00].repeat(TILES_PER_ROW * 8);
+    send_packet(&mut printer, CMD_DATA, false, &tiles);
+    send_packet(&mut printer, CMD_PRINT, false, &[0x01, 0x01, 0x00, 0x40]);
+    assert_eq!(printer.pages()[0].pixels[0], 1);
+}
+#[test]
+fn printer_saves_png() {
+    let dir = std::env::temp_dir().join("gb_emu_printer_test");
+    let mut printer = Printer::new(Some(dir.clone()));
+    send_packet(&mut printer, CMD_DATA, false, &vec![0; TILES_PER_ROW * TILE_BYTES]);
+    send_packet(&mut printer, CMD_PRINT, false, &[0x01, 0x03, 0xE4, 0x40]);
+    let bytes = std::fs::read(dir.join("print_001.png")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(&bytes[1..4], b"PNG");
+}
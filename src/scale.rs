@@ -0,0 +1,343 @@
+// Pixel art upscalers. Nearest neighbour, Scale2x and Scale3x only compare
+// pixels, so they work on palette-indexed frames as well as RGBA; the
+// HQ2x-style and xBR-lite filters blend colours and need RGBA.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    XbrLite,
+}
+
+impl Filter {
+    pub fn parse(name: &str) -> Option<Filter> {
+        match name.to_ascii_lowercase().as_str() {
+            "nearest" | "none" => Some(Filter::Nearest),
+            "scale2x" | "epx" => Some(Filter::Scale2x),
+            "scale3x" => Some(Filter::Scale3x),
+            "hq2x" => Some(Filter::Hq2x),
+            "xbr" | "xbr-lite" => Some(Filter::XbrLite),
+            _ => None,
+        }
+    }
+
+    // Scale the filter itself produces in one pass
+    pub fn factor(self) -> usize {
+        match self {
+            Filter::Nearest => 1,
+            Filter::Scale3x => 3,
+            _ => 2,
+        }
+    }
+
+    fn blends(self) -> bool {
+        matches!(self, Filter::Hq2x | Filter::XbrLite)
+    }
+}
+
+// Scales by `scale` in each direction. The filter runs as many times as
+// the scale allows (Scale2x twice for 4x) and nearest neighbour makes up
+// the rest, so the scale has to be a multiple of the filter's own factor.
+pub fn scale<T: Copy + PartialEq>(pixels: &[T], width: usize, height: usize, filter: Filter, scale: usize) -> Result<Vec<T>, String> {
+    if filter.blends() {
+        return Err(format!("{:?} only works on RGBA frames", filter));
+    }
+    scale_with(pixels, width, height, filter, scale, |_, _, _, _, _| unreachable!())
+}
+
+pub fn scale_rgba(rgba: &[u8], width: usize, height: usize, filter: Filter, scale: usize) -> Result<Vec<u8>, String> {
+    let pixels: Vec<[u8; 4]> = rgba.chunks(4).map(|p| [p[0], p[1], p[2], p[3]]).collect();
+    let scaled = scale_with(&pixels, width, height, filter, scale, |filter, src, w, h, dst| {
+        match filter {
+            Filter::Hq2x => hq2x(src, w, h, dst),
+            _ => xbr_lite(src, w, h, dst),
+        }
+    })?;
+    Ok(scaled.concat())
+}
+
+type BlendingFilter<T> = fn(Filter, &[T], usize, usize, &mut [T]);
+
+fn scale_with<T: Copy + PartialEq>(
+    pixels: &[T], width: usize, height: usize, filter: Filter, scale: usize, blending: BlendingFilter<T>,
+) -> Result<Vec<T>, String> {
+    if pixels.len() != width * height {
+        return Err("pixel data doesn't match image size".to_string());
+    }
+    if scale == 0 || !scale.is_multiple_of(filter.factor()) {
+        return Err(format!("{:?} can't scale by {}", filter, scale));
+    }
+    let mut image = pixels.to_vec();
+    let (mut w, mut h) = (width, height);
+    let mut remaining = scale;
+    while filter != Filter::Nearest && remaining.is_multiple_of(filter.factor()) {
+        let factor = filter.factor();
+        let mut out = vec![image[0]; w * factor * h * factor];
+        match filter {
+            Filter::Scale2x => scale2x(&image, w, h, &mut out),
+            Filter::Scale3x => scale3x(&image, w, h, &mut out),
+            _ => blending(filter, &image, w, h, &mut out),
+        }
+        image = out;
+        w *= factor;
+        h *= factor;
+        remaining /= factor;
+    }
+    Ok(nearest(&image, w, h, remaining))
+}
+
+fn nearest<T: Copy>(pixels: &[T], width: usize, height: usize, scale: usize) -> Vec<T> {
+    if scale == 1 {
+        return pixels.to_vec();
+    }
+    let mut out = Vec::with_capacity(pixels.len() * scale * scale);
+    for row in pixels.chunks(width).take(height) {
+        let mut line = Vec::with_capacity(width * scale);
+        for &p in row {
+            line.extend(std::iter::repeat_n(p, scale));
+        }
+        for _ in 0..scale {
+            out.extend_from_slice(&line);
+        }
+    }
+    out
+}
+
+// Pixel at (x + dx, y + dy), repeating the edge pixels outside the image
+fn at<T: Copy>(pixels: &[T], width: usize, height: usize, x: usize, y: usize, dx: isize, dy: isize) -> T {
+    let x = (x as isize + dx).clamp(0, width as isize - 1) as usize;
+    let y = (y as isize + dy).clamp(0, height as isize - 1) as usize;
+    pixels[y * width + x]
+}
+
+fn scale2x<T: Copy + PartialEq>(src: &[T], w: usize, h: usize, dst: &mut [T]) {
+    for y in 0..h {
+        for x in 0..w {
+            let p = |dx, dy| at(src, w, h, x, y, dx, dy);
+            let (a, b, c, d, e) = (p(0, -1), p(1, 0), p(-1, 0), p(0, 1), p(0, 0));
+            let out = [
+                if c == a && c != d && a != b { a } else { e },
+                if a == b && a != c && b != d { b } else { e },
+                if d == c && d != b && c != a { c } else { e },
+                if b == d && b != a && d != c { d } else { e },
+            ];
+            for (i, &o) in out.iter().enumerate() {
+                dst[(y * 2 + i / 2) * w * 2 + x * 2 + i % 2] = o;
+            }
+        }
+    }
+}
+
+fn scale3x<T: Copy + PartialEq>(src: &[T], w: usize, h: usize, dst: &mut [T]) {
+    for y in 0..h {
+        for x in 0..w {
+            let p = |dx, dy| at(src, w, h, x, y, dx, dy);
+            let (a, b, c) = (p(-1, -1), p(0, -1), p(1, -1));
+            let (d, e, f) = (p(-1, 0), p(0, 0), p(1, 0));
+            let (g, hh, i) = (p(-1, 1), p(0, 1), p(1, 1));
+            let out = if b != hh && d != f {
+                [
+                    if d == b { d } else { e },
+                    if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                    if b == f { f } else { e },
+                    if (d == b && e != g) || (d == hh && e != a) { d } else { e },
+                    e,
+                    if (b == f && e != i) || (hh == f && e != c) { f } else { e },
+                    if d == hh { d } else { e },
+                    if (d == hh && e != i) || (hh == f && e != g) { hh } else { e },
+                    if hh == f { f } else { e },
+                ]
+            } else {
+                [e; 9]
+            };
+            for (n, &o) in out.iter().enumerate() {
+                dst[(y * 3 + n / 3) * w * 3 + x * 3 + n % 3] = o;
+            }
+        }
+    }
+}
+
+fn yuv(p: [u8; 4]) -> (i32, i32, i32) {
+    let (r, g, b) = (p[0] as i32, p[1] as i32, p[2] as i32);
+    (
+        (299 * r + 587 * g + 114 * b) / 1000,
+        (-169 * r - 331 * g + 500 * b) / 1000 + 128,
+        (500 * r - 419 * g - 81 * b) / 1000 + 128,
+    )
+}
+
+// The colour difference thresholds HQ2x uses
+fn similar(a: [u8; 4], b: [u8; 4]) -> bool {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    (ya - yb).abs() <= 48 && (ua - ub).abs() <= 7 && (va - vb).abs() <= 6
+}
+
+fn distance(a: [u8; 4], b: [u8; 4]) -> i32 {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    48 * (ya - yb).abs() + 7 * (ua - ub).abs() + 6 * (va - vb).abs()
+}
+
+fn mix(colors: &[([u8; 4], u32)]) -> [u8; 4] {
+    let total: u32 = colors.iter().map(|&(_, w)| w).sum();
+    let mut out = [0; 4];
+    for (channel, value) in out.iter_mut().enumerate() {
+        let sum: u32 = colors.iter().map(|&(c, w)| c[channel] as u32 * w).sum();
+        *value = ((sum + total / 2) / total) as u8;
+    }
+    out
+}
+
+type Offset = (isize, isize);
+
+// Corners of a 2x output pixel as (x, y), with the two neighbours next to
+// each corner and the one diagonal to it
+const CORNERS: [((usize, usize), Offset, Offset, Offset); 4] = [
+    ((0, 0), (0, -1), (-1, 0), (-1, -1)),
+    ((1, 0), (0, -1), (1, 0), (1, -1)),
+    ((0, 1), (0, 1), (-1, 0), (-1, 1)),
+    ((1, 1), (0, 1), (1, 0), (1, 1)),
+];
+
+// A simplified HQ2x: instead of the full pattern table, a corner whose two
+// neighbours match each other but not the pixel is blended towards them,
+// which rounds off edges without the staircase Scale2x leaves
+fn hq2x(src: &[[u8; 4]], w: usize, h: usize, dst: &mut [[u8; 4]]) {
+    for y in 0..h {
+        for x in 0..w {
+            let p = |(dx, dy): (isize, isize)| at(src, w, h, x, y, dx, dy);
+            let e = p((0, 0));
+            for &((cx, cy), n1, n2, diagonal) in CORNERS.iter() {
+                let (a, b, d) = (p(n1), p(n2), p(diagonal));
+                let out = if similar(a, b) && !similar(e, a) {
+                    if similar(d, a) {
+                        mix(&[(e, 2), (a, 1), (b, 1)])
+                    } else {
+                        mix(&[(e, 6), (a, 1), (b, 1)])
+                    }
+                } else {
+                    e
+                };
+                dst[(y * 2 + cy) * w * 2 + x * 2 + cx] = out;
+            }
+        }
+    }
+}
+
+// The first level of xBR: each corner compares the colour distances along
+// both diagonals of the surrounding 4x4 block, and where an edge runs
+// across the corner it is blended with the closer of its two neighbours
+fn xbr_lite(src: &[[u8; 4]], w: usize, h: usize, dst: &mut [[u8; 4]]) {
+    // Output corner for each quarter turn of the bottom right case
+    const TURNS: [(usize, usize); 4] = [(1, 1), (0, 1), (0, 0), (1, 0)];
+    for y in 0..h {
+        for x in 0..w {
+            for (turn, &(cx, cy)) in TURNS.iter().enumerate() {
+                // Offsets as seen from the bottom right, rotated a quarter
+                // turn at a time
+                let p = |dx: isize, dy: isize| {
+                    let (mut dx, mut dy) = (dx, dy);
+                    for _ in 0..turn {
+                        let t = dx;
+                        dx = -dy;
+                        dy = t;
+                    }
+                    at(src, w, h, x, y, dx, dy)
+                };
+                let (e, f, hh, i) = (p(0, 0), p(1, 0), p(0, 1), p(1, 1));
+                let (b, c, d, g) = (p(0, -1), p(1, -1), p(-1, 0), p(-1, 1));
+                let (f4, i4, h5, i5) = (p(2, 0), p(2, 1), p(0, 2), p(1, 2));
+                let across = distance(e, c) + distance(e, g) + distance(i, f4) + distance(i, h5) + 4 * distance(hh, f);
+                let along = distance(hh, d) + distance(hh, i5) + distance(f, i4) + distance(f, b) + 4 * distance(e, i);
+                let out = if across < along && e != f && e != hh {
+                    let new = if distance(e, f) <= distance(e, hh) { f } else { hh };
+                    mix(&[(e, 1), (new, 1)])
+                } else {
+                    e
+                };
+                dst[(y * 2 + cy) * w * 2 + x * 2 + cx] = out;
+            }
+        }
+    }
+}
+
+
+// Scaler Tests
+#[cfg(test)]
+const W: [u8; 4] = [255, 255, 255, 255];
+#[cfg(test)]
+const K: [u8; 4] = [0, 0, 0, 255];
+
+#[test]
+fn scale_nearest() {
+    let out = scale(&[1, 2, 3, 4], 2, 2, Filter::Nearest, 2).unwrap();
+    assert_eq!(out, vec![1, 1, 2, 2, 1, 1, 2, 2, 3, 3, 4, 4, 3, 3, 4, 4]);
+    assert_eq!(scale(&[1, 2], 2, 1, Filter::Nearest, 1).unwrap(), vec![1, 2]);
+}
+#[test]
+fn scale_scale2x_rounds_diagonals() {
+    // A diagonal step
+    let src = [0, 1, 1, 1];
+    let out = scale(&src, 2, 2, Filter::Scale2x, 2).unwrap();
+    assert_eq!(out, vec![
+        0, 0, 1, 1,
+        0, 1, 1, 1,
+        1, 1, 1, 1,
+        1, 1, 1, 1,
+    ]);
+}
+#[test]
+fn scale_scale3x_keeps_flat_areas() {
+    let out = scale(&[5; 4], 2, 2, Filter::Scale3x, 3).unwrap();
+    assert_eq!(out, vec![5; 36]);
+    let out = scale(&[0, 1, 1, 1], 2, 2, Filter::Scale3x, 3).unwrap();
+    assert_eq!(&out[0..6], &[0, 0, 0, 1, 1, 1]);
+    assert_eq!(&out[6..12], &[0, 0, 1, 1, 1, 1]);
+    assert_eq!(&out[12..18], &[0, 1, 1, 1, 1, 1]);
+}
+#[test]
+fn scale_repeats_filter_then_nearest() {
+    let src = [0, 1, 1, 1];
+    let twice = scale(&src, 2, 2, Filter::Scale2x, 4).unwrap();
+    let step = scale(&src, 2, 2, Filter::Scale2x, 2).unwrap();
+    assert_eq!(twice, scale(&step, 4, 4, Filter::Scale2x, 2).unwrap());
+    assert_eq!(scale(&src, 2, 2, Filter::Scale3x, 6).unwrap().len(), 144);
+    assert!(scale(&src, 2, 2, Filter::Scale2x, 3).is_err());
+    assert!(scale(&src, 2, 2, Filter::Nearest, 0).is_err());
+    assert!(scale(&src, 2, 2, Filter::Hq2x, 2).is_err());
+}
+#[test]
+fn scale_hq2x_blends_edges() {
+    let src = [K, W, W, W].concat();
+    let out = scale_rgba(&src, 2, 2, Filter::Hq2x, 2).unwrap();
+    let px = |x: usize, y: usize| &out[(y * 4 + x) * 4..(y * 4 + x) * 4 + 4];
+    // The black pixel's corner facing the white ones is softened
+    assert_eq!(px(1, 1), &[128, 128, 128, 255]);
+    assert_eq!(px(0, 0), &K);
+    assert_eq!(px(3, 3), &W);
+}
+#[test]
+fn scale_xbr_lite_smooths_diagonal_lines() {
+    // A one pixel wide diagonal line
+    let mut src = [W; 16];
+    for i in 0..4 {
+        src[i * 4 + i] = K;
+    }
+    let out = scale_rgba(&src.concat(), 4, 4, Filter::XbrLite, 2).unwrap();
+    assert_eq!(out.len(), 8 * 8 * 4);
+    let px = |x: usize, y: usize| [out[(y * 8 + x) * 4], out[(y * 8 + x) * 4 + 1]];
+    assert_eq!(px(2, 2), [0, 0]);
+    // The gaps between the steps are filled in
+    assert_eq!(px(3, 2), [128, 128]);
+    assert_eq!(px(0, 7), [255, 255]);
+}
+#[test]
+fn scale_filter_names() {
+    assert_eq!(Filter::parse("Scale2x"), Some(Filter::Scale2x));
+    assert_eq!(Filter::parse("xbr"), Some(Filter::XbrLite));
+    assert_eq!(Filter::parse("bilinear"), None);
+    assert_eq!(Filter::Scale3x.factor(), 3);
+}
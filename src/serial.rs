@@ -0,0 +1,206 @@
+// Cycles per bit with the internal 8192 Hz clock
+const BIT_CYCLES: u32 = 512;
+
+// Something plugged into the link port. The Game Boy on the other end of
+// a transfer sees the bits the device shifts back one byte at a time.
+pub trait SerialDevice: Send {
+    // The Game Boy is driving the clock and shifting out `byte`; returns
+    // the byte the device shifts back in the same transfer.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    // The Game Boy is waiting for an external clock with `byte` in SB.
+    // Returns the byte shifted in once the device has clocked a whole
+    // transfer, or None while nothing has happened yet.
+    fn external_clock(&mut self, byte: u8) -> Option<u8>;
+
+    // Called as the Game Boy runs, for devices that keep time with it
+    fn tick(&mut self, _cycles: u32) {}
+}
+
+// Nothing connected: the input line is pulled high and nobody ever drives
+// the clock
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+
+    fn external_clock(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    device: Box<dyn SerialDevice>,
+    // Byte being shifted in during an internally clocked transfer
+    incoming: u8,
+    bits_left: u8,
+    bit_timer: u32,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0,
+            device: Box::new(Disconnected),
+            incoming: 0xFF,
+            bits_left: 0,
+            bit_timer: 0,
+        }
+    }
+
+    // Plugs a device into the link port, returning the previous one
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, device)
+    }
+
+    pub fn disconnect(&mut self) -> Box<dyn SerialDevice> {
+        self.connect(Box::new(Disconnected))
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | 0x7E,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF01 => self.sb = val,
+            0xFF02 => {
+                self.sc = val & 0x81;
+                if self.transferring() && self.internal_clock() {
+                    self.incoming = self.device.exchange(self.sb);
+                    self.bits_left = 8;
+                    self.bit_timer = BIT_CYCLES;
+                } else {
+                    self.bits_left = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn transferring(&self) -> bool {
+        self.sc & 0x80 != 0
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.sc & 0x01 != 0
+    }
+
+    // Advances a transfer in progress. Returns true when it completes and
+    // the serial interrupt should be requested.
+    pub fn tick(&mut self, cycles: u32) -> bool {
+        self.device.tick(cycles);
+        if !self.transferring() {
+            return false;
+        }
+        if !self.internal_clock() {
+            return match self.device.external_clock(self.sb) {
+                Some(byte) => {
+                    self.sb = byte;
+                    self.sc &= 0x7F;
+                    true
+                }
+                None => false,
+            };
+        }
+        let mut cycles = cycles;
+        while cycles >= self.bit_timer && self.bits_left > 0 {
+            cycles -= self.bit_timer;
+            self.bit_timer = BIT_CYCLES;
+            // SB shifts out MSB first while the incoming bit enters at bit 0
+            self.bits_left -= 1;
+            self.sb = (self.sb << 1) | ((self.incoming >> self.bits_left) & 1);
+        }
+        if self.bits_left == 0 {
+            self.sc &= 0x7F;
+            return true;
+        }
+        self.bit_timer -= cycles;
+        false
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial::new()
+    }
+}
+
+
+// Serial Tests
+#[cfg(test)]
+struct Reply(u8);
+
+#[cfg(test)]
+impl SerialDevice for Reply {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        self.0
+    }
+
+    fn external_clock(&mut self, byte: u8) -> Option<u8> {
+        Some(self.exchange(byte))
+    }
+}
+
+#[test]
+fn serial_register_reads() {
+    let mut serial = Serial::new();
+    serial.write(0xFF01, 0x42);
+    assert_eq!(serial.read(0xFF01), 0x42);
+    assert_eq!(serial.read(0xFF02), 0x7E);
+    serial.write(0xFF02, 0x01);
+    assert_eq!(serial.read(0xFF02), 0x7F);
+}
+#[test]
+fn serial_internal_transfer_with_nothing_connected() {
+    let mut serial = Serial::new();
+    serial.write(0xFF01, 0x12);
+    serial.write(0xFF02, 0x81);
+    assert!(!serial.tick(8 * BIT_CYCLES - 4));
+    assert_eq!(serial.read(0xFF02), 0xFF);
+    assert!(serial.tick(4));
+    assert_eq!(serial.read(0xFF01), 0xFF);
+    assert_eq!(serial.read(0xFF02), 0x7F);
+    // Nothing more happens after the transfer
+    assert!(!serial.tick(8 * BIT_CYCLES));
+}
+#[test]
+fn serial_shifts_one_bit_at_a_time() {
+    let mut serial = Serial::new();
+    serial.connect(Box::new(Reply(0x00)));
+    serial.write(0xFF01, 0xF0);
+    serial.write(0xFF02, 0x81);
+    serial.tick(3 * BIT_CYCLES);
+    assert_eq!(serial.read(0xFF01), 0x80);
+}
+#[test]
+fn serial_external_clock_waits() {
+    let mut serial = Serial::new();
+    serial.write(0xFF01, 0x12);
+    serial.write(0xFF02, 0x80);
+    assert!(!serial.tick(100 * BIT_CYCLES));
+    assert_eq!(serial.read(0xFF02), 0xFE);
+    serial.connect(Box::new(Reply(0x34)));
+    assert!(serial.tick(4));
+    assert_eq!(serial.read(0xFF01), 0x34);
+    assert_eq!(serial.read(0xFF02), 0x7E);
+}
+#[test]
+fn serial_device_reply_shifted_in() {
+    let mut serial = Serial::new();
+    serial.connect(Box::new(Reply(0x5A)));
+    serial.write(0xFF01, 0xA5);
+    serial.write(0xFF02, 0x81);
+    assert!(serial.tick(8 * BIT_CYCLES));
+    assert_eq!(serial.read(0xFF01), 0x5A);
+    serial.disconnect();
+}
@@ -0,0 +1,603 @@
+use crate::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+// Where the Game Boy picture sits inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+// The attribute map assigns one of the four palettes to every 8x8 cell
+const CELLS_X: usize = SCREEN_WIDTH / 8;
+const CELLS_Y: usize = SCREEN_HEIGHT / 8;
+const ATTR_FILE_SIZE: usize = CELLS_X * CELLS_Y / 4;
+const ATTR_FILES: usize = 45;
+
+const PACKET_BITS: usize = 128;
+
+// Commands
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+const DEFAULT_PALETTE: [u16; 4] = [0x7FFF, 0x56B5, 0x294A, 0x0000];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mask {
+    Off,
+    // Keep showing the picture from when the mask was enabled
+    Freeze,
+    Black,
+    // Fill the screen with colour 0
+    Color0,
+}
+
+// Data copied out of VRAM by the SGB, which reads whatever the Game Boy
+// displays on the frame after the command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transfer {
+    Palettes,
+    BorderTiles(usize),
+    BorderMap,
+    AttributeFiles,
+}
+
+// The Super Game Boy side of the hardware. Games send it commands as
+// 16-byte packets by pulsing P14 and P15, and it draws the Game Boy picture
+// in colour inside a border on the TV.
+pub struct Sgb {
+    // Packet being received, bit by bit
+    receiving: bool,
+    bit_count: usize,
+    packet: [u8; 16],
+    last_select: u8,
+    // Packets of the current command
+    command: Vec<u8>,
+    packets_left: usize,
+
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    attributes: [u8; CELLS_X * CELLS_Y],
+    attribute_files: Vec<u8>,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>,
+    // Palettes 4-7 of sixteen colours each
+    border_palettes: Vec<u16>,
+    mask: Mask,
+    frozen: Option<Vec<u16>>,
+    player_count: usize,
+    transfer: Option<Transfer>,
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            receiving: false,
+            bit_count: 0,
+            packet: [0; 16],
+            last_select: 0x30,
+            command: vec![],
+            packets_left: 0,
+
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; 512 * 4],
+            attributes: [0; CELLS_X * CELLS_Y],
+            attribute_files: vec![0; ATTR_FILES * ATTR_FILE_SIZE],
+            border_tiles: vec![0; 256 * 32],
+            border_map: vec![0; 32 * 32 * 2],
+            border_palettes: vec![0; 4 * 16],
+            mask: Mask::Off,
+            frozen: None,
+            player_count: 1,
+            transfer: None,
+        }
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.player_count
+    }
+
+    pub fn mask(&self) -> Mask {
+        self.mask
+    }
+
+    pub fn palettes(&self) -> &[[u16; 4]; 4] {
+        &self.palettes
+    }
+
+    // Palette used by each 8x8 cell of the Game Boy screen
+    pub fn attributes(&self) -> &[u8] {
+        &self.attributes
+    }
+
+    // Called on every write to P1. A reset pulse (both lines low) starts a
+    // packet, then each pulse of P14 sends a 0 and each pulse of P15 a 1,
+    // least significant bit first, with a 0 stop bit after 128 bits.
+    pub fn write_joypad(&mut self, val: u8) {
+        let select = val & 0x30;
+        let pulse = self.last_select == 0x30;
+        self.last_select = select;
+        match select {
+            0x00 => {
+                self.receiving = true;
+                self.bit_count = 0;
+                self.packet = [0; 16];
+            }
+            0x10 | 0x20 if self.receiving && pulse => {
+                let bit = select == 0x10;
+                if self.bit_count == PACKET_BITS {
+                    self.receiving = false;
+                    if !bit {
+                        self.receive_packet();
+                    }
+                } else {
+                    self.packet[self.bit_count / 8] |= (bit as u8) << (self.bit_count % 8);
+                    self.bit_count += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_packet(&mut self) {
+        if self.packets_left == 0 {
+            self.command.clear();
+            self.packets_left = ((self.packet[0] & 0x07) as usize).max(1);
+        }
+        self.command.extend_from_slice(&self.packet);
+        self.packets_left -= 1;
+        if self.packets_left == 0 {
+            let command = std::mem::take(&mut self.command);
+            self.run_command(&command);
+        }
+    }
+
+    fn run_command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01 => self.set_palette_pair(data, 0, 1),
+            PAL23 => self.set_palette_pair(data, 2, 3),
+            PAL03 => self.set_palette_pair(data, 0, 3),
+            PAL12 => self.set_palette_pair(data, 1, 2),
+            ATTR_BLK => self.attr_blk(data),
+            ATTR_LIN => self.attr_lin(data),
+            ATTR_DIV => self.attr_div(data),
+            ATTR_CHR => self.attr_chr(data),
+            PAL_SET => self.pal_set(data),
+            PAL_TRN => self.transfer = Some(Transfer::Palettes),
+            MLT_REQ => {
+                self.player_count = match data[1] & 0x03 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+            }
+            CHR_TRN => self.transfer = Some(Transfer::BorderTiles((data[1] & 0x01) as usize)),
+            PCT_TRN => self.transfer = Some(Transfer::BorderMap),
+            ATTR_TRN => self.transfer = Some(Transfer::AttributeFiles),
+            ATTR_SET => {
+                self.load_attribute_file((data[1] & 0x3F) as usize);
+                if data[1] & 0x40 != 0 {
+                    self.set_mask(Mask::Off);
+                }
+            }
+            MASK_EN => {
+                self.set_mask(match data[1] & 0x03 {
+                    0 => Mask::Off,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                });
+            }
+            // Sound, SNES program and other commands that don't affect the
+            // picture
+            _ => {}
+        }
+    }
+
+    // Colour 0 is shared by all palettes, so setting it for one sets it
+    // for every palette
+    fn set_palette_pair(&mut self, data: &[u8], a: usize, b: usize) {
+        let color = |i: usize| u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) & 0x7FFF;
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[a][i] = color(i);
+            self.palettes[b][i] = color(i + 3);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets = (data[1] as usize).min((data.len() - 2) / 6);
+        for set in data[2..2 + sets * 6].chunks(6) {
+            let control = set[0];
+            let inside = set[1] & 0x03;
+            let outside = (set[1] >> 4) & 0x03;
+            // A block with only its inside or outside changed also changes
+            // the border line to match
+            let (change_border, border) = match control & 0x07 {
+                0x01 => (true, inside),
+                0x04 => (true, outside),
+                c => (c & 0x02 != 0, (set[1] >> 2) & 0x03),
+            };
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = if on_border {
+                        if !change_border { continue; }
+                        border
+                    } else if within {
+                        if control & 0x01 == 0 { continue; }
+                        inside
+                    } else {
+                        if control & 0x04 == 0 { continue; }
+                        outside
+                    };
+                    self.attributes[y * CELLS_X + x] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = (data[1] as usize).min(data.len() - 2);
+        for &line in &data[2..2 + count] {
+            let n = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                if n < CELLS_Y {
+                    self.attributes[n * CELLS_X..(n + 1) * CELLS_X].iter_mut().for_each(|a| *a = palette);
+                }
+            } else if n < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attributes[y * CELLS_X + n] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let split = data[2] as usize;
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * CELLS_X + x] = match position.cmp(&split) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    // Palettes for a run of cells, two bits each from the most significant
+    // end, going across rows or down columns
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let vertical = data[5] & 0x01 != 0;
+        for i in 0..count.min((data.len() - 6) * 4) {
+            if x >= CELLS_X || y >= CELLS_Y {
+                break;
+            }
+            let palette = (data[6 + i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+            self.attributes[y * CELLS_X + x] = palette;
+            if vertical {
+                y += 1;
+                if y == CELLS_Y { y = 0; x += 1; }
+            } else {
+                x += 1;
+                if x == CELLS_X { x = 0; y += 1; }
+            }
+        }
+    }
+
+    // Picks the four palettes from the ones sent with PAL_TRN
+    fn pal_set(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let n = (u16::from_le_bytes([data[1 + i * 2], data[2 + i * 2]]) & 0x1FF) as usize;
+            self.palettes[i].copy_from_slice(&self.system_palettes[n * 4..n * 4 + 4]);
+        }
+        let shared = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = shared;
+        }
+        if data[9] & 0x80 != 0 {
+            self.load_attribute_file((data[9] & 0x3F) as usize);
+        }
+        if data[9] & 0x40 != 0 {
+            self.set_mask(Mask::Off);
+        }
+    }
+
+    fn load_attribute_file(&mut self, file: usize) {
+        if file >= ATTR_FILES {
+            return;
+        }
+        let bytes = &self.attribute_files[file * ATTR_FILE_SIZE..(file + 1) * ATTR_FILE_SIZE];
+        for (i, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (bytes[i / 4] >> (6 - (i % 4) * 2)) & 0x03;
+        }
+    }
+
+    fn set_mask(&mut self, mask: Mask) {
+        self.mask = mask;
+        if mask != Mask::Freeze {
+            self.frozen = None;
+        }
+    }
+
+    // Called at the start of every VBlank with the frame just drawn
+    pub fn vblank(&mut self, ppu: &Ppu) {
+        if self.mask == Mask::Freeze && self.frozen.is_none() {
+            self.frozen = Some(ppu.frame().to_vec());
+        }
+        let transfer = match self.transfer.take() {
+            Some(t) => t,
+            None => return,
+        };
+        let data = screen_tiles(ppu);
+        match transfer {
+            Transfer::Palettes => {
+                for (i, color) in self.system_palettes.iter_mut().enumerate() {
+                    *color = u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]) & 0x7FFF;
+                }
+            }
+            Transfer::BorderTiles(half) => {
+                self.border_tiles[half * 0x1000..(half + 1) * 0x1000].copy_from_slice(&data);
+            }
+            Transfer::BorderMap => {
+                self.border_map.copy_from_slice(&data[..0x800]);
+                for (i, color) in self.border_palettes.iter_mut().enumerate() {
+                    *color = u16::from_le_bytes([data[0x800 + i * 2], data[0x801 + i * 2]]) & 0x7FFF;
+                }
+            }
+            Transfer::AttributeFiles => {
+                self.attribute_files.copy_from_slice(&data[..ATTR_FILES * ATTR_FILE_SIZE]);
+            }
+        }
+    }
+
+    // Draws the 256x224 TV picture in RGB555: the Game Boy screen coloured
+    // by the attribute map, inside the border
+    pub fn render(&self, frame: &[u16]) -> Vec<u16> {
+        let backdrop = self.palettes[0][0];
+        let mut out = vec![backdrop; SGB_WIDTH * SGB_HEIGHT];
+        let frame = self.frozen.as_deref().unwrap_or(frame);
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let color = match self.mask {
+                    Mask::Black => 0,
+                    Mask::Color0 => backdrop,
+                    _ => {
+                        let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                        self.palettes[palette][(frame[y * SCREEN_WIDTH + x] & 0x03) as usize]
+                    }
+                };
+                out[(y + SCREEN_Y) * SGB_WIDTH + x + SCREEN_X] = color;
+            }
+        }
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                let in_screen = (SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y);
+                if in_screen {
+                    continue;
+                }
+                if let Some(color) = self.border_pixel(x, y) {
+                    out[y * SGB_WIDTH + x] = color;
+                }
+            }
+        }
+        out
+    }
+
+    // Border tiles are SNES 4 bits per pixel tiles; colour 0 is
+    // transparent and shows the backdrop
+    fn border_pixel(&self, x: usize, y: usize) -> Option<u16> {
+        let i = ((y / 8) * 32 + x / 8) * 2;
+        let entry = u16::from_le_bytes([self.border_map[i], self.border_map[i + 1]]);
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0x07) as usize;
+        let row = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+        let column = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+        let bytes = &self.border_tiles[tile * 32..tile * 32 + 32];
+        let bit = 7 - column;
+        let color = ((bytes[row * 2] >> bit) & 1)
+            | (((bytes[row * 2 + 1] >> bit) & 1) << 1)
+            | (((bytes[16 + row * 2] >> bit) & 1) << 2)
+            | (((bytes[16 + row * 2 + 1] >> bit) & 1) << 3);
+        if color == 0 || palette < 4 {
+            return None;
+        }
+        Some(self.border_palettes[(palette - 4) * 16 + color as usize])
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Sgb {
+        Sgb::new()
+    }
+}
+
+// The 4 KiB of tile data a VRAM transfer sends: the first 256 tiles shown
+// by the background map, read across 20 tiles per row
+fn screen_tiles(ppu: &Ppu) -> Vec<u8> {
+    let map = if ppu.read(0xFF40) & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+    let vram = ppu.vram(0);
+    let mut data = Vec::with_capacity(0x1000);
+    for n in 0..256 {
+        let tile = vram[map + (n / 20) * 32 + n % 20];
+        let addr = ppu.bg_tile_addr(tile);
+        data.extend_from_slice(&vram[addr..addr + 16]);
+    }
+    data
+}
+
+
+// SGB Tests
+#[cfg(test)]
+fn send_packet(sgb: &mut Sgb, packet: &[u8]) {
+    let mut bytes = [0; 16];
+    bytes[..packet.len()].copy_from_slice(packet);
+    sgb.write_joypad(0x00);
+    sgb.write_joypad(0x30);
+    for i in 0..PACKET_BITS {
+        let bit = (bytes[i / 8] >> (i % 8)) & 1;
+        sgb.write_joypad(if bit == 1 { 0x10 } else { 0x20 });
+        sgb.write_joypad(0x30);
+    }
+    sgb.write_joypad(0x20);
+    sgb.write_joypad(0x30);
+}
+
+#[test]
+fn sgb_pal01_sets_colours() {
+    let mut sgb = Sgb::new();
+    send_packet(&mut sgb, &[
+        PAL01 << 3 | 1,
+        0x11, 0x11, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00,
+        0x04, 0x00, 0x05, 0x00, 0x06, 0x00,
+    ]);
+    assert_eq!(sgb.palettes()[0], [0x1111, 1, 2, 3]);
+    assert_eq!(sgb.palettes()[1], [0x1111, 4, 5, 6]);
+    assert_eq!(sgb.palettes()[2], [0x1111, 0x56B5, 0x294A, 0x0000]);
+}
+#[test]
+fn sgb_packet_needs_stop_bit() {
+    let mut sgb = Sgb::new();
+    sgb.write_joypad(0x00);
+    sgb.write_joypad(0x30);
+    for _ in 0..PACKET_BITS {
+        sgb.write_joypad(0x10);
+        sgb.write_joypad(0x30);
+    }
+    // A 1 stop bit throws the packet away
+    sgb.write_joypad(0x10);
+    sgb.write_joypad(0x30);
+    assert_eq!(sgb.palettes()[0], DEFAULT_PALETTE);
+}
+#[test]
+fn sgb_mlt_req() {
+    let mut sgb = Sgb::new();
+    send_packet(&mut sgb, &[MLT_REQ << 3 | 1, 0x03]);
+    assert_eq!(sgb.player_count(), 4);
+    send_packet(&mut sgb, &[MLT_REQ << 3 | 1, 0x01]);
+    assert_eq!(sgb.player_count(), 2);
+    send_packet(&mut sgb, &[MLT_REQ << 3 | 1, 0x00]);
+    assert_eq!(sgb.player_count(), 1);
+}
+#[test]
+fn sgb_attr_blk() {
+    let mut sgb = Sgb::new();
+    // Inside palette 1, border palette 2, outside palette 3
+    send_packet(&mut sgb, &[ATTR_BLK << 3 | 1, 1, 0x07, 0x39, 2, 2, 5, 4]);
+    let at = |x: usize, y: usize| sgb.attributes()[y * CELLS_X + x];
+    assert_eq!(at(3, 3), 1);
+    assert_eq!(at(2, 2), 2);
+    assert_eq!(at(5, 3), 2);
+    assert_eq!(at(0, 0), 3);
+    assert_eq!(at(6, 3), 3);
+}
+#[test]
+fn sgb_attr_blk_inside_only_changes_border() {
+    let mut sgb = Sgb::new();
+    send_packet(&mut sgb, &[ATTR_BLK << 3 | 1, 1, 0x01, 0x02, 0, 0, 1, 1]);
+    assert_eq!(sgb.attributes()[0], 2);
+    assert_eq!(sgb.attributes()[2], 0);
+}
+#[test]
+fn sgb_attr_lin_and_div() {
+    let mut sgb = Sgb::new();
+    send_packet(&mut sgb, &[ATTR_DIV << 3 | 1, 0x40 | 0x20 | 0x04 | 0x03, 9]);
+    assert_eq!(sgb.attributes()[8 * CELLS_X], 1);
+    assert_eq!(sgb.attributes()[9 * CELLS_X], 2);
+    assert_eq!(sgb.attributes()[10 * CELLS_X], 3);
+    // Column 4 palette 3, row 0 palette 2
+    send_packet(&mut sgb, &[ATTR_LIN << 3 | 1, 2, 0x64, 0xC0]);
+    assert_eq!(sgb.attributes()[CELLS_X + 4], 3);
+    assert_eq!(sgb.attributes()[4], 2);
+    assert_eq!(sgb.attributes()[1], 2);
+}
+#[test]
+fn sgb_attr_chr() {
+    let mut sgb = Sgb::new();
+    send_packet(&mut sgb, &[ATTR_CHR << 3 | 1, 18, 0, 5, 0, 0, 0b0110_1100, 0b1100_0000]);
+    assert_eq!(&sgb.attributes()[18..20], &[1, 2]);
+    assert_eq!(&sgb.attributes()[CELLS_X..CELLS_X + 3], &[3, 0, 3]);
+}
+#[test]
+fn sgb_pal_trn_and_pal_set() {
+    let mut sgb = Sgb::new();
+    let mut ppu = Ppu::new();
+    // Tile data for the transfer: map entry n shows tile n
+    for n in 0..256 {
+        ppu.write_vram(0x9800 + ((n / 20) * 32 + n % 20) as u16, n as u8);
+    }
+    // Palette 5 colours
+    for (i, &color) in [0x0001u16, 0x0002, 0x0003, 0x0004].iter().enumerate() {
+        let addr = 0x8000 + (5 * 8 + i * 2) as u16;
+        ppu.write_vram(addr, color as u8);
+        ppu.write_vram(addr + 1, (color >> 8) as u8);
+    }
+    ppu.write(0xFF40, 0x91);
+    send_packet(&mut sgb, &[PAL_TRN << 3 | 1]);
+    sgb.vblank(&ppu);
+    send_packet(&mut sgb, &[PAL_SET << 3 | 1, 5, 0, 5, 0, 0, 0, 0, 0, 0x40]);
+    assert_eq!(sgb.palettes()[1], [1, 2, 3, 4]);
+    assert_eq!(sgb.palettes()[2], [1, 0, 0, 0]);
+}
+#[test]
+fn sgb_mask_and_render() {
+    let mut sgb = Sgb::new();
+    send_packet(&mut sgb, &[ATTR_DIV << 3 | 1, 0x01, 10]);
+    let mut frame = vec![3; SCREEN_WIDTH * SCREEN_HEIGHT];
+    frame[0] = 0;
+    send_packet(&mut sgb, &[PAL01 << 3 | 1, 0x1F, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xE0, 0x03]);
+    let out = sgb.render(&frame);
+    assert_eq!(out.len(), SGB_WIDTH * SGB_HEIGHT);
+    assert_eq!(out[0], 0x001F);
+    assert_eq!(out[SCREEN_Y * SGB_WIDTH + SCREEN_X], 0x001F);
+    assert_eq!(out[SCREEN_Y * SGB_WIDTH + SCREEN_X + 1], 0x0000);
+    assert_eq!(out[SCREEN_Y * SGB_WIDTH + SCREEN_X + 100], 0x03E0);
+    send_packet(&mut sgb, &[MASK_EN << 3 | 1, 2]);
+    assert_eq!(sgb.mask(), Mask::Black);
+    assert_eq!(sgb.render(&frame)[SCREEN_Y * SGB_WIDTH + SCREEN_X], 0);
+    send_packet(&mut sgb, &[MASK_EN << 3 | 1, 1]);
+    sgb.vblank(&Ppu::new());
+    // Frozen on the blank frame
+    assert_eq!(sgb.render(&frame)[SCREEN_Y * SGB_WIDTH + SCREEN_X + 100], 0x001F);
+}
+#[test]
+fn sgb_border_tiles_and_map() {
+    let mut sgb = Sgb::new();
+    // Tile 1 has colour 15 in its top-left pixel
+    for plane in [0, 1, 16, 17].iter() {
+        sgb.border_tiles[32 + plane] = 0x80;
+    }
+    sgb.border_map[0..2].copy_from_slice(&(1u16 | (4 << 10)).to_le_bytes());
+    sgb.border_map[2..4].copy_from_slice(&(1u16 | (4 << 10) | 0x4000).to_le_bytes());
+    sgb.border_palettes[15] = 0x1234;
+    let out = sgb.render(&vec![0; SCREEN_WIDTH * SCREEN_HEIGHT]);
+    assert_eq!(out[0], 0x1234);
+    assert_eq!(out[1], DEFAULT_PALETTE[0]);
+    assert_eq!(out[15], 0x1234);
+}
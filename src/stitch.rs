@@ -0,0 +1,269 @@
+use crate::color::{ColorCorrection, OutputPalette};
+use crate::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::vram_viewer::{Image, VramViewer};
+
+// Largest world the canvas grows to in either direction
+const MAX_SIZE: usize = 8192;
+const UNSEEN_COLOR: [u8; 3] = [0x00, 0x00, 0x00];
+
+// Builds a picture of a whole level by following the background as it
+// scrolls. Each frame the part of the background on screen is copied from
+// the tile map, leaving out whatever the window covers, and placed in the
+// world by how far SCX and SCY moved since the last frame. Later frames
+// paint over earlier ones, so the picture shows each place as last seen.
+//
+// Moves of more than 127 pixels in a frame can't be told apart from moves
+// the other way, and cuts to a different scene are stitched as if they
+// were scrolling, so call reset() when the scene changes.
+pub struct MapStitcher {
+    // World rectangle seen so far
+    left: i64,
+    top: i64,
+    width: usize,
+    height: usize,
+    // The canvas holds the seen rectangle with room to grow, so scrolling
+    // a pixel at a time doesn't copy everything each frame
+    canvas_left: i64,
+    canvas_top: i64,
+    canvas_width: usize,
+    canvas_height: usize,
+    rgb: Vec<u8>,
+    seen: Vec<bool>,
+    // World position of the screen and the scroll registers it came from
+    position: (i64, i64),
+    scroll: Option<(u8, u8)>,
+    frames: u64,
+}
+
+impl MapStitcher {
+    pub fn new() -> MapStitcher {
+        MapStitcher {
+            left: 0,
+            top: 0,
+            width: 0,
+            height: 0,
+            canvas_left: 0,
+            canvas_top: 0,
+            canvas_width: 0,
+            canvas_height: 0,
+            rgb: vec![],
+            seen: vec![],
+            position: (0, 0),
+            scroll: None,
+            frames: 0,
+        }
+    }
+
+    // Starts a new picture
+    pub fn reset(&mut self) {
+        *self = MapStitcher::new();
+    }
+
+    // Frames stitched in so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Stitches in the background as it is now, normally at VBlank. Frames
+    // with the LCD or background off are skipped.
+    pub fn capture(&mut self, ppu: &Ppu, correction: ColorCorrection, palette: OutputPalette) {
+        let lcdc = ppu.read(0xFF40);
+        // LCDC bit 0 only turns the background off in DMG mode
+        if lcdc & 0x80 == 0 || (!ppu.is_cgb() && lcdc & 0x01 == 0) {
+            return;
+        }
+        let scroll = (ppu.read(0xFF43), ppu.read(0xFF42));
+        if let Some((scx, scy)) = self.scroll {
+            self.position.0 += scroll.0.wrapping_sub(scx) as i8 as i64;
+            self.position.1 += scroll.1.wrapping_sub(scy) as i8 as i64;
+        }
+        self.scroll = Some(scroll);
+        let (x, y) = self.position;
+        if !self.grow_to(x, y, x + SCREEN_WIDTH as i64, y + SCREEN_HEIGHT as i64) {
+            return;
+        }
+
+        let map = VramViewer::new(ppu, correction, palette).tile_map((lcdc >> 3) as usize & 1);
+        let (wx, wy) = (ppu.read(0xFF4B) as usize, ppu.read(0xFF4A) as usize);
+        let window = lcdc & 0x20 != 0;
+        for screen_y in 0..SCREEN_HEIGHT {
+            for screen_x in 0..SCREEN_WIDTH {
+                if window && screen_x + 7 >= wx && screen_y >= wy {
+                    continue;
+                }
+                let color = map.pixel((screen_x + scroll.0 as usize) % map.width, (screen_y + scroll.1 as usize) % map.height);
+                let i = self.index(x + screen_x as i64, y + screen_y as i64);
+                self.rgb[i * 3..i * 3 + 3].copy_from_slice(&color);
+                self.seen[i] = true;
+            }
+        }
+        self.frames += 1;
+    }
+
+    // The world so far, with places never seen left black
+    pub fn image(&self) -> Image {
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+        for y in 0..self.height as i64 {
+            let i = self.index(self.left, self.top + y);
+            rgb.extend_from_slice(&self.rgb[i * 3..(i + self.width) * 3]);
+        }
+        Image { width: self.width, height: self.height, rgb }
+    }
+
+    // Whether a pixel of image() was ever on screen
+    pub fn seen(&self, x: usize, y: usize) -> bool {
+        self.seen[self.index(self.left + x as i64, self.top + y as i64)]
+    }
+
+    // Canvas index of a world position
+    fn index(&self, x: i64, y: i64) -> usize {
+        (y - self.canvas_top) as usize * self.canvas_width + (x - self.canvas_left) as usize
+    }
+
+    // Grows the seen rectangle to cover the given world rectangle, or
+    // returns false if that would make it too large
+    fn grow_to(&mut self, left: i64, top: i64, right: i64, bottom: i64) -> bool {
+        let new_left = if self.width == 0 { left } else { left.min(self.left) };
+        let new_top = if self.height == 0 { top } else { top.min(self.top) };
+        let new_right = right.max(self.left + self.width as i64);
+        let new_bottom = bottom.max(self.top + self.height as i64);
+        let (width, height) = ((new_right - new_left) as usize, (new_bottom - new_top) as usize);
+        if width > MAX_SIZE || height > MAX_SIZE {
+            return false;
+        }
+        let fits = new_left >= self.canvas_left && new_top >= self.canvas_top
+            && new_right <= self.canvas_left + self.canvas_width as i64
+            && new_bottom <= self.canvas_top + self.canvas_height as i64;
+        if !fits {
+            self.grow_canvas(new_left, new_top, width, height);
+        }
+        self.left = new_left;
+        self.top = new_top;
+        self.width = width;
+        self.height = height;
+        true
+    }
+
+    // Reallocates the canvas at twice the size needed, with the room on the
+    // side the picture is growing towards
+    fn grow_canvas(&mut self, left: i64, top: i64, width: usize, height: usize) {
+        let canvas_width = (width * 2).min(MAX_SIZE).max(self.canvas_width);
+        let canvas_height = (height * 2).min(MAX_SIZE).max(self.canvas_height);
+        let canvas_left = if self.width > 0 && left < self.left { left + width as i64 - canvas_width as i64 } else { left };
+        let canvas_top = if self.height > 0 && top < self.top { top + height as i64 - canvas_height as i64 } else { top };
+
+        let mut rgb = UNSEEN_COLOR.repeat(canvas_width * canvas_height);
+        let mut seen = vec![false; canvas_width * canvas_height];
+        for y in self.top..self.top + self.height as i64 {
+            let from = self.index(self.left, y);
+            let to = (y - canvas_top) as usize * canvas_width + (self.left - canvas_left) as usize;
+            rgb[to * 3..(to + self.width) * 3].copy_from_slice(&self.rgb[from * 3..(from + self.width) * 3]);
+            seen[to..to + self.width].copy_from_slice(&self.seen[from..from + self.width]);
+        }
+        self.canvas_left = canvas_left;
+        self.canvas_top = canvas_top;
+        self.canvas_width = canvas_width;
+        self.canvas_height = canvas_height;
+        self.rgb = rgb;
+        self.seen = seen;
+    }
+}
+
+impl Default for MapStitcher {
+    fn default() -> MapStitcher {
+        MapStitcher::new()
+    }
+}
+
+
+// Map Stitching Tests
+#[cfg(test)]
+fn ppu_with_column() -> Ppu {
+    let mut ppu = Ppu::new();
+    // Tile 1 is solid colour 3, drawn down the first column of the map
+    for i in 0..16 {
+        ppu.write_vram(0x8010 + i, 0xFF);
+    }
+    for row in 0..32 {
+        ppu.write_vram(0x9800 + row * 32, 1);
+    }
+    ppu.write(0xFF40, 0x91);
+    ppu.write(0xFF47, 0xE4);
+    ppu
+}
+
+#[test]
+fn stitch_follows_scrolling() {
+    let mut ppu = ppu_with_column();
+    let mut stitcher = MapStitcher::new();
+    stitcher.capture(&ppu, ColorCorrection::Raw, OutputPalette::POCKET);
+    assert_eq!((stitcher.image().width, stitcher.image().height), (160, 144));
+    // Scrolling right by 200 pixels wraps past the end of the map
+    for _ in 0..4 {
+        let scx = ppu.read(0xFF43);
+        ppu.write(0xFF43, scx.wrapping_add(50));
+        stitcher.capture(&ppu, ColorCorrection::Raw, OutputPalette::POCKET);
+    }
+    let image = stitcher.image();
+    assert_eq!((image.width, image.height), (360, 144));
+    assert_eq!(stitcher.frames(), 5);
+    // The column shows up again after 256 pixels
+    assert_eq!(image.pixel(0, 0), [0x00, 0x00, 0x00]);
+    assert_eq!(image.pixel(8, 0), [0xFF, 0xFF, 0xFF]);
+    assert_eq!(image.pixel(256, 100), [0x00, 0x00, 0x00]);
+    assert_eq!(image.pixel(264, 100), [0xFF, 0xFF, 0xFF]);
+}
+#[test]
+fn stitch_grows_up_and_left() {
+    let mut ppu = ppu_with_column();
+    let mut stitcher = MapStitcher::new();
+    stitcher.capture(&ppu, ColorCorrection::Raw, OutputPalette::POCKET);
+    ppu.write(0xFF43, 0xF8);
+    ppu.write(0xFF42, 0xF0);
+    stitcher.capture(&ppu, ColorCorrection::Raw, OutputPalette::POCKET);
+    let image = stitcher.image();
+    assert_eq!((image.width, image.height), (168, 160));
+    assert_eq!(image.pixel(8, 16), [0x00, 0x00, 0x00]);
+    assert!(stitcher.seen(0, 0));
+    // Neither frame covered the bottom left corner
+    assert!(!stitcher.seen(0, 159));
+    assert!(stitcher.seen(167, 159));
+}
+#[test]
+fn stitch_leaves_out_window_and_blank_frames() {
+    let mut ppu = ppu_with_column();
+    ppu.write(0xFF40, 0xB1);
+    ppu.write(0xFF4B, 7);
+    ppu.write(0xFF4A, 128);
+    let mut stitcher = MapStitcher::new();
+    stitcher.capture(&ppu, ColorCorrection::Raw, OutputPalette::POCKET);
+    assert!(stitcher.seen(0, 127));
+    assert!(!stitcher.seen(0, 128));
+
+    ppu.write(0xFF40, 0x00);
+    stitcher.capture(&ppu, ColorCorrection::Raw, OutputPalette::POCKET);
+    assert_eq!(stitcher.frames(), 1);
+    stitcher.reset();
+    assert_eq!(stitcher.image().width, 0);
+}
+#[test]
+fn stitch_grows_canvas_with_room_to_spare() {
+    let mut ppu = ppu_with_column();
+    let mut stitcher = MapStitcher::new();
+    stitcher.capture(&ppu, ColorCorrection::Raw, OutputPalette::POCKET);
+    let mut reallocations = 0;
+    for scy in 1..=200u8 {
+        let canvas = stitcher.rgb.as_ptr();
+        ppu.write(0xFF42, scy.wrapping_neg());
+        stitcher.capture(&ppu, ColorCorrection::Raw, OutputPalette::POCKET);
+        if stitcher.rgb.as_ptr() != canvas {
+            reallocations += 1;
+        }
+    }
+    assert!(reallocations <= 2);
+    let image = stitcher.image();
+    assert_eq!((image.width, image.height), (160, 344));
+    // The first frame ends up at the bottom
+    assert_eq!(image.pixel(0, 343), [0x00, 0x00, 0x00]);
+    assert!(stitcher.seen(159, 0));
+}
@@ -0,0 +1,485 @@
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::joypad::{Button, ButtonState};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    Enter,
+    Backspace,
+    Tab,
+    Escape,
+    Quit,
+}
+
+impl Key {
+    pub fn from_name(name: &str) -> Option<Key> {
+        match name.to_ascii_lowercase().as_str() {
+            "up" => Some(Key::Up),
+            "down" => Some(Key::Down),
+            "left" => Some(Key::Left),
+            "right" => Some(Key::Right),
+            "enter" | "return" => Some(Key::Enter),
+            "backspace" => Some(Key::Backspace),
+            "tab" => Some(Key::Tab),
+            "esc" | "escape" => Some(Key::Escape),
+            "space" => Some(Key::Char(' ')),
+            _ => {
+                let mut chars = name.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(Key::Char(c.to_ascii_lowercase())),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+// Puts stdin into raw, non-blocking mode for as long as it is alive and
+// restores the previous settings when dropped.
+pub struct RawTerminal {
+    original: libc::termios,
+    pending: Vec<u8>,
+}
+
+impl RawTerminal {
+    pub fn new() -> io::Result<RawTerminal> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return Err(io::Error::other("stdin is not a terminal"));
+            }
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+            raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+            // VMIN = 0 / VTIME = 0 makes read() return immediately with
+            // whatever is buffered, so polling never stalls emulation.
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(RawTerminal { original, pending: vec![] })
+        }
+    }
+
+    pub fn read_keys(&mut self) -> Vec<Key> {
+        let mut buf = [0u8; 64];
+        loop {
+            match io::stdin().read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+                Err(_) => break,
+            }
+        }
+        let (keys, consumed) = parse_keys(&self.pending);
+        self.pending.drain(..consumed);
+        keys
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Turns SIGINT and SIGTERM into a request to stop, so the caller can
+// finish its files instead of being killed part way through them. The
+// handlers are installed without SA_RESTART, so a blocking read that the
+// signal interrupts fails with EINTR rather than waiting on.
+pub fn catch_stop_signals() {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = 0;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+        libc::sigaction(libc::SIGTERM, &action, std::ptr::null_mut());
+    }
+}
+
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+// Decodes raw terminal bytes into keys. Returns the keys and how many bytes
+// were consumed; an incomplete escape sequence at the end is left unconsumed.
+pub fn parse_keys(bytes: &[u8]) -> (Vec<Key>, usize) {
+    let mut keys = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            0x1B => {
+                if i + 1 >= bytes.len() {
+                    keys.push(Key::Escape);
+                    i += 1;
+                } else if bytes[i + 1] == b'[' || bytes[i + 1] == b'O' {
+                    // CSI sequences can carry parameter and intermediate
+                    // bytes (modifiers, key numbers) before the final byte
+                    let mut end = i + 2;
+                    if bytes[i + 1] == b'[' {
+                        while end < bytes.len() && (0x20..=0x3F).contains(&bytes[end]) {
+                            end += 1;
+                        }
+                    }
+                    if end >= bytes.len() {
+                        break;
+                    }
+                    // Arrows keep working with modifiers held; anything
+                    // else is dropped rather than read as characters
+                    match bytes[end] {
+                        b'A' => keys.push(Key::Up),
+                        b'B' => keys.push(Key::Down),
+                        b'C' => keys.push(Key::Right),
+                        b'D' => keys.push(Key::Left),
+                        _ => {}
+                    }
+                    i = end + 1;
+                } else {
+                    keys.push(Key::Escape);
+                    i += 1;
+                }
+            }
+            0x03 => { keys.push(Key::Quit); i += 1; }
+            b'\r' | b'\n' => { keys.push(Key::Enter); i += 1; }
+            0x7F | 0x08 => { keys.push(Key::Backspace); i += 1; }
+            b'\t' => { keys.push(Key::Tab); i += 1; }
+            c => { keys.push(Key::Char((c as char).to_ascii_lowercase())); i += 1; }
+        }
+    }
+    (keys, i)
+}
+
+pub struct KeyBindings {
+    bindings: Vec<(Key, Button)>,
+}
+
+impl KeyBindings {
+    pub fn new() -> KeyBindings {
+        KeyBindings {
+            bindings: vec![
+                (Key::Right, Button::Right),
+                (Key::Left, Button::Left),
+                (Key::Up, Button::Up),
+                (Key::Down, Button::Down),
+                (Key::Char('x'), Button::A),
+                (Key::Char('z'), Button::B),
+                (Key::Backspace, Button::Select),
+                (Key::Enter, Button::Start),
+            ],
+        }
+    }
+
+    // Parses overrides of the form "a=k,b=j,start=space". Buttons that are
+    // not mentioned keep their default key.
+    pub fn parse(spec: &str) -> Result<KeyBindings, String> {
+        let mut bindings = KeyBindings::new();
+        for entry in spec.split(',').filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            let button_name = parts.next().unwrap_or("").trim();
+            let key_name = parts.next().unwrap_or("").trim();
+            let button = Button::from_name(button_name)
+                .ok_or(format!("unknown button '{}'", button_name))?;
+            let key = Key::from_name(key_name)
+                .ok_or(format!("unknown key '{}'", key_name))?;
+            bindings.bind(key, button);
+        }
+        Ok(bindings)
+    }
+
+    // A key presses one button, so binding it takes it away from any
+    // button it pressed before
+    pub fn bind(&mut self, key: Key, button: Button) {
+        self.bindings.retain(|&(k, b)| b != button && k != key);
+        self.bindings.push((key, button));
+    }
+
+    pub fn button_for(&self, key: Key) -> Option<Button> {
+        self.bindings.iter().find(|&&(k, _)| k == key).map(|&(_, b)| b)
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        KeyBindings::new()
+    }
+}
+
+// Terminals only report key presses (plus autorepeat), never releases, so a
+// press holds its button for press_time and every autorepeat extends the
+// hold to repeat_time from then. press_time needs to outlast the delay
+// before the first autorepeat, or a held key lets go in between.
+pub struct KeyHold {
+    until: [Option<Instant>; 8],
+    pub press_time: Duration,
+    pub repeat_time: Duration,
+}
+
+impl KeyHold {
+    pub fn new(press_time: Duration, repeat_time: Duration) -> KeyHold {
+        KeyHold { until: [None; 8], press_time, repeat_time }
+    }
+
+    pub fn press(&mut self, button: Button, now: Instant) {
+        let until = &mut self.until[button.bit() as usize];
+        *until = Some(match *until {
+            Some(held) if held > now => held.max(now + self.repeat_time),
+            _ => now + self.press_time,
+        });
+    }
+
+    pub fn state(&self, now: Instant) -> ButtonState {
+        let mut state = ButtonState::new();
+        for &button in Button::ALL.iter() {
+            state.set(button, self.until[button.bit() as usize].is_some_and(|until| until > now));
+        }
+        state
+    }
+}
+
+pub struct TerminalInput {
+    terminal: RawTerminal,
+    bindings: KeyBindings,
+    hold: KeyHold,
+    quit: bool,
+}
+
+impl TerminalInput {
+    pub fn new(bindings: KeyBindings) -> io::Result<TerminalInput> {
+        Ok(TerminalInput {
+            terminal: RawTerminal::new()?,
+            bindings,
+            // Terminals usually wait 250-600 ms before autorepeating
+            hold: KeyHold::new(Duration::from_millis(650), Duration::from_millis(150)),
+            quit: false,
+        })
+    }
+
+    // Called once per emulated frame; returns the buttons held this frame.
+    pub fn poll(&mut self) -> ButtonState {
+        let now = Instant::now();
+        for key in self.terminal.read_keys() {
+            if key == Key::Quit {
+                self.quit = true;
+            } else if let Some(button) = self.bindings.button_for(key) {
+                self.hold.press(button, now);
+            }
+        }
+        self.hold.state(now)
+    }
+
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+}
+
+
+// Draws frames on the terminal in 24-bit colour, using half block
+// characters so each character cell shows two pixels, one above the other.
+// A 160x144 frame needs a terminal of 160x72. Only cells that changed since
+// the last frame are redrawn. The terminal's alternate screen is used while
+// this is alive.
+pub struct TerminalScreen<W: Write> {
+    out: W,
+    width: usize,
+    // Top and bottom colour of each cell as last drawn
+    cells: Vec<Option<([u8; 3], [u8; 3])>>,
+}
+
+impl<W: Write> TerminalScreen<W> {
+    pub fn new(mut out: W) -> io::Result<TerminalScreen<W>> {
+        // Alternate screen, hide the cursor, clear
+        out.write_all(b"\x1b[?1049h\x1b[?25l\x1b[2J")?;
+        out.flush()?;
+        Ok(TerminalScreen { out, width: 0, cells: vec![] })
+    }
+
+    pub fn draw(&mut self, rgba: &[u8], width: usize, height: usize) -> io::Result<()> {
+        let rows = height.div_ceil(2);
+        if self.width != width || self.cells.len() != width * rows {
+            self.width = width;
+            self.cells = vec![None; width * rows];
+        }
+        let pixel = |x: usize, y: usize| -> [u8; 3] {
+            if y >= height {
+                return [0; 3];
+            }
+            let i = (y * width + x) * 4;
+            [rgba[i], rgba[i + 1], rgba[i + 2]]
+        };
+        let mut bytes = vec![];
+        // Where the cursor is after the last cell written
+        let mut cursor = None;
+        for row in 0..rows {
+            for x in 0..width {
+                let cell = (pixel(x, row * 2), pixel(x, row * 2 + 1));
+                if self.cells[row * width + x] == Some(cell) {
+                    continue;
+                }
+                self.cells[row * width + x] = Some(cell);
+                if cursor != Some((x, row)) {
+                    bytes.extend_from_slice(format!("\x1b[{};{}H", row + 1, x + 1).as_bytes());
+                }
+                let ([tr, tg, tb], [br, bg, bb]) = cell;
+                bytes.extend_from_slice(format!("\x1b[38;2;{};{};{};48;2;{};{};{}m\u{2580}", tr, tg, tb, br, bg, bb).as_bytes());
+                cursor = Some((x + 1, row));
+            }
+        }
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        bytes.extend_from_slice(b"\x1b[0m");
+        self.out.write_all(&bytes)?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Drop for TerminalScreen<W> {
+    fn drop(&mut self) {
+        let _ = self.out.write_all(b"\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = self.out.flush();
+    }
+}
+
+// Key Parsing Tests
+#[test]
+fn parse_keys_arrows_and_chars() {
+    let (keys, used) = parse_keys(b"\x1b[Ax\x1b[D\r");
+    assert_eq!(keys, vec![Key::Up, Key::Char('x'), Key::Left, Key::Enter]);
+    assert_eq!(used, 8);
+}
+#[test]
+fn parse_keys_partial_escape() {
+    let (keys, used) = parse_keys(b"z\x1b[");
+    assert_eq!(keys, vec![Key::Char('z')]);
+    assert_eq!(used, 1);
+}
+#[test]
+fn parse_keys_drops_longer_sequences() {
+    let (keys, used) = parse_keys(b"\x1b[1;5A\x1b[3~x\x1bOB");
+    assert_eq!(keys, vec![Key::Up, Key::Char('x'), Key::Down]);
+    assert_eq!(used, 14);
+    let (keys, used) = parse_keys(b"x\x1b[1;5");
+    assert_eq!(keys, vec![Key::Char('x')]);
+    assert_eq!(used, 1);
+}
+#[test]
+fn parse_keys_ctrl_c_quits() {
+    let (keys, _) = parse_keys(&[0x03]);
+    assert_eq!(keys, vec![Key::Quit]);
+}
+
+// Signal Tests
+#[test]
+fn stop_signals_are_caught() {
+    catch_stop_signals();
+    // Raising the signal could interrupt other tests' reads, so check how
+    // the handler was installed and call it directly
+    for &signal in [libc::SIGINT, libc::SIGTERM].iter() {
+        let action = unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            libc::sigaction(signal, std::ptr::null(), &mut action);
+            action
+        };
+        assert_eq!(action.sa_sigaction, request_stop as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        assert_eq!(action.sa_flags & libc::SA_RESTART, 0);
+    }
+    request_stop(libc::SIGTERM);
+    assert!(stop_requested());
+}
+
+// Binding Tests
+#[test]
+fn bindings_default() {
+    let bindings = KeyBindings::new();
+    assert_eq!(bindings.button_for(Key::Char('x')), Some(Button::A));
+    assert_eq!(bindings.button_for(Key::Enter), Some(Button::Start));
+    assert_eq!(bindings.button_for(Key::Char('q')), None);
+}
+#[test]
+fn bindings_parse_overrides() {
+    let bindings = KeyBindings::parse("a=k,start=space").unwrap();
+    assert_eq!(bindings.button_for(Key::Char('k')), Some(Button::A));
+    assert_eq!(bindings.button_for(Key::Char('x')), None);
+    assert_eq!(bindings.button_for(Key::Char(' ')), Some(Button::Start));
+    assert_eq!(bindings.button_for(Key::Char('z')), Some(Button::B));
+    assert!(KeyBindings::parse("turbo=t").is_err());
+}
+#[test]
+fn bindings_rebind_default_key() {
+    let bindings = KeyBindings::parse("a=z").unwrap();
+    assert_eq!(bindings.button_for(Key::Char('z')), Some(Button::A));
+    assert_eq!(bindings.button_for(Key::Char('x')), None);
+    let swapped = KeyBindings::parse("a=z,b=x").unwrap();
+    assert_eq!(swapped.button_for(Key::Char('z')), Some(Button::A));
+    assert_eq!(swapped.button_for(Key::Char('x')), Some(Button::B));
+}
+
+// Screen Tests
+#[test]
+fn screen_draws_changed_cells() {
+    let mut bytes = vec![];
+    {
+        let mut screen = TerminalScreen::new(&mut bytes).unwrap();
+        // Two columns, three rows: the last cell has black below
+        let mut rgba = vec![0xFF; 2 * 3 * 4];
+        screen.draw(&rgba, 2, 3).unwrap();
+        screen.draw(&rgba, 2, 3).unwrap();
+        rgba[4 * 4] = 0x10;
+        screen.draw(&rgba, 2, 3).unwrap();
+    }
+    let text = String::from_utf8(bytes).unwrap();
+    let cells: Vec<&str> = text.split('\u{2580}').collect();
+    assert_eq!(cells.len(), 2 * 2 + 1 + 1);
+    assert!(cells[0].ends_with("\x1b[1;1H\x1b[38;2;255;255;255;48;2;255;255;255m"));
+    assert!(cells[2].ends_with("\x1b[2;1H\x1b[38;2;255;255;255;48;2;0;0;0m"));
+    // Only the changed cell is drawn again
+    assert!(cells[4].ends_with("\x1b[0m\x1b[2;1H\x1b[38;2;16;255;255;48;2;0;0;0m"));
+    assert!(cells[5].ends_with("\x1b[?1049l"));
+}
+
+// Hold Tests
+#[test]
+fn hold_releases_after_press_time() {
+    let start = Instant::now();
+    let mut hold = KeyHold::new(Duration::from_millis(300), Duration::from_millis(100));
+    hold.press(Button::A, start);
+    assert!(hold.state(start + Duration::from_millis(299)).is_pressed(Button::A));
+    assert!(!hold.state(start + Duration::from_millis(300)).is_pressed(Button::A));
+}
+#[test]
+fn hold_extended_by_repeat() {
+    let start = Instant::now();
+    let ms = Duration::from_millis;
+    let mut hold = KeyHold::new(ms(300), ms(100));
+    hold.press(Button::Up, start);
+    // An early repeat doesn't cut the first hold short
+    hold.press(Button::Up, start + ms(50));
+    assert!(hold.state(start + ms(299)).is_pressed(Button::Up));
+    hold.press(Button::Up, start + ms(280));
+    assert!(hold.state(start + ms(379)).is_pressed(Button::Up));
+    assert!(!hold.state(start + ms(380)).is_pressed(Button::Up));
+    // A press after letting go starts a full hold again
+    hold.press(Button::Up, start + ms(500));
+    assert!(hold.state(start + ms(799)).is_pressed(Button::Up));
+}
@@ -0,0 +1,154 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::Path;
+
+use crate::apu::CPU_CLOCK;
+
+// VGM timestamps are always counted in 44100 Hz samples
+const VGM_RATE: u64 = 44100;
+const VERSION: u32 = 0x171;
+const HEADER_LEN: u32 = 0x100;
+const GB_DMG_CLOCK_OFFSET: u64 = 0x80;
+
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_60HZ: u8 = 0x62;
+const CMD_WAIT_50HZ: u8 = 0x63;
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_GB_DMG_WRITE: u8 = 0xB3;
+const CMD_END: u8 = 0x66;
+
+// Logs sound register writes as a VGM 1.71 stream for the GB DMG chip.
+// Writes are timestamped in CPU cycles and converted into VGM wait
+// commands. Like WavWriter, the header totals are patched in by
+// finalize(), which also runs on drop.
+pub struct VgmWriter<W: Write + Seek> {
+    out: W,
+    // Cycle the log was started at, and how many samples of waits have
+    // been written since
+    start_cycle: u64,
+    samples: u64,
+    data_len: u32,
+}
+
+impl VgmWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, start_cycle: u64) -> io::Result<VgmWriter<BufWriter<File>>> {
+        VgmWriter::new(BufWriter::new(File::create(path)?), start_cycle)
+    }
+}
+
+impl<W: Write + Seek> VgmWriter<W> {
+    pub fn new(mut out: W, start_cycle: u64) -> io::Result<VgmWriter<W>> {
+        let mut header = [0u8; HEADER_LEN as usize];
+        header[0x00..0x04].copy_from_slice(b"Vgm ");
+        header[0x08..0x0C].copy_from_slice(&VERSION.to_le_bytes());
+        // Data offset is relative to its own position
+        header[0x34..0x38].copy_from_slice(&(HEADER_LEN - 0x34).to_le_bytes());
+        let clock = GB_DMG_CLOCK_OFFSET as usize;
+        header[clock..clock + 4].copy_from_slice(&CPU_CLOCK.to_le_bytes());
+        out.write_all(&header)?;
+        Ok(VgmWriter { out, start_cycle, samples: 0, data_len: 0 })
+    }
+
+    // Logs a write to 0xFF10-0xFF3F made at the given CPU cycle
+    pub fn write_register(&mut self, cycle: u64, addr: u16, val: u8) -> io::Result<()> {
+        self.wait_until(cycle)?;
+        self.write_command(&[CMD_GB_DMG_WRITE, (addr - 0xFF10) as u8, val])
+    }
+
+    // Emits waits so the stream's position catches up with `cycle`
+    pub fn wait_until(&mut self, cycle: u64) -> io::Result<()> {
+        let target = cycle.saturating_sub(self.start_cycle) * VGM_RATE / CPU_CLOCK as u64;
+        while self.samples < target {
+            let wait = (target - self.samples).min(0xFFFF);
+            match wait {
+                1..=16 => self.write_command(&[CMD_WAIT_SHORT + (wait - 1) as u8])?,
+                735 => self.write_command(&[CMD_WAIT_60HZ])?,
+                882 => self.write_command(&[CMD_WAIT_50HZ])?,
+                _ => {
+                    let [lo, hi] = (wait as u16).to_le_bytes();
+                    self.write_command(&[CMD_WAIT, lo, hi])?
+                }
+            }
+            self.samples += wait;
+        }
+        Ok(())
+    }
+
+    fn write_command(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.data_len += bytes.len() as u32;
+        Ok(())
+    }
+
+    // Writes the end marker and header totals. The end marker is
+    // overwritten if more commands are logged afterwards.
+    pub fn finalize(&mut self) -> io::Result<()> {
+        self.out.write_all(&[CMD_END])?;
+        let file_len = HEADER_LEN + self.data_len + 1;
+        self.out.seek(SeekFrom::Start(0x04))?;
+        self.out.write_all(&(file_len - 4).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(0x18))?;
+        self.out.write_all(&(self.samples as u32).to_le_bytes())?;
+        self.out.seek(SeekFrom::End(-1))?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for VgmWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+
+// VGM Tests
+#[test]
+fn vgm_header() {
+    let mut bytes = vec![];
+    {
+        let _vgm = VgmWriter::new(io::Cursor::new(&mut bytes), 0).unwrap();
+    }
+    assert_eq!(bytes.len(), 0x101);
+    assert_eq!(&bytes[0x00..0x04], b"Vgm ");
+    assert_eq!(&bytes[0x04..0x08], &0xFDu32.to_le_bytes());
+    assert_eq!(&bytes[0x08..0x0C], &0x171u32.to_le_bytes());
+    assert_eq!(&bytes[0x34..0x38], &0xCCu32.to_le_bytes());
+    assert_eq!(&bytes[0x80..0x84], &4194304u32.to_le_bytes());
+    assert_eq!(bytes[0x100], 0x66);
+}
+#[test]
+fn vgm_register_writes_and_waits() {
+    let mut bytes = vec![];
+    {
+        let mut vgm = VgmWriter::new(io::Cursor::new(&mut bytes), 1000).unwrap();
+        vgm.write_register(1000, 0xFF26, 0x80).unwrap();
+        // 10 samples later
+        vgm.write_register(1000 + 952, 0xFF30, 0x12).unwrap();
+        // One 60 Hz frame later
+        vgm.wait_until(1000 + 952 + 69905).unwrap();
+    }
+    assert_eq!(&bytes[0x100..], &[0xB3, 0x16, 0x80, 0x79, 0xB3, 0x20, 0x12, 0x62, 0x66]);
+    assert_eq!(&bytes[0x18..0x1C], &(10u32 + 735).to_le_bytes());
+}
+#[test]
+fn vgm_long_waits() {
+    let mut bytes = vec![];
+    {
+        let mut vgm = VgmWriter::new(io::Cursor::new(&mut bytes), 0).unwrap();
+        vgm.wait_until(4194304).unwrap();
+    }
+    assert_eq!(&bytes[0x100..], &[0x61, 0x44, 0xAC, 0x66]);
+}
+#[test]
+fn vgm_finalize_can_continue_writing() {
+    let mut bytes = vec![];
+    {
+        let mut vgm = VgmWriter::new(io::Cursor::new(&mut bytes), 0).unwrap();
+        vgm.write_register(0, 0xFF24, 0x77).unwrap();
+        vgm.finalize().unwrap();
+        vgm.write_register(0, 0xFF25, 0xFF).unwrap();
+    }
+    assert_eq!(&bytes[0x100..], &[0xB3, 0x14, 0x77, 0xB3, 0x15, 0xFF, 0x66]);
+}
@@ -0,0 +1,416 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::Path;
+
+use crate::apu::CPU_CLOCK;
+use crate::gb::CYCLES_PER_FRAME;
+
+// AVI index flag for frames that don't depend on others
+const AVIIF_KEYFRAME: u32 = 0x10;
+const AVIF_HASINDEX: u32 = 0x10;
+
+// YUV4MPEG2 video for piping into ffmpeg: a text header, then each frame as
+// full resolution Y, Cb and Cr planes (BT.601, limited range). The frame
+// rate is the exact 4194304 / 70224 Hz of the LCD.
+pub struct Y4mWriter<W: Write> {
+    out: W,
+    width: usize,
+    height: usize,
+}
+
+impl Y4mWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, width: usize, height: usize) -> io::Result<Y4mWriter<BufWriter<File>>> {
+        Y4mWriter::new(BufWriter::new(File::create(path)?), width, height)
+    }
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(mut out: W, width: usize, height: usize) -> io::Result<Y4mWriter<W>> {
+        writeln!(out, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444", width, height, CPU_CLOCK, CYCLES_PER_FRAME)?;
+        Ok(Y4mWriter { out, width, height })
+    }
+
+    pub fn write_frame(&mut self, rgba: &[u8]) -> io::Result<()> {
+        check_frame_size(rgba, self.width, self.height)?;
+        let pixels = self.width * self.height;
+        let mut planes = vec![0; pixels * 3];
+        for (i, p) in rgba.chunks(4).enumerate() {
+            let (y, u, v) = rgb_to_yuv(p[0], p[1], p[2]);
+            planes[i] = y;
+            planes[pixels + i] = u;
+            planes[pixels * 2 + i] = v;
+        }
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&planes)
+    }
+
+    pub fn finalize(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<W: Write> Drop for Y4mWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+fn rgb_to_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    (
+        (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8,
+        (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8,
+        (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8,
+    )
+}
+
+fn check_frame_size(rgba: &[u8], width: usize, height: usize) -> io::Result<()> {
+    if rgba.len() != width * height * 4 {
+        return Err(io::Error::other("frame doesn't match video size"));
+    }
+    Ok(())
+}
+
+// Uncompressed AVI with 24-bit RGB video and 16-bit stereo PCM sound,
+// interleaved one video frame then the sound that played during it.
+//
+// Header fields that depend on the length are patched in by finalize(),
+// which also writes the index after the data and runs on drop. Frames
+// written after finalize() overwrite the index, which is written again.
+pub struct AviWriter<W: Write + Seek> {
+    out: W,
+    width: usize,
+    height: usize,
+    frames: u32,
+    audio_frames: u32,
+    // Position of the 'movi' list type, which index offsets count from,
+    // and of the end of the data written so far
+    movi_start: u64,
+    movi_end: u64,
+    // (chunk id, offset, size) of every chunk
+    index: Vec<([u8; 4], u32, u32)>,
+    // Chunks that would take the file past this many bytes are refused
+    max_size: u64,
+}
+
+// AVI 1.0 readers give up on files over 1 GiB, and the sizes in the file
+// can't go past 4 GiB
+const MAX_AVI_SIZE: u64 = 1 << 30;
+
+// Offsets of the header fields finalize() patches
+const AVIH_TOTAL_FRAMES: u64 = 0x30;
+const VIDEO_STRH_LENGTH: u64 = 0x8C;
+const AUDIO_STRH_LENGTH: u64 = 0x108;
+const RIFF_SIZE: u64 = 0x04;
+
+impl AviWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, width: usize, height: usize, sample_rate: u32) -> io::Result<AviWriter<BufWriter<File>>> {
+        AviWriter::new(BufWriter::new(File::create(path)?), width, height, sample_rate)
+    }
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    pub fn new(mut out: W, width: usize, height: usize, sample_rate: u32) -> io::Result<AviWriter<W>> {
+        let frame_size = (row_size(width) * height) as u32;
+        let audio_per_frame = (sample_rate as u64 * CYCLES_PER_FRAME as u64 / CPU_CLOCK as u64 + 1) as u32 * 4;
+        let us_per_frame = (CYCLES_PER_FRAME as u64 * 1_000_000 / CPU_CLOCK as u64) as u32;
+        let rate = |n: u32| (n as u64 * CPU_CLOCK as u64 / CYCLES_PER_FRAME as u64) as u32;
+
+        let mut avih = vec![];
+        for value in [
+            us_per_frame,
+            rate(frame_size + audio_per_frame),
+            0,
+            AVIF_HASINDEX,
+            0, // total frames
+            0,
+            2, // streams
+            frame_size + audio_per_frame,
+            width as u32,
+            height as u32,
+            0, 0, 0, 0,
+        ].iter() {
+            avih.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let mut video_strh = stream_header(b"vids", b"DIB ", CYCLES_PER_FRAME, CPU_CLOCK, frame_size, 0);
+        video_strh[0x30..0x38].copy_from_slice(&[0, 0, 0, 0, width as u8, (width >> 8) as u8, height as u8, (height >> 8) as u8]);
+        let mut bitmap_info = vec![];
+        bitmap_info.extend_from_slice(&40u32.to_le_bytes());
+        bitmap_info.extend_from_slice(&(width as i32).to_le_bytes());
+        // Positive height means the rows are stored bottom up
+        bitmap_info.extend_from_slice(&(height as i32).to_le_bytes());
+        bitmap_info.extend_from_slice(&1u16.to_le_bytes());
+        bitmap_info.extend_from_slice(&24u16.to_le_bytes());
+        bitmap_info.extend_from_slice(&0u32.to_le_bytes()); // BI_RGB
+        bitmap_info.extend_from_slice(&frame_size.to_le_bytes());
+        bitmap_info.extend_from_slice(&[0; 16]);
+
+        let audio_strh = stream_header(b"auds", &[0; 4], 4, sample_rate * 4, audio_per_frame, 4);
+        let mut wave_format = vec![];
+        wave_format.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wave_format.extend_from_slice(&2u16.to_le_bytes());
+        wave_format.extend_from_slice(&sample_rate.to_le_bytes());
+        wave_format.extend_from_slice(&(sample_rate * 4).to_le_bytes());
+        wave_format.extend_from_slice(&4u16.to_le_bytes());
+        wave_format.extend_from_slice(&16u16.to_le_bytes());
+
+        let video_strl = list(b"strl", &[chunk(b"strh", &video_strh), chunk(b"strf", &bitmap_info)].concat());
+        let audio_strl = list(b"strl", &[chunk(b"strh", &audio_strh), chunk(b"strf", &wave_format)].concat());
+        let hdrl = list(b"hdrl", &[chunk(b"avih", &avih), video_strl, audio_strl].concat());
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"AVI ")?;
+        out.write_all(&hdrl)?;
+        out.write_all(b"LIST")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        let movi_start = 12 + hdrl.len() as u64 + 8;
+        out.write_all(b"movi")?;
+        Ok(AviWriter {
+            out,
+            width,
+            height,
+            frames: 0,
+            audio_frames: 0,
+            movi_start,
+            movi_end: movi_start + 4,
+            index: vec![],
+            max_size: MAX_AVI_SIZE,
+        })
+    }
+
+    pub fn write_frame(&mut self, rgba: &[u8]) -> io::Result<()> {
+        check_frame_size(rgba, self.width, self.height)?;
+        let stride = row_size(self.width);
+        let mut data = vec![0; stride * self.height];
+        for (y, row) in rgba.chunks(self.width * 4).enumerate() {
+            let line = &mut data[(self.height - 1 - y) * stride..];
+            for (x, p) in row.chunks(4).enumerate() {
+                line[x * 3..x * 3 + 3].copy_from_slice(&[p[2], p[1], p[0]]);
+            }
+        }
+        self.write_chunk(*b"00db", &data)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    // Interleaved stereo samples
+    pub fn write_audio(&mut self, samples: &[i16]) -> io::Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let mut data = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+        self.write_chunk(*b"01wb", &data)?;
+        self.audio_frames += samples.len() as u32 / 2;
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, id: [u8; 4], data: &[u8]) -> io::Result<()> {
+        let bytes = chunk(&id, data);
+        // Leave room for the index finalize() writes after the data
+        let index_size = 8 + 16 * (self.index.len() as u64 + 1);
+        if self.movi_end + bytes.len() as u64 + index_size > self.max_size {
+            return Err(io::Error::other("AVI file reached the 1 GiB limit"));
+        }
+        self.out.seek(SeekFrom::Start(self.movi_end))?;
+        self.out.write_all(&bytes)?;
+        self.index.push((id, (self.movi_end - self.movi_start) as u32, data.len() as u32));
+        self.movi_end += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub fn finalize(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(self.movi_end))?;
+        let mut idx = vec![];
+        for (id, offset, size) in self.index.iter() {
+            idx.extend_from_slice(id);
+            idx.extend_from_slice(&AVIIF_KEYFRAME.to_le_bytes());
+            idx.extend_from_slice(&offset.to_le_bytes());
+            idx.extend_from_slice(&size.to_le_bytes());
+        }
+        let idx = chunk(b"idx1", &idx);
+        self.out.write_all(&idx)?;
+        let file_len = self.movi_end + idx.len() as u64;
+
+        let patches = [
+            (RIFF_SIZE, (file_len - 8) as u32),
+            (AVIH_TOTAL_FRAMES, self.frames),
+            (VIDEO_STRH_LENGTH, self.frames),
+            (AUDIO_STRH_LENGTH, self.audio_frames),
+            (self.movi_start - 4, (self.movi_end - self.movi_start) as u32),
+        ];
+        for &(position, value) in patches.iter() {
+            self.out.seek(SeekFrom::Start(position))?;
+            self.out.write_all(&value.to_le_bytes())?;
+        }
+        self.out.seek(SeekFrom::Start(self.movi_end))?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for AviWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+// Rows of 24-bit pixels are padded to four bytes
+fn row_size(width: usize) -> usize {
+    (width * 3).div_ceil(4) * 4
+}
+
+fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() + 9);
+    bytes.extend_from_slice(id);
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        bytes.push(0);
+    }
+    bytes
+}
+
+fn list(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+    chunk(b"LIST", &[&kind[..], contents].concat())
+}
+
+fn stream_header(kind: &[u8; 4], handler: &[u8; 4], scale: u32, rate: u32, buffer_size: u32, sample_size: u32) -> Vec<u8> {
+    let mut strh = vec![];
+    strh.extend_from_slice(kind);
+    strh.extend_from_slice(handler);
+    // Flags, priority and language, initial frames
+    strh.extend_from_slice(&[0; 12]);
+    strh.extend_from_slice(&scale.to_le_bytes());
+    strh.extend_from_slice(&rate.to_le_bytes());
+    strh.extend_from_slice(&0u32.to_le_bytes()); // start
+    strh.extend_from_slice(&0u32.to_le_bytes()); // length
+    strh.extend_from_slice(&buffer_size.to_le_bytes());
+    strh.extend_from_slice(&u32::MAX.to_le_bytes()); // default quality
+    strh.extend_from_slice(&sample_size.to_le_bytes());
+    strh.extend_from_slice(&[0; 8]); // frame rectangle
+    strh
+}
+
+// A recording in either format, picked by file extension
+pub enum VideoWriter {
+    Y4m(Y4mWriter<BufWriter<File>>),
+    Avi(AviWriter<BufWriter<File>>),
+}
+
+impl VideoWriter {
+    pub fn create<P: AsRef<Path>>(path: P, width: usize, height: usize, sample_rate: u32) -> io::Result<VideoWriter> {
+        let is_avi = path.as_ref().extension().is_some_and(|e| e.eq_ignore_ascii_case("avi"));
+        if is_avi {
+            Ok(VideoWriter::Avi(AviWriter::create(path, width, height, sample_rate)?))
+        } else {
+            Ok(VideoWriter::Y4m(Y4mWriter::create(path, width, height)?))
+        }
+    }
+
+    pub fn write_frame(&mut self, rgba: &[u8]) -> io::Result<()> {
+        match self {
+            VideoWriter::Y4m(y4m) => y4m.write_frame(rgba),
+            VideoWriter::Avi(avi) => avi.write_frame(rgba),
+        }
+    }
+
+    // Y4M has no sound, so it is dropped
+    pub fn write_audio(&mut self, samples: &[i16]) -> io::Result<()> {
+        match self {
+            VideoWriter::Y4m(_) => Ok(()),
+            VideoWriter::Avi(avi) => avi.write_audio(samples),
+        }
+    }
+
+    pub fn finalize(&mut self) -> io::Result<()> {
+        match self {
+            VideoWriter::Y4m(y4m) => y4m.finalize(),
+            VideoWriter::Avi(avi) => avi.finalize(),
+        }
+    }
+}
+
+
+// Video Tests
+#[cfg(test)]
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+#[test]
+fn video_y4m_frames() {
+    let mut bytes = vec![];
+    {
+        let mut y4m = Y4mWriter::new(&mut bytes, 2, 1).unwrap();
+        y4m.write_frame(&[255, 255, 255, 255, 0, 0, 0, 255]).unwrap();
+        assert!(y4m.write_frame(&[0; 4]).is_err());
+    }
+    let header = b"YUV4MPEG2 W2 H1 F4194304:70224 Ip A1:1 C444\nFRAME\n";
+    assert_eq!(&bytes[..header.len()], &header[..]);
+    assert_eq!(&bytes[header.len()..], &[235, 16, 128, 128, 128, 128]);
+}
+#[test]
+fn video_yuv_primaries() {
+    assert_eq!(rgb_to_yuv(255, 0, 0), (82, 90, 240));
+    assert_eq!(rgb_to_yuv(0, 0, 255), (41, 240, 110));
+}
+#[test]
+fn video_avi_layout() {
+    let mut bytes = io::Cursor::new(vec![]);
+    {
+        let mut avi = AviWriter::new(&mut bytes, 2, 2, 48000).unwrap();
+        avi.write_frame(&[
+            1, 2, 3, 255, 4, 5, 6, 255,
+            7, 8, 9, 255, 10, 11, 12, 255,
+        ]).unwrap();
+        avi.write_audio(&[1, -1, 2, -2]).unwrap();
+        avi.write_frame(&[0; 16]).unwrap();
+    }
+    let bytes = bytes.into_inner();
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+    assert_eq!(&bytes[8..12], b"AVI ");
+    assert_eq!(&bytes[12..16], b"LIST");
+    assert_eq!(&bytes[20..24], b"hdrl");
+    assert_eq!(&bytes[24..28], b"avih");
+    assert_eq!(u32_at(&bytes, AVIH_TOTAL_FRAMES as usize), 2);
+    assert_eq!(&bytes[VIDEO_STRH_LENGTH as usize - 0x20..VIDEO_STRH_LENGTH as usize - 0x1C], b"vids");
+    assert_eq!(u32_at(&bytes, VIDEO_STRH_LENGTH as usize), 2);
+    assert_eq!(&bytes[AUDIO_STRH_LENGTH as usize - 0x20..AUDIO_STRH_LENGTH as usize - 0x1C], b"auds");
+    assert_eq!(u32_at(&bytes, AUDIO_STRH_LENGTH as usize), 2);
+
+    let movi = bytes.windows(4).position(|w| w == b"movi").unwrap();
+    assert_eq!(&bytes[movi - 8..movi - 4], b"LIST");
+    // The bottom row comes first, as BGR padded to four bytes
+    assert_eq!(&bytes[movi + 4..movi + 12], b"00db\x10\0\0\0");
+    assert_eq!(&bytes[movi + 12..movi + 20], &[9, 8, 7, 12, 11, 10, 0, 0]);
+    assert_eq!(&bytes[movi + 28..movi + 36], b"01wb\x08\0\0\0");
+    let idx = bytes.len() - 8 - 3 * 16;
+    assert_eq!(&bytes[idx..idx + 4], b"idx1");
+    assert_eq!(u32_at(&bytes, movi - 4) as usize, idx - movi);
+    assert_eq!(&bytes[idx + 8..idx + 12], b"00db");
+    assert_eq!(u32_at(&bytes, idx + 16), 4);
+    assert_eq!(u32_at(&bytes, idx + 24 + 8), 4 + 24);
+}
+#[test]
+fn video_avi_stops_at_size_limit() {
+    let mut bytes = io::Cursor::new(vec![]);
+    {
+        let mut avi = AviWriter::new(&mut bytes, 2, 2, 48000).unwrap();
+        // Room for the first frame and its index entry, but not a second
+        avi.max_size = avi.movi_end + 24 + 8 + 16;
+        avi.write_frame(&[0; 16]).unwrap();
+        assert!(avi.write_frame(&[0; 16]).is_err());
+        assert!(avi.write_audio(&[1, -1]).is_err());
+    }
+    let bytes = bytes.into_inner();
+    assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+    assert_eq!(u32_at(&bytes, AVIH_TOTAL_FRAMES as usize), 1);
+    assert_eq!(bytes.windows(4).filter(|w| w == b"00db").count(), 2);
+}
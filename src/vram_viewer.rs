@@ -0,0 +1,334 @@
+use std::io;
+use std::path::Path;
+
+use crate::color::{ColorCorrection, OutputPalette};
+use crate::png::{self, ColorType};
+use crate::ppu::{self, Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const TILES: usize = 384;
+const TILES_PER_ROW: usize = 16;
+const MAP_SIZE: usize = 256;
+// Each sprite gets a cell with the sprite at double size on the left and
+// its OAM bytes written on the right
+const SPRITE_COLUMNS: usize = 8;
+const CELL_WIDTH: usize = 40;
+const CELL_HEIGHT: usize = 36;
+
+const VIEWPORT_COLOR: [u8; 3] = [0xFF, 0x00, 0x00];
+const WINDOW_COLOR: [u8; 3] = [0x00, 0x80, 0xFF];
+const CELL_COLOR: [u8; 3] = [0x40, 0x40, 0x40];
+const TEXT_COLOR: [u8; 3] = [0xFF, 0xFF, 0xFF];
+
+// 3x5 glyphs for writing OAM bytes, one row per byte
+const FONT: &str = "0123456789ABCDEFTXY";
+const GLYPHS: [[u8; 5]; 19] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b010, 0b101, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b011, 0b100, 0b100, 0b100, 0b011],
+    [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b110, 0b100, 0b111],
+    [0b111, 0b100, 0b110, 0b100, 0b100],
+    [0b111, 0b010, 0b010, 0b010, 0b010],
+    [0b101, 0b101, 0b010, 0b101, 0b101],
+    [0b101, 0b101, 0b010, 0b010, 0b010],
+];
+
+// An RGB picture
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, color: [u8; 3]) -> Image {
+        Image { width, height, rgb: color.repeat(width * height) }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * self.width + x) * 3;
+        [self.rgb[i], self.rgb[i + 1], self.rgb[i + 2]]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        let i = (y * self.width + x) * 3;
+        self.rgb[i..i + 3].copy_from_slice(&color);
+    }
+
+    // Outline of a rectangle, wrapping around the edges like the
+    // background does
+    fn draw_wrapped_rect(&mut self, left: usize, top: usize, width: usize, height: usize, color: [u8; 3]) {
+        for x in 0..width {
+            self.set_pixel((left + x) % self.width, top % self.height, color);
+            self.set_pixel((left + x) % self.width, (top + height - 1) % self.height, color);
+        }
+        for y in 0..height {
+            self.set_pixel(left % self.width, (top + y) % self.height, color);
+            self.set_pixel((left + width - 1) % self.width, (top + y) % self.height, color);
+        }
+    }
+
+    fn draw_text(&mut self, left: usize, top: usize, text: &str) {
+        for (i, c) in text.chars().enumerate() {
+            let glyph = match FONT.find(c) {
+                Some(index) => GLYPHS[index],
+                None => continue,
+            };
+            for (y, row) in glyph.iter().enumerate() {
+                for x in 0..3 {
+                    if row & (0b100 >> x) != 0 {
+                        self.set_pixel(left + i * 4 + x, top + y, TEXT_COLOR);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        png::save(path, self.width as u32, self.height as u32, ColorType::Rgb, &self.rgb)
+    }
+}
+
+// Draws what is in VRAM and OAM for debugging, in the colours the screen
+// would show them in
+pub struct VramViewer<'a> {
+    ppu: &'a Ppu,
+    correction: ColorCorrection,
+    palette: OutputPalette,
+}
+
+impl<'a> VramViewer<'a> {
+    pub fn new(ppu: &'a Ppu, correction: ColorCorrection, palette: OutputPalette) -> VramViewer<'a> {
+        VramViewer { ppu, correction, palette }
+    }
+
+    // All 384 tiles, 16 to a row, in the four shades with no palette
+    // applied. In CGB mode bank 1 is drawn to the right of bank 0.
+    pub fn tile_sheet(&self) -> Image {
+        let banks = if self.ppu.is_cgb() { 2 } else { 1 };
+        let bank_width = TILES_PER_ROW * 8;
+        let mut image = Image::new(bank_width * banks, TILES / TILES_PER_ROW * 8, [0; 3]);
+        for bank in 0..banks {
+            for tile in 0..TILES {
+                let left = bank * bank_width + tile % TILES_PER_ROW * 8;
+                let top = tile / TILES_PER_ROW * 8;
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let color = self.tile_pixel(bank, tile * 16, y, x);
+                        image.set_pixel(left + x, top + y, self.palette.colors[color as usize]);
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    // One of the two 32x32 tile maps (0 at 0x9800, 1 at 0x9C00) as the
+    // background would draw it. The screen's view of the background is
+    // outlined when the map is the one in use.
+    pub fn bg_map(&self, map: usize) -> Image {
+        let mut image = self.tile_map(map);
+        let lcdc = self.ppu.read(0xFF40);
+        if (lcdc >> 3) as usize & 1 == map {
+            let scx = self.ppu.read(0xFF43) as usize;
+            let scy = self.ppu.read(0xFF42) as usize;
+            image.draw_wrapped_rect(scx, scy, SCREEN_WIDTH, SCREEN_HEIGHT, VIEWPORT_COLOR);
+        }
+        image
+    }
+
+    // The map the window shows, with the part on screen outlined when the
+    // window is enabled
+    pub fn window_map(&self) -> Image {
+        let lcdc = self.ppu.read(0xFF40);
+        let mut image = self.tile_map((lcdc >> 6) as usize & 1);
+        let wx = self.ppu.read(0xFF4B) as usize;
+        let wy = self.ppu.read(0xFF4A) as usize;
+        if lcdc & 0x20 != 0 && wx < SCREEN_WIDTH + 7 && wy < SCREEN_HEIGHT {
+            let width = SCREEN_WIDTH + 7 - wx.max(7);
+            image.draw_wrapped_rect(0, 0, width, SCREEN_HEIGHT - wy, WINDOW_COLOR);
+        }
+        image
+    }
+
+    // The 40 sprites in OAM order, 8 to a row, each at double size next
+    // to its X, Y, tile and attribute bytes in hex. Sprites are drawn at
+    // the current sprite height.
+    pub fn oam_sheet(&self) -> Image {
+        let rows = 40 / SPRITE_COLUMNS;
+        let mut image = Image::new(SPRITE_COLUMNS * CELL_WIDTH, rows * CELL_HEIGHT, [0; 3]);
+        let height = if self.ppu.read(0xFF40) & 0x04 != 0 { 16 } else { 8 };
+        for (i, sprite) in self.ppu.oam().chunks(4).enumerate() {
+            let left = i % SPRITE_COLUMNS * CELL_WIDTH;
+            let top = i / SPRITE_COLUMNS * CELL_HEIGHT;
+            for y in 1..CELL_HEIGHT - 1 {
+                for x in 1..CELL_WIDTH - 1 {
+                    image.set_pixel(left + x, top + y, CELL_COLOR);
+                }
+            }
+
+            let attributes = sprite[3];
+            let mut tile = sprite[2] as usize;
+            if height == 16 { tile &= 0xFE; }
+            let bank = if self.ppu.is_cgb() { ((attributes >> 3) & 1) as usize } else { 0 };
+            for y in 0..height {
+                for x in 0..8 {
+                    let row = if attributes & 0x40 != 0 { height - 1 - y } else { y };
+                    let column = if attributes & 0x20 != 0 { 7 - x } else { x };
+                    let color = self.tile_pixel(bank, tile * 16, row, column);
+                    if color == 0 {
+                        continue;
+                    }
+                    let rgb = self.sprite_color(attributes, color);
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                        image.set_pixel(left + 2 + x * 2 + dx, top + 2 + y * 2 + dy, rgb);
+                    }
+                }
+            }
+
+            for (line, (label, value)) in ["X", "Y", "T", "A"].iter().zip([sprite[1], sprite[0], sprite[2], attributes].iter()).enumerate() {
+                image.draw_text(left + 21, top + 2 + line * 6, &format!("{} {:02X}", label, value));
+            }
+        }
+        image
+    }
+
+    // Writes tiles.png, bg_map0.png, bg_map1.png, window_map.png and
+    // oam.png to the directory
+    pub fn save_all<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        self.tile_sheet().save(dir.join("tiles.png"))?;
+        self.bg_map(0).save(dir.join("bg_map0.png"))?;
+        self.bg_map(1).save(dir.join("bg_map1.png"))?;
+        self.window_map().save(dir.join("window_map.png"))?;
+        self.oam_sheet().save(dir.join("oam.png"))
+    }
+
+    // A tile map as the background would draw it, without outlines
+    pub fn tile_map(&self, map: usize) -> Image {
+        let mut image = Image::new(MAP_SIZE, MAP_SIZE, [0; 3]);
+        let base = 0x1800 + map * 0x400;
+        for i in 0..32 * 32 {
+            let tile = self.ppu.vram(0)[base + i];
+            let attributes = if self.ppu.is_cgb() { self.ppu.vram(1)[base + i] } else { 0 };
+            let tile_addr = self.ppu.bg_tile_addr(tile);
+            let bank = ((attributes >> 3) & 1) as usize;
+            for y in 0..8 {
+                for x in 0..8 {
+                    let row = if attributes & 0x40 != 0 { 7 - y } else { y };
+                    let column = if attributes & 0x20 != 0 { 7 - x } else { x };
+                    let color = self.tile_pixel(bank, tile_addr, row, column);
+                    let rgb = if self.ppu.is_cgb() {
+                        self.correction.apply(self.ppu.bg_palette_color(attributes & 0x07, color))
+                    } else {
+                        self.palette.colors[ppu::shade(self.ppu.read(0xFF47), color) as usize]
+                    };
+                    image.set_pixel(i % 32 * 8 + x, i / 32 * 8 + y, rgb);
+                }
+            }
+        }
+        image
+    }
+
+    fn sprite_color(&self, attributes: u8, color: u8) -> [u8; 3] {
+        if self.ppu.is_cgb() {
+            return self.correction.apply(self.ppu.obj_palette_color(attributes & 0x07, color));
+        }
+        let obp = if attributes & 0x10 != 0 { self.ppu.read(0xFF49) } else { self.ppu.read(0xFF48) };
+        self.palette.colors[ppu::shade(obp, color) as usize]
+    }
+
+    fn tile_pixel(&self, bank: usize, tile_addr: usize, row: usize, column: usize) -> u8 {
+        let vram = self.ppu.vram(bank);
+        let low = vram[tile_addr + row * 2];
+        let high = vram[tile_addr + row * 2 + 1];
+        let bit = 7 - column;
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+    }
+}
+
+
+// VRAM Viewer Tests
+#[cfg(test)]
+fn ppu_with_tiles() -> Ppu {
+    let mut ppu = Ppu::new();
+    // Tile 1 is solid colour 3, tile 2 solid colour 1
+    for i in 0..16 {
+        ppu.write_vram(0x8010 + i, 0xFF);
+        ppu.write_vram(0x8020 + i, if i % 2 == 0 { 0xFF } else { 0 });
+    }
+    ppu.write(0xFF40, 0x91);
+    ppu.write(0xFF47, 0xE4);
+    ppu
+}
+
+#[test]
+fn vram_viewer_tile_sheet() {
+    let ppu = ppu_with_tiles();
+    let sheet = VramViewer::new(&ppu, ColorCorrection::Raw, OutputPalette::POCKET).tile_sheet();
+    assert_eq!((sheet.width, sheet.height), (128, 192));
+    assert_eq!(sheet.pixel(0, 0), [0xFF, 0xFF, 0xFF]);
+    assert_eq!(sheet.pixel(8, 7), [0x00, 0x00, 0x00]);
+    assert_eq!(sheet.pixel(16, 0), [0xAA, 0xAA, 0xAA]);
+}
+#[test]
+fn vram_viewer_bg_map_viewport() {
+    let mut ppu = ppu_with_tiles();
+    ppu.write_vram(0x9801, 1);
+    ppu.write(0xFF43, 200);
+    ppu.write(0xFF42, 10);
+    // BGP maps colour 3 to white
+    ppu.write(0xFF47, 0x24);
+    let viewer = VramViewer::new(&ppu, ColorCorrection::Raw, OutputPalette::POCKET);
+    let map = viewer.bg_map(0);
+    assert_eq!(map.pixel(8, 0), [0xFF, 0xFF, 0xFF]);
+    assert_eq!(map.pixel(0, 0), [0xFF, 0xFF, 0xFF]);
+    // The outline wraps from the right edge to the left
+    assert_eq!(map.pixel(200, 10), VIEWPORT_COLOR);
+    assert_eq!(map.pixel(255, 10), VIEWPORT_COLOR);
+    assert_eq!(map.pixel((200 + 159) % 256, 50), VIEWPORT_COLOR);
+    assert_eq!(map.pixel(0, 153), VIEWPORT_COLOR);
+    assert_eq!(map.pixel(199, 10), [0xFF, 0xFF, 0xFF]);
+    // The other map isn't on screen
+    assert!(viewer.bg_map(1).rgb.chunks(3).all(|p| p != VIEWPORT_COLOR));
+}
+#[test]
+fn vram_viewer_window_map() {
+    let mut ppu = ppu_with_tiles();
+    ppu.write(0xFF40, 0xF1);
+    ppu.write(0xFF4B, 87);
+    ppu.write(0xFF4A, 100);
+    let map = VramViewer::new(&ppu, ColorCorrection::Raw, OutputPalette::POCKET).window_map();
+    assert_eq!(map.pixel(79, 43), WINDOW_COLOR);
+    assert_eq!(map.pixel(80, 43), [0xFF, 0xFF, 0xFF]);
+    assert_eq!(map.pixel(40, 44), [0xFF, 0xFF, 0xFF]);
+}
+#[test]
+fn vram_viewer_oam_sheet() {
+    let mut ppu = ppu_with_tiles();
+    ppu.write(0xFF48, 0xE4);
+    for (i, &val) in [16, 8, 1, 0x20].iter().enumerate() {
+        ppu.write_oam(0xFE04 + i as u16, val);
+    }
+    let sheet = VramViewer::new(&ppu, ColorCorrection::Raw, OutputPalette::POCKET).oam_sheet();
+    assert_eq!((sheet.width, sheet.height), (320, 180));
+    assert_eq!(sheet.pixel(CELL_WIDTH + 2, 2), [0x00, 0x00, 0x00]);
+    assert_eq!(sheet.pixel(CELL_WIDTH + 17, 17), [0x00, 0x00, 0x00]);
+    // Colour 0 is see-through
+    assert_eq!(sheet.pixel(2, 2), CELL_COLOR);
+    // The "X" of the first line
+    assert_eq!(sheet.pixel(CELL_WIDTH + 21, 2), TEXT_COLOR);
+    assert_eq!(sheet.pixel(CELL_WIDTH + 22, 2), CELL_COLOR);
+    assert_eq!(sheet.pixel(CELL_WIDTH + 22, 4), TEXT_COLOR);
+}
@@ -0,0 +1,136 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::path::Path;
+
+const HEADER_LEN: u32 = 44;
+// The RIFF size field covers everything after it, so this is the most
+// sample data a file can hold
+const MAX_DATA_LEN: u32 = u32::MAX - (HEADER_LEN - 8);
+
+// 16-bit PCM WAV writer. The RIFF and data chunk sizes are written as zero
+// up front and patched in by finalize(), which also runs on drop.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    data_len: u32,
+    // Samples that would take the data past this many bytes are refused
+    max_data_len: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16) -> io::Result<WavWriter<BufWriter<File>>> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<WavWriter<W>> {
+        let block_align = channels * 2;
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { out, data_len: 0, max_data_len: MAX_DATA_LEN })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let data_len = u32::try_from(samples.len() * 2).ok()
+            .and_then(|len| self.data_len.checked_add(len))
+            .filter(|&len| len <= self.max_data_len)
+            .ok_or_else(|| io::Error::other("WAV file reached the 4 GiB limit"))?;
+        write_pcm(&mut self.out, samples)?;
+        self.data_len = data_len;
+        Ok(())
+    }
+
+    pub fn finalize(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&self.data_len.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+// Raw signed 16-bit little-endian PCM, as stored in the data chunk
+pub fn write_pcm<W: Write>(out: &mut W, samples: &[i16]) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(samples.len() * 2);
+    for sample in samples {
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+    out.write_all(&bytes)
+}
+
+
+// WAV Tests
+#[test]
+fn wav_header_and_data() {
+    let mut bytes = vec![];
+    {
+        let mut wav = WavWriter::new(io::Cursor::new(&mut bytes), 48000, 2).unwrap();
+        wav.write_samples(&[1, -1, 0x1234, -0x1234]).unwrap();
+    }
+    assert_eq!(bytes.len(), 44 + 8);
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(&bytes[4..8], &(36u32 + 8).to_le_bytes());
+    assert_eq!(&bytes[8..16], b"WAVEfmt ");
+    assert_eq!(&bytes[22..24], &2u16.to_le_bytes());
+    assert_eq!(&bytes[24..28], &48000u32.to_le_bytes());
+    assert_eq!(&bytes[28..32], &(48000u32 * 4).to_le_bytes());
+    assert_eq!(&bytes[32..34], &4u16.to_le_bytes());
+    assert_eq!(&bytes[34..36], &16u16.to_le_bytes());
+    assert_eq!(&bytes[36..40], b"data");
+    assert_eq!(&bytes[40..44], &8u32.to_le_bytes());
+    assert_eq!(&bytes[44..48], &[0x01, 0x00, 0xFF, 0xFF]);
+    assert_eq!(&bytes[48..52], &[0x34, 0x12, 0xCC, 0xED]);
+}
+#[test]
+fn wav_finalize_can_continue_writing() {
+    let mut bytes = vec![];
+    {
+        let mut wav = WavWriter::new(io::Cursor::new(&mut bytes), 44100, 1).unwrap();
+        wav.write_samples(&[7]).unwrap();
+        wav.finalize().unwrap();
+        wav.write_samples(&[8]).unwrap();
+    }
+    assert_eq!(&bytes[40..44], &4u32.to_le_bytes());
+    assert_eq!(&bytes[44..], &[7, 0, 8, 0]);
+}
+#[test]
+fn wav_stops_at_size_limit() {
+    let mut bytes = vec![];
+    {
+        let mut wav = WavWriter::new(io::Cursor::new(&mut bytes), 44100, 2).unwrap();
+        wav.max_data_len = 6;
+        wav.write_samples(&[1, 2]).unwrap();
+        assert!(wav.write_samples(&[3, 4]).is_err());
+        wav.write_samples(&[5]).unwrap();
+    }
+    assert_eq!(&bytes[4..8], &(36u32 + 6).to_le_bytes());
+    assert_eq!(&bytes[40..44], &6u32.to_le_bytes());
+    assert_eq!(&bytes[44..], &[1, 0, 2, 0, 5, 0]);
+}
+#[test]
+fn wav_raw_pcm() {
+    let mut bytes = vec![];
+    write_pcm(&mut bytes, &[1, -2]).unwrap();
+    assert_eq!(bytes, vec![0x01, 0x00, 0xFE, 0xFF]);
+}